@@ -1,788 +1,6003 @@
-use std::collections::BTreeSet;
+use flate2::read::MultiGzDecoder;
+use std::collections::{BTreeMap, BTreeSet};
 use std::env;
 use std::fs;
-use std::io::{self, BufRead, Write};
+use std::io::{self, BufRead, IsTerminal, Read, Write};
+use std::os::unix::fs::{MetadataExt, chown};
 use std::path::{Path, PathBuf};
-use std::process::{Command, ExitCode};
+use std::process::{Command, ExitCode, Stdio};
+use std::sync::OnceLock;
+use std::thread;
+use std::time::{Duration, SystemTime};
 
 // ── Colors ──────────────────────────────────────────────────────────
-const RESET: &str = "\x1b[0m";
-const BOLD: &str = "\x1b[1m";
-const DIM: &str = "\x1b[2m";
-const GREEN: &str = "\x1b[32m";
-const RED: &str = "\x1b[31m";
-const YELLOW: &str = "\x1b[33m";
-const CYAN: &str = "\x1b[36m";
 
-// ── Package list I/O ────────────────────────────────────────────────
-
-const PKG_FILENAME: &str = "packages.txt";
+// An escape code that prints as nothing once colors are off, so `{GREEN}` works everywhere
+#[derive(Clone, Copy)]
+struct Color(&'static str);
 
-fn pkg_file_path() -> PathBuf {
-    if let Ok(path) = env::var("APT_SYNC_FILE") {
-        return PathBuf::from(path);
-    }
-    let home = env::var("HOME").unwrap_or_else(|_| ".".into());
-    let config_dir = env::var("XDG_CONFIG_HOME")
-        .map_or_else(|_| PathBuf::from(&home).join(".config"), PathBuf::from)
-        .join("apt-sync");
-    let xdg_path = config_dir.join(PKG_FILENAME);
-    if xdg_path.exists() {
-        return xdg_path;
+impl std::fmt::Display for Color {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if color_enabled() { f.write_str(self.0) } else { Ok(()) }
     }
-    if let Some(repo) = env::current_exe().unwrap_or_default()
-        .parent().unwrap_or(Path::new("."))
-        .ancestors().find(|p| p.join("Cargo.toml").exists())
-    {
-        return repo.join(PKG_FILENAME);
-    }
-    let _ = fs::create_dir_all(&config_dir);
-    xdg_path
 }
 
-fn load_packages(path: &Path) -> BTreeSet<String> {
-    fs::read_to_string(path).map_or_else(|_| BTreeSet::new(), |c| parse_packages(&c))
+const RESET: Color = Color("\x1b[0m");
+const BOLD: Color = Color("\x1b[1m");
+const DIM: Color = Color("\x1b[2m");
+const GREEN: Color = Color("\x1b[32m");
+const RED: Color = Color("\x1b[31m");
+const YELLOW: Color = Color("\x1b[33m");
+const CYAN: Color = Color("\x1b[36m");
+
+static COLOR: OnceLock<bool> = OnceLock::new();
+
+// Decided once: main settles it from --no-color before anything prints
+fn color_enabled() -> bool {
+    *COLOR.get_or_init(|| wants_color(env::var_os("NO_COLOR").as_deref(), false))
 }
 
-fn parse_packages(contents: &str) -> BTreeSet<String> {
-    contents
-        .lines()
-        .map(str::trim)
-        .filter(|l| !l.is_empty() && !l.starts_with('#'))
-        .map(String::from)
-        .collect()
+// https://no-color.org: a non-empty NO_COLOR turns colors off
+fn wants_color(no_color: Option<&std::ffi::OsStr>, flag: bool) -> bool {
+    !flag && no_color.is_none_or(|v| v.is_empty())
 }
 
-fn save_packages(path: &Path, pkgs: &BTreeSet<String>) -> io::Result<()> {
-    let mut f = fs::File::create(path)?;
-    writeln!(f, "# apt-sync curated packages")?;
-    writeln!(f, "# one package per line, comments start with #")?;
-    for p in pkgs {
-        writeln!(f, "{p}")?;
-    }
-    Ok(())
+static FANCY: OnceLock<bool> = OnceLock::new();
+
+// Emoji and other multibyte markers only go to a terminal; tests always see the fancy form
+fn fancy_output() -> bool {
+    *FANCY.get_or_init(|| cfg!(test) || io::stdout().is_terminal())
 }
 
-// ── System queries ──────────────────────────────────────────────────
+// An output marker with an ASCII stand-in for piped output, trailing space included
+#[derive(Clone, Copy)]
+struct Glyph(&'static str, &'static str);
 
-fn system_manual_packages() -> BTreeSet<String> {
-    let output = Command::new("apt-mark")
-        .arg("showmanual")
-        .output()
-        .expect("failed to run apt-mark — is apt installed?");
-    let stdout = String::from_utf8_lossy(&output.stdout);
-    stdout
-        .lines()
-        .map(str::trim)
-        .filter(|l| !l.is_empty())
-        .map(String::from)
-        .collect()
+impl Glyph {
+    fn pick(self, fancy: bool) -> &'static str {
+        if fancy { self.0 } else { self.1 }
+    }
 }
 
-fn installed_set(pkgs: &BTreeSet<String>) -> BTreeSet<String> {
-    if pkgs.is_empty() {
-        return BTreeSet::new();
+impl std::fmt::Display for Glyph {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(self.pick(fancy_output()))
     }
-    let output = Command::new("dpkg-query")
-        .args(["-W", "-f=${Package}\t${Status}\n"])
-        .args(pkgs)
-        .stderr(std::process::Stdio::null())
-        .output()
-        .expect("failed to run dpkg-query — is dpkg installed?");
-    parse_installed(&String::from_utf8_lossy(&output.stdout))
 }
 
-fn parse_installed(output: &str) -> BTreeSet<String> {
-    output
-        .lines()
-        .filter_map(|line| {
-            let (pkg, status) = line.split_once('\t')?;
-            status
-                .contains("install ok installed")
-                .then(|| pkg.to_string())
-        })
-        .collect()
+const ICON_OK: Glyph = Glyph("✔ ", "[ok] ");
+const ICON_NO: Glyph = Glyph("✘ ", "[--] ");
+const ICON_ADD: Glyph = Glyph("＋ ", "[+] ");
+const ICON_DEL: Glyph = Glyph("－ ", "[-] ");
+const ICON_UNCURATED: Glyph = Glyph("? ", "[?] ");
+const ICON_WARN: Glyph = Glyph("⚠ ", "warning: ");
+const ICON_ERROR: Glyph = Glyph("💥 ", "error: ");
+const ICON_PACKAGE: Glyph = Glyph("📦 ", "");
+const ICON_EMPTY: Glyph = Glyph("📭 ", "");
+const ICON_DONE: Glyph = Glyph("✨ ", "");
+const ICON_NOTE: Glyph = Glyph("📝 ", "");
+const ICON_SHIELD: Glyph = Glyph("🛡️  ", "");
+const ICON_WAIT: Glyph = Glyph("⏳ ", "");
+const ICON_BROOM: Glyph = Glyph("🧹 ", "");
+const ICON_ROCKET: Glyph = Glyph("🚀 ", "");
+const ICON_DRY_RUN: Glyph = Glyph("🏜️  ", "");
+const ICON_TAG: Glyph = Glyph("🏷️  ", "");
+const ICON_MARK: Glyph = Glyph("🔖 ", "");
+const ICON_IDEA: Glyph = Glyph("💡 ", "");
+const ICON_SEARCH: Glyph = Glyph("🔍 ", "");
+const ICON_LIST: Glyph = Glyph("📋 ", "");
+const ICON_FILE: Glyph = Glyph("📄 ", "");
+const ICON_SNAP: Glyph = Glyph("📸 ", "");
+const ICON_DATE: Glyph = Glyph("📅 ", "");
+const ICON_REMOVED: Glyph = Glyph("🗑️  ", "");
+const ICON_PURGED: Glyph = Glyph("🔥 ", "");
+
+// ── Progress ────────────────────────────────────────────────────────
+
+const PROGRESS_LOG_THRESHOLD: usize = 8;
+
+// Transient dim line on stderr, erased when dropped
+struct StatusLine(bool);
+
+impl StatusLine {
+    fn show(msg: &str, enabled: bool) -> Self {
+        // Erasing the line needs an escape code, so plain output skips it
+        let enabled = enabled && color_enabled();
+        if enabled {
+            eprint!("{DIM}{msg}{RESET}");
+            let _ = io::stderr().flush();
+        }
+        Self(enabled)
+    }
 }
 
-// ── Apt history ─────────────────────────────────────────────────────
+impl Drop for StatusLine {
+    fn drop(&mut self) {
+        if self.0 {
+            eprint!("\r\x1b[K");
+        }
+    }
+}
 
-struct HistoryEntry {
-    date: String,
-    commandline: String,
-    requested_by: Option<String>,
-    installed: Vec<String>,
+// ── Config ──────────────────────────────────────────────────────────
+
+const CONFIG_FILENAME: &str = "config.toml";
+
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Config {
+    file: Option<PathBuf>,
+    large_list: Option<usize>, // status prints a heads-up above this many packages
+    interesting_only: Option<String>,
+    header: Option<String>, // comment block save_packages writes above the entries
+    sort_into: bool,        // `add` files new packages alphabetically even into unsorted sections
+    why_oldest_first: bool, // `why_order = "oldest-first"`: `why` lists events chronologically
 }
 
-fn read_history_logs() -> String {
-    let mut buf = String::new();
+fn config_dir() -> PathBuf {
+    let home = env::var("HOME").unwrap_or_else(|_| ".".into());
+    env::var("XDG_CONFIG_HOME")
+        .map_or_else(|_| PathBuf::from(&home).join(".config"), PathBuf::from)
+        .join("apt-sync")
+}
 
-    let mut gz_paths: Vec<PathBuf> = fs::read_dir("/var/log/apt")
-        .into_iter()
-        .flatten()
-        .filter_map(Result::ok)
-        .map(|e| e.path())
-        .filter(|p| p.file_name().is_some_and(|n| { let s = n.to_string_lossy(); s.starts_with("history") && s.ends_with(".gz") }))
-        .collect();
-    gz_paths.sort_by(|a, b| b.cmp(a)); // highest number = oldest, read oldest first
+// An explicit --config must exist; the default location is optional
+fn load_config(explicit: Option<&Path>) -> io::Result<Config> {
+    let path = explicit.map_or_else(|| config_dir().join(CONFIG_FILENAME), Path::to_path_buf);
+    match fs::read_to_string(&path) {
+        Ok(contents) => Ok(parse_config(&contents, path.parent().unwrap_or(Path::new(".")))),
+        Err(e) if explicit.is_none() && e.kind() == io::ErrorKind::NotFound => Ok(Config::default()),
+        Err(e) => Err(e),
+    }
+}
+
+// Minimal TOML subset: top-level `key = "value"` pairs and comments
+fn parse_config(contents: &str, base_dir: &Path) -> Config {
+    let mut config = Config::default();
+    for line in contents.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') || line.starts_with('[') {
+            continue;
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            eprintln!("{YELLOW}{ICON_WARN}Ignoring malformed config line: {line}{RESET}");
+            continue;
+        };
+        let value = value.trim().trim_matches('"');
+        match key.trim() {
+            "file" => config.file = Some(resolve_config_path(value, base_dir)),
+            "interesting_only" => config.interesting_only = Some(value.to_string()),
+            "header" => config.header = Some(value.to_string()),
+            "sort_into" => match value.parse() {
+                Ok(b) => config.sort_into = b,
+                Err(_) => eprintln!("{YELLOW}{ICON_WARN}sort_into must be true or false: {value}{RESET}"),
+            },
+            "why_order" => match value {
+                "oldest-first" => config.why_oldest_first = true,
+                "newest-first" => config.why_oldest_first = false,
+                _ => eprintln!("{YELLOW}{ICON_WARN}why_order must be oldest-first or newest-first: {value}{RESET}"),
+            },
+            "large_list" => match value.parse() {
+                Ok(n) => config.large_list = Some(n),
+                Err(_) => eprintln!("{YELLOW}{ICON_WARN}large_list must be a number: {value}{RESET}"),
+            },
+            other => eprintln!("{YELLOW}{ICON_WARN}Unknown config key: {other}{RESET}"),
+        }
+    }
+    config
+}
 
-    if !gz_paths.is_empty()
-        && let Ok(output) = Command::new("zcat").args(&gz_paths).output()
+// `~/` expands to $HOME; relative paths are relative to the config file; URLs are kept as-is
+fn resolve_config_path(value: &str, base_dir: &Path) -> PathBuf {
+    if is_url(value) {
+        return PathBuf::from(value);
+    }
+    if let Some(rest) = value.strip_prefix("~/")
+        && let Ok(home) = env::var("HOME")
     {
-        buf.push_str(&String::from_utf8_lossy(&output.stdout));
+        return PathBuf::from(home).join(rest);
     }
+    base_dir.join(value)
+}
 
-    if let Ok(current) = fs::read_to_string("/var/log/apt/history.log") {
-        buf.push_str(&current);
+// Removes `--flag value` / `--flag=value` from args, returning the value; a trailing `--flag` is an error
+fn take_flag_value(args: &mut Vec<String>, flag: &str) -> Result<Option<String>, String> {
+    let prefix = format!("{flag}=");
+    let Some(i) = args.iter().position(|a| a == flag || a.starts_with(&prefix)) else {
+        return Ok(None);
+    };
+    let arg = args.remove(i);
+    match arg.strip_prefix(&prefix) {
+        Some(v) => Ok(Some(v.to_string())),
+        None if i < args.len() => Ok(Some(args.remove(i))),
+        None => Err(format!("`{flag}` needs a value")),
     }
+}
 
-    buf
+// ── Package list I/O ────────────────────────────────────────────────
+
+const PKG_FILENAME: &str = "packages.txt";
+
+// $APT_SYNC_FILE, then `file` from config.toml
+fn explicit_pkg_path(config: &Config) -> Option<PathBuf> {
+    env::var("APT_SYNC_FILE").ok().map(PathBuf::from).or_else(|| config.file.clone())
 }
 
-fn parse_history(log: &str) -> Vec<HistoryEntry> {
-    log.split("\n\n")
-        .filter_map(|block| {
-            let mut date = "";
-            let mut cmdline = "";
-            let mut requested = None;
-            let mut installed = Vec::new();
-            for line in block.lines() {
-                if let Some(d) = line.strip_prefix("Start-Date: ") { date = d.trim(); }
-                else if let Some(c) = line.strip_prefix("Commandline: ") { cmdline = c.trim(); }
-                else if let Some(r) = line.strip_prefix("Requested-By: ") { requested = Some(r.trim().to_string()); }
-                else if let Some(pkgs) = line.strip_prefix("Install: ") { installed = parse_history_packages(pkgs); }
-            }
-            (!installed.is_empty()).then(|| HistoryEntry {
-                date: date.to_string(),
-                commandline: cmdline.to_string(),
-                requested_by: requested,
-                installed,
-            })
-        })
-        .collect()
+fn pkg_file_path(config: &Config) -> PathBuf {
+    resolve_pkg_file(env::var("APT_SYNC_FILE").ok(), config).0
 }
 
-fn parse_history_packages(pkgs_line: &str) -> Vec<String> {
-    pkgs_line
-        .split("), ")
-        .filter_map(|entry| {
-            let name = entry.split(':').next().filter(|n| !n.is_empty())?;
-            (!entry.contains("automatic")).then(|| name.to_string())
-        })
-        .collect()
+// The packages file and the rule that chose it (reported by `env`)
+fn resolve_pkg_file(env_file: Option<String>, config: &Config) -> (PathBuf, &'static str) {
+    if let Some(path) = env_file {
+        return (PathBuf::from(path), "$APT_SYNC_FILE");
+    }
+    if let Some(path) = &config.file {
+        return (path.clone(), "`file` in config.toml");
+    }
+    let config_dir = config_dir();
+    let xdg_path = config_dir.join(PKG_FILENAME);
+    if xdg_path.exists() {
+        return (xdg_path, "default location");
+    }
+    if let Some(repo) = env::current_exe().unwrap_or_default()
+        .parent().unwrap_or(Path::new("."))
+        .ancestors().find(|p| p.join("Cargo.toml").exists())
+    {
+        return (repo.join(PKG_FILENAME), "source checkout of the binary");
+    }
+    let _ = fs::create_dir_all(&config_dir);
+    (xdg_path, "default location (not created yet)")
 }
 
-fn find_install_history<'a>(entries: &'a [HistoryEntry], pkg: &str) -> Vec<&'a HistoryEntry> {
-    entries
-        .iter()
-        .filter(|e| e.installed.iter().any(|p| p == pkg))
-        .collect()
+// Per-package metadata carried alongside the name in packages.txt
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct PkgEntry {
+    tags: BTreeSet<String>,
+    release: Option<String>, // `pkg/release` → apt-get install pkg/release
+    version: Option<String>, // `pkg=version` pin; a trailing `*` matches any version with that prefix
+    section: Option<String>, // the `## Name` header the entry sits under
 }
 
-fn format_pkg_list(pkgs: &[&str]) -> String {
-    const MAX: usize = 10;
-    let s = pkgs[..pkgs.len().min(MAX)].join(", ");
-    if pkgs.len() > MAX { format!("{s} + {} more", pkgs.len() - MAX) } else { s }
+type PkgList = BTreeMap<String, PkgEntry>;
+
+fn load_packages(path: &Path) -> BTreeSet<String> {
+    fs::read_to_string(path).map_or_else(|_| BTreeSet::new(), |c| parse_packages(&c))
 }
 
-fn same_day_neighbors<'a>(
-    entries: &'a [HistoryEntry],
-    entry: &HistoryEntry,
-    name: &str,
-    sibling_set: &BTreeSet<&str>,
-) -> Vec<&'a str> {
-    let day = entry.date.split_whitespace().next().unwrap_or("");
-    entries
-        .iter()
-        .filter(|e| {
-            e.date.split_whitespace().next().unwrap_or("") == day
-                && !(e.date == entry.date && e.commandline == entry.commandline)
-        })
-        .flat_map(|e| e.installed.iter().map(String::as_str))
-        .filter(|p| *p != name && !sibling_set.contains(p))
-        .collect::<BTreeSet<&str>>()
-        .into_iter()
-        .collect()
+fn load_list(path: &Path) -> PkgList {
+    fs::read_to_string(path).map_or_else(|_| PkgList::new(), |c| parse_list(&c))
 }
 
-// ── Shell history and journal context ───────────────────────────────
+// Packages outside any section form the `default` group
+const DEFAULT_GROUP: &str = "default";
 
-struct ShellHistoryEntry {
-    timestamp: i64,
-    command: String,
+fn in_group(entry: &PkgEntry, group: &str) -> bool {
+    entry.section.as_deref().map_or(group == DEFAULT_GROUP, |s| s == group)
 }
 
-fn read_journal_pwd(apt_date: &str, commandline: &str) -> Option<String> {
-    let normalized: String = apt_date.split_whitespace().collect::<Vec<_>>().join(" ");
+fn group_names(list: &PkgList) -> Vec<&str> {
+    let names: BTreeSet<&str> = list.values().map(|e| e.section.as_deref().unwrap_or(DEFAULT_GROUP)).collect();
+    names.into_iter().collect()
+}
 
-    // Query journal with ±60s window around the apt command
-    let output = Command::new("journalctl")
-        .args([
-            "_COMM=sudo",
-            "--no-pager",
-            &format!("--since={normalized} -5seconds"),
-            &format!("--until={normalized} +60seconds"),
-        ])
-        .output()
-        .ok()?;
+// `--group=NAME`: just that section's packages; the whole list without one
+fn load_group(path: &Path, group: Option<&str>) -> PkgList {
+    let mut list = load_list(path);
+    if let Some(group) = group {
+        list.retain(|_, e| in_group(e, group));
+    }
+    list
+}
 
-    let journal = String::from_utf8_lossy(&output.stdout);
-    parse_journal_pwd(&journal, commandline)
+fn parse_packages(contents: &str) -> BTreeSet<String> {
+    parse_list(contents).into_keys().collect()
 }
 
-fn parse_journal_pwd(journal_output: &str, commandline: &str) -> Option<String> {
-    let pkg_names: Vec<&str> = commandline
-        .split_whitespace()
-        .skip_while(|w| w.starts_with('-') || *w == "apt-get" || *w == "apt" || *w == "install")
-        .filter(|w| !w.starts_with('-'))
-        .collect();
-    if pkg_names.is_empty() {
+// `## Name` (or `# [name]`) opens a section; a bare `##` closes it
+fn section_header(line: &str) -> Option<Option<&str>> {
+    if let Some(name) = line.strip_prefix('#').and_then(|l| l.trim().strip_prefix('[')?.strip_suffix(']')) {
+        let name = name.trim();
+        return (!name.is_empty()).then_some(Some(name));
+    }
+    let name = line.strip_prefix("##")?.trim();
+    if name.starts_with('#') {
         return None;
     }
-    let home = env::var("HOME").ok()?;
+    Some((!name.is_empty()).then_some(name))
+}
 
-    for line in journal_output.lines() {
-        // sudo journal format: "... ]: PWD=... ; USER=... ; COMMAND=..."
-        let fields = line.rsplit_once("]: ").map_or(line, |(_, f)| f);
-        let pwd = fields.split(" ; ").find_map(|f| f.strip_prefix("PWD="));
-        let command = fields.find("COMMAND=").map(|i| &fields[i + 8..]);
+// `jq  # for scripts` → ("jq", Some("# for scripts")); no package name contains `#`
+fn split_comment(line: &str) -> (&str, Option<&str>) {
+    match line.find('#') {
+        Some(i) => (line[..i].trim_end(), Some(&line[i..])),
+        None => (line, None),
+    }
+}
 
-        if let (Some(p), Some(c)) = (pwd, command)
-            && c.contains("apt")
-            && pkg_names.iter().any(|pkg| c.contains(pkg))
-        {
-            return Some(p.strip_prefix(home.as_str()).map_or_else(|| p.to_string(), |rel| format!("~{rel}")));
+// Lines are `name[/release] [@tag...] [# comment]`, grouped under optional `## Section` headers
+fn parse_list(contents: &str) -> PkgList {
+    let mut list = PkgList::new();
+    let mut section: Option<&str> = None;
+    for l in contents.lines().map(str::trim).filter(|l| !l.is_empty()) {
+        if let Some(header) = section_header(l) {
+            section = header;
+            continue;
+        }
+        let (l, _) = split_comment(l);
+        let mut words = l.split_whitespace().filter_map(|raw| {
+            let word = clean_pkg_name(raw);
+            if word != raw {
+                eprintln!("{YELLOW}{ICON_WARN}Stripped stray punctuation: {raw} → {word}{RESET}");
+            }
+            (!word.is_empty()).then_some(word)
+        });
+        let Some(spec) = words.next() else { continue };
+        let (name, release, version) = split_spec(spec);
+        if name.is_empty() {
+            eprintln!("{YELLOW}{ICON_WARN}Ignoring entry without a package name: {l}{RESET}");
+            continue;
+        }
+        // No package name starts with `-`; passing one on would turn it into an apt flag
+        if name.starts_with('-') {
+            eprintln!("{YELLOW}{ICON_WARN}Ignoring flag-like entry: {l}{RESET}");
+            continue;
+        }
+        let entry = list.entry(name.to_string()).or_default();
+        entry.section = section.map(String::from);
+        if let Some(release) = release {
+            entry.release = Some(release.to_string());
+        }
+        if let Some(version) = version {
+            entry.version = Some(version.to_string());
+        }
+        for word in words {
+            match word.strip_prefix('@').filter(|t| !t.is_empty()) {
+                Some(tag) => { entry.tags.insert(tag.to_string()); }
+                None => eprintln!("{YELLOW}{ICON_WARN}Ignoring unexpected word after {name}: {word}{RESET}"),
+            }
         }
     }
-    None
+    list
 }
 
-fn read_shell_history() -> Vec<ShellHistoryEntry> {
-    let path = env::var("HISTFILE").ok().or_else(|| {
-        let home = env::var("HOME").ok()?;
-        [".zsh_history", ".bash_history"]
-            .iter()
-            .map(|f| PathBuf::from(&home).join(f))
-            .find(|p| p.exists())
-            .map(|p| p.to_string_lossy().to_string())
-    });
-    path.and_then(|p| fs::read_to_string(p).ok()).map_or_else(Vec::new, |c| parse_shell_history(&c))
+// `name[=version]` or `name[/release]` → (name, release, version)
+fn split_spec(spec: &str) -> (&str, Option<&str>, Option<&str>) {
+    match spec.split_once('=') {
+        Some((name, version)) => (clean_pkg_name(name), None, Some(version).filter(|v| !v.is_empty())),
+        None => {
+            let (name, release) = split_release(spec);
+            (name, release, None)
+        }
+    }
 }
 
-fn parse_shell_history(contents: &str) -> Vec<ShellHistoryEntry> {
-    contents.lines().filter_map(|line| {
-        let rest = line.strip_prefix(": ")?;
-        let (epoch_part, cmd) = rest.split_once(';')?;
-        let timestamp = epoch_part.split(':').next()?.parse().ok()?;
-        Some(ShellHistoryEntry { timestamp, command: cmd.to_string() })
-    }).collect()
+fn split_release(spec: &str) -> (&str, Option<&str>) {
+    spec.split_once('/').map_or((spec, None), |(n, r)| (clean_pkg_name(n), Some(r).filter(|r| !r.is_empty())))
 }
 
-fn apt_date_to_epoch(apt_date: &str) -> Option<i64> {
-    let normalized: String = apt_date.split_whitespace().collect::<Vec<_>>().join(" ");
+// Only the tail is touched: `+`, `-` and mid-name `.` are valid in package names
+fn clean_pkg_name(raw: &str) -> &str {
+    raw.trim_end_matches(|c: char| matches!(c, ',' | ';' | '.') || c.is_whitespace())
+}
 
-    let output = Command::new("date")
-        .args(["-d", &normalized, "+%s"])
-        .output()
-        .ok()?;
+// Starter file for `init`: the usual header, a few commented examples, then any seed packages
+fn init_packages_file(path: &Path, seed: &BTreeSet<String>, force: bool) -> io::Result<()> {
+    if path.exists() && !force {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, "file already exists (use --force to overwrite)"));
+    }
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut f = Vec::new();
+    write_header(&mut f, configured_header())?;
+    writeln!(f, "#")?;
+    writeln!(f, "# examples:")?;
+    writeln!(f, "#   git")?;
+    writeln!(f, "#   ripgrep @cli                  tag with @name")?;
+    writeln!(f, "#   neovim/bookworm-backports     install from a target release")?;
+    for p in seed {
+        writeln!(f, "{p}")?;
+    }
+    write_atomically(path, &f)
+}
 
-    let stdout = String::from_utf8_lossy(&output.stdout);
-    stdout.trim().parse::<i64>().ok()
+// Canonical layout for `fmt`: trailing whitespace trimmed, blank runs collapsed, one final
+// newline, and entries sorted within each run between blank or comment lines
+fn format_packages_text(contents: &str) -> String {
+    let mut out: Vec<&str> = Vec::new();
+    let mut block: Vec<&str> = Vec::new();
+    fn flush<'a>(block: &mut Vec<&'a str>, out: &mut Vec<&'a str>) {
+        block.sort_by_key(|l| l.split_whitespace().next().unwrap_or("").to_lowercase());
+        out.append(block);
+    }
+    for line in contents.lines().map(str::trim_end) {
+        if line.trim_start().is_empty() || line.trim_start().starts_with('#') {
+            flush(&mut block, &mut out);
+            if line.is_empty() && out.last().is_none_or(|l| l.is_empty()) {
+                continue;
+            }
+            out.push(if line.trim_start().is_empty() { "" } else { line });
+        } else {
+            block.push(line);
+        }
+    }
+    flush(&mut block, &mut out);
+    while out.last() == Some(&"") {
+        out.pop();
+    }
+    if out.is_empty() { String::new() } else { out.join("\n") + "\n" }
 }
 
-fn find_nearby_commands(
-    history: &[ShellHistoryEntry],
-    target_epoch: i64,
-    window_secs: i64,
-    show_all: bool,
-) -> Vec<String> {
-    let mut nearby: Vec<_> = history
-        .iter()
-        .filter_map(|e| {
-            let d = (e.timestamp - target_epoch).abs();
-            (d <= window_secs).then_some((e, d))
-        })
-        .collect();
-    nearby.sort_by_key(|(_, d)| *d);
-    nearby
-        .into_iter()
-        .map(|(e, _)| &e.command)
-        .filter(|c| !c.contains("apt-get install") && !c.contains("apt install"))
-        .filter(|c| {
-            show_all
-                || !matches!(
-                    c.split_whitespace().next().unwrap_or(""),
-                    "ls" | "clear" | "exit" | "pwd" | "echo" | "cat" | "true" | "history" | ""
-                )
+const DEFAULT_HEADER: &str = "apt-sync curated packages\none package per line, comments start with #";
+
+// `header` from config.toml, settled by main before anything is saved
+static HEADER: OnceLock<String> = OnceLock::new();
+
+// One comment per line; a literal `\n` in the config value starts a new one
+fn header_lines(header: &str) -> Vec<String> {
+    header
+        .replace("\\n", "\n")
+        .lines()
+        .map(|l| match l.trim_end() {
+            "" => "#".to_string(),
+            l if l.starts_with('#') => l.to_string(),
+            l => format!("# {l}"),
         })
-        .take(5)
-        .cloned()
         .collect()
 }
 
-// ── Commands ────────────────────────────────────────────────────────
+// `--sort-into` or `sort_into = true`, settled by main like HEADER
+static SORT_INTO: OnceLock<bool> = OnceLock::new();
 
-fn cmd_status(pkg_path: &Path) {
-    let pkgs = load_packages(pkg_path);
-    if pkgs.is_empty() {
-        println!("{YELLOW}📭 No curated packages yet. Use `apt-sync add <pkg>` to get started!{RESET}");
-        return;
+fn sort_into_enabled() -> bool {
+    SORT_INTO.get().copied().unwrap_or(false)
+}
+
+fn configured_header() -> &'static str {
+    HEADER.get().map_or(DEFAULT_HEADER, String::as_str)
+}
+
+fn write_header(f: &mut impl Write, header: &str) -> io::Result<()> {
+    for line in header_lines(header) {
+        writeln!(f, "{line}")?;
+    }
+    Ok(())
+}
+
+fn save_packages(path: &Path, pkgs: &PkgList) -> io::Result<()> {
+    save_packages_with_header(path, pkgs, configured_header())
+}
+
+// The header is only ever written here; a copy already at the top is replaced, never stacked
+fn save_packages_with_header(path: &Path, pkgs: &PkgList, header: &str) -> io::Result<()> {
+    let original = fs::read_to_string(path).unwrap_or_default();
+    write_atomically(path, render_packages(&original, pkgs, header, sort_into_enabled()).as_bytes())
+}
+
+// One rendered file line; `entry` is the package it lists, `section` the block it sits in
+struct ListLine {
+    text: String,
+    section: Option<String>,
+    entry: Option<String>,
+    is_header: bool,
+}
+
+fn entry_text(name: &str, entry: &PkgEntry) -> String {
+    let mut text = install_spec(name, entry);
+    for t in &entry.tags {
+        text.push_str(&format!(" @{t}"));
+    }
+    text
+}
+
+// Package name of an entry line, as parse_list reads it; None for blanks, comments and junk
+fn entry_line_name(line: &str) -> Option<&str> {
+    let first = split_comment(line).0.split_whitespace().next()?;
+    let name = split_spec(clean_pkg_name(first)).0;
+    (!name.is_empty() && !name.starts_with('-')).then_some(name)
+}
+
+// Minimal edit of the existing file: comments, blank lines and order survive; lines of dropped
+// packages go, changed ones are rewritten in place (keeping an inline `# comment`), and new
+// ones join their section, in sorted position when the section already is sorted (or sort_into)
+fn render_packages(original: &str, pkgs: &PkgList, header: &str, sort_into: bool) -> String {
+    let mut body: Vec<&str> = original.lines().collect();
+    for known in [header, DEFAULT_HEADER] {
+        let lines = header_lines(known);
+        if !lines.is_empty() && body.len() >= lines.len() && body.iter().zip(&lines).all(|(b, h)| b.trim_end() == h) {
+            body.drain(..lines.len());
+            break;
+        }
+    }
+
+    let mut out: Vec<ListLine> = Vec::new();
+    let mut section: Option<String> = None;
+    let mut written: BTreeSet<&str> = BTreeSet::new();
+    for raw in body {
+        let l = raw.trim();
+        if let Some(h) = section_header(l) {
+            section = h.map(String::from);
+            // A section left without entries loses its header
+            if section.is_some() && !pkgs.values().any(|e| e.section == section) {
+                continue;
+            }
+            out.push(ListLine { text: raw.trim_end().to_string(), section: section.clone(), entry: None, is_header: true });
+            continue;
+        }
+        let Some(name) = entry_line_name(l) else {
+            out.push(ListLine { text: raw.trim_end().to_string(), section: section.clone(), entry: None, is_header: false });
+            continue;
+        };
+        let Some((name, entry)) = pkgs.get_key_value(name) else { continue };
+        if entry.section != section || !written.insert(name) {
+            continue;
+        }
+        let (spec, comment) = split_comment(l);
+        let rendered = entry_text(name, entry);
+        let text = if spec.split_whitespace().collect::<Vec<_>>().join(" ") == rendered {
+            raw.trim_end().to_string()
+        } else {
+            comment.map_or_else(|| rendered.clone(), |c| format!("{rendered}  {c}"))
+        };
+        out.push(ListLine { text, section: section.clone(), entry: Some(name.clone()), is_header: false });
     }
-    println!("{BOLD}{CYAN}📦 apt-sync status{RESET}  {DIM}({} curated){RESET}\n", pkgs.len());
-    let installed = installed_set(&pkgs);
-    for p in &pkgs {
-        if installed.contains(p) {
-            println!("  {GREEN}✔ {p}{RESET}");
+
+    for (name, entry) in pkgs.iter().filter(|(n, _)| !written.contains(n.as_str())) {
+        let line = ListLine { text: entry_text(name, entry), section: entry.section.clone(), entry: Some(name.clone()), is_header: false };
+        let siblings: Vec<usize> =
+            (0..out.len()).filter(|&i| out[i].entry.is_some() && out[i].section == entry.section).collect();
+        if let Some(&last) = siblings.last() {
+            let names: Vec<&String> = siblings.iter().filter_map(|&i| out[i].entry.as_ref()).collect();
+            let pos = if sort_into || names.is_sorted() {
+                siblings.iter().copied().find(|&i| out[i].entry.as_ref() > Some(name)).unwrap_or(last + 1)
+            } else {
+                last + 1
+            };
+            out.insert(pos, line);
+        } else if let Some(h) = entry.section.as_ref().and_then(|_| out.iter().position(|l| l.is_header && l.section == entry.section)) {
+            out.insert(h + 1, line);
+        } else if entry.section.is_none() {
+            // Unsectioned entries go above the first section, ahead of its blank separator
+            let mut pos = out.iter().position(|l| l.is_header).unwrap_or(out.len());
+            while pos > 0 && out[pos - 1].text.is_empty() {
+                pos -= 1;
+            }
+            out.insert(pos, line);
+            if pos + 1 < out.len() && !out[pos + 1].text.is_empty() && out[pos + 1].entry.is_none() {
+                out.insert(pos + 1, ListLine { text: String::new(), section: None, entry: None, is_header: false });
+            }
         } else {
-            println!("  {RED}✘ {p}{RESET}  {DIM}(not installed){RESET}");
+            // A new section goes in sorted position among sorted ones, else at the end
+            let section = entry.section.clone();
+            let headers: Vec<usize> = (0..out.len()).filter(|&i| out[i].is_header && out[i].section.is_some()).collect();
+            let sorted = headers.iter().map(|&i| &out[i].section).is_sorted();
+            let header = ListLine { text: format!("## {}", section.as_deref().unwrap_or_default()), section, entry: None, is_header: true };
+            let blank = || ListLine { text: String::new(), section: None, entry: None, is_header: false };
+            match headers.iter().copied().find(|&i| sorted && out[i].section > header.section) {
+                Some(pos) => {
+                    out.splice(pos..pos, [header, line, blank()]);
+                }
+                None => out.extend([blank(), header, line]),
+            }
         }
     }
-    let n_missing = pkgs.len() - installed.len();
-    println!();
-    println!("  {GREEN}{} installed{RESET}  {RED}{n_missing} missing{RESET}", installed.len());
-    if n_missing > 0 {
-        println!("  {DIM}Run `apt-sync install` to install missing packages{RESET}");
+
+    let mut text = String::new();
+    for line in header_lines(header) {
+        text.push_str(&line);
+        text.push('\n');
+    }
+    let mut previous_blank = true;
+    for line in &out {
+        let blank = line.text.is_empty();
+        if !(blank && previous_blank) {
+            text.push_str(&line.text);
+            text.push('\n');
+        }
+        previous_blank = blank;
+    }
+    while text.ends_with("\n\n") {
+        text.pop();
     }
+    text
 }
 
-fn cmd_list(pkg_path: &Path) {
-    let pkgs = load_packages(pkg_path);
-    if pkgs.is_empty() {
-        println!("{YELLOW}📭 No curated packages yet.{RESET}");
-        return;
+fn sibling_path(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+// Temp file + rename, so a crash or full disk mid-write never leaves a truncated list.
+// The previous contents go to `<file>.bak`; a symlinked list (e.g. into dotfiles) stays a symlink.
+fn write_atomically(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let target = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let tmp = sibling_path(&target, &format!(".tmp{}", std::process::id()));
+    let written = (|| {
+        let mut f = fs::File::create(&tmp)?;
+        f.write_all(contents)?;
+        f.sync_all()?;
+        if let Ok(meta) = fs::metadata(&target) {
+            fs::set_permissions(&tmp, meta.permissions())?;
+            // A save under sudo keeps the list the user's; only root can hand files over, so as
+            // anyone else this is a no-op on their own file and a harmless failure otherwise
+            let _ = chown(&tmp, Some(meta.uid()), Some(meta.gid()));
+            if let Err(e) = back_up(&target, &meta) {
+                eprintln!("{YELLOW}{ICON_WARN}Could not update {}: {e}{RESET}", sibling_path(&target, ".bak").display());
+            }
+        }
+        fs::rename(&tmp, &target)
+    })();
+    if written.is_err() {
+        let _ = fs::remove_file(&tmp);
     }
-    for p in &pkgs {
-        println!("{p}");
+    written
+}
+
+// `.bak` is a convenience: one left root-owned by an earlier sudo save is replaced, not written through
+fn back_up(target: &Path, meta: &fs::Metadata) -> io::Result<()> {
+    let bak = sibling_path(target, ".bak");
+    if fs::copy(target, &bak).is_err() {
+        let _ = fs::remove_file(&bak);
+        fs::copy(target, &bak)?;
     }
+    let _ = chown(&bak, Some(meta.uid()), Some(meta.gid()));
+    Ok(())
 }
 
-fn cmd_modify(pkg_path: &Path, names: &[String], add: bool) {
-    let mut pkgs = load_packages(pkg_path);
-    let mut changed = Vec::new();
-    let mut unchanged = Vec::new();
-    for name in names {
-        let ok = if add { pkgs.insert(name.clone()) } else { pkgs.remove(name) };
-        if ok { changed.push(name.as_str()) } else { unchanged.push(name.as_str()) }
+// Unsectioned entries first, then each section by name
+fn by_section(pkgs: &PkgList) -> BTreeMap<Option<&str>, Vec<(&String, &PkgEntry)>> {
+    let mut sections: BTreeMap<Option<&str>, Vec<(&String, &PkgEntry)>> = BTreeMap::new();
+    for (p, entry) in pkgs {
+        sections.entry(entry.section.as_deref()).or_default().push((p, entry));
     }
-    save_packages(pkg_path, &pkgs).expect("failed to write packages.txt");
-    let (sym, color, verb, skip_msg) = if add {
-        ("＋", GREEN, "Added", "already listed")
-    } else {
-        ("－", RED, "Removed", "not in list")
+    sections
+}
+
+// Files the named packages under `section`; returns those that moved from elsewhere
+fn assign_section<'a>(pkgs: &mut PkgList, names: &'a [String], section: &str) -> Vec<&'a str> {
+    let mut moved = Vec::new();
+    for name in names.iter().filter(|n| !n.starts_with('@')) {
+        if let Some(entry) = pkgs.get_mut(split_spec(name).0)
+            && entry.section.as_deref() != Some(section)
+        {
+            moved.push(name.as_str());
+            entry.section = Some(section.to_string());
+        }
+    }
+    moved
+}
+
+fn install_spec(name: &str, entry: &PkgEntry) -> String {
+    match (&entry.version, &entry.release) {
+        (Some(v), _) => format!("{name}={v}"),
+        (None, Some(r)) => format!("{name}/{r}"),
+        (None, None) => name.to_string(),
+    }
+}
+
+// apt-get takes `pkg=1.2*` too, so a trailing `*` pins a version prefix
+fn version_matches_pin(installed: &str, pin: &str) -> bool {
+    match pin.strip_suffix('*') {
+        Some(prefix) => installed.starts_with(prefix),
+        None => installed == pin,
+    }
+}
+
+// Installed pinned packages whose version isn't the pinned one: name → (pin, installed version)
+fn pin_mismatches<'a>(list: &'a PkgList, versions: &'a BTreeMap<String, String>) -> BTreeMap<&'a str, (&'a str, &'a str)> {
+    list.iter()
+        .filter_map(|(p, entry)| {
+            let (pin, installed) = (entry.version.as_deref()?, versions.get(p)?);
+            (!version_matches_pin(installed, pin)).then_some((p.as_str(), (pin, installed.as_str())))
+        })
+        .collect()
+}
+
+// mtime + size snapshot taken at load, to catch edits made while we were working
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+struct FileStamp {
+    modified: Option<SystemTime>,
+    len: u64,
+}
+
+fn file_stamp(path: &Path) -> Option<FileStamp> {
+    let meta = fs::metadata(path).ok()?;
+    Some(FileStamp { modified: meta.modified().ok(), len: meta.len() })
+}
+
+// Saves pkgs unless the file changed since `loaded`; then re-loads it and re-applies
+// the caller's edit instead of clobbering the external changes. Returns whether it merged.
+fn save_merging(
+    path: &Path,
+    loaded: Option<FileStamp>,
+    pkgs: &PkgList,
+    reapply: impl Fn(&mut PkgList),
+) -> io::Result<bool> {
+    if file_stamp(path) == loaded {
+        save_packages(path, pkgs)?;
+        return Ok(false);
+    }
+    eprintln!("{YELLOW}{ICON_WARN}{} was modified externally, merging changes{RESET}", path.display());
+    let mut fresh = load_list(path);
+    reapply(&mut fresh);
+    save_packages(path, &fresh)?;
+    Ok(true)
+}
+
+// ── Remote lists ────────────────────────────────────────────────────
+
+const REMOTE_MAX_AGE: Duration = Duration::from_secs(5 * 60);
+
+fn is_url(s: &str) -> bool {
+    s.starts_with("http://") || s.starts_with("https://")
+}
+
+// Commands that write the list back can't work on a fetched copy
+fn modifies_list(cmd: &str, args: &[String]) -> bool {
+    let has_arg = |flag: &str| args.iter().any(|a| a == flag);
+    matches!(cmd, "add" | "a" | "remove" | "rm" | "snap" | "import" | "init" | "fmt")
+        || (cmd == "tags" && args.iter().any(|a| !a.starts_with('-')))
+        || (matches!(cmd, "uninstall" | "un") && has_arg("--forget"))
+        || (cmd == "recommends" && !has_arg("--dry-run"))
+}
+
+const LIST_LOCK_WAIT: Duration = Duration::from_secs(10);
+
+// Advisory flock on `<file>.lock`, held from load to save so two concurrent edits can't drop
+// each other's changes. Ok(None) when the list's directory doesn't exist yet (init creates it).
+fn lock_list(path: &Path, wait: Duration) -> io::Result<Option<fs::File>> {
+    let target = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let lock_path = sibling_path(&target, ".lock");
+    // A lock file left root-owned by a sudo run still locks: flock works on a read-only descriptor
+    let opened = match fs::OpenOptions::new().create(true).truncate(false).write(true).open(&lock_path) {
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => fs::File::open(&lock_path),
+        opened => opened,
     };
-    for c in &changed {
-        println!("  {color}{sym} {c}{RESET}");
+    let lock = match opened {
+        Ok(f) => f,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let start = std::time::Instant::now();
+    loop {
+        match lock.try_lock() {
+            Ok(()) => return Ok(Some(lock)),
+            Err(fs::TryLockError::WouldBlock) if start.elapsed() < wait => thread::sleep(Duration::from_millis(100)),
+            Err(fs::TryLockError::WouldBlock) => return Err(io::ErrorKind::WouldBlock.into()),
+            Err(fs::TryLockError::Error(e)) => return Err(e),
+        }
     }
-    for u in &unchanged {
-        println!("  {DIM}  {u} ({skip_msg}){RESET}");
+}
+
+fn remote_cache_path(url: &str) -> PathBuf {
+    use std::hash::{DefaultHasher, Hash, Hasher};
+    let mut h = DefaultHasher::new();
+    url.hash(&mut h);
+    let home = env::var("HOME").unwrap_or_else(|_| ".".into());
+    env::var("XDG_CACHE_HOME")
+        .map_or_else(|_| PathBuf::from(&home).join(".cache"), PathBuf::from)
+        .join("apt-sync")
+        .join(format!("remote-{:016x}.txt", h.finish()))
+}
+
+fn fetch_url(url: &str) -> io::Result<String> {
+    let output = Command::new("curl").args(["-fsSL", "--max-time", "15", url]).output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(String::from_utf8_lossy(&output.stderr).trim().to_string()));
     }
-    if !changed.is_empty() {
-        println!("\n{CYAN}📝 {verb} {} package(s) from packages.txt{RESET}", changed.len());
+    String::from_utf8(output.stdout).map_err(|_| io::Error::other("response is not UTF-8 text"))
+}
+
+// Returns a local copy of the list at `url`, refetched once older than `max_age`.
+// If the fetch fails, a stale copy beats no list at all.
+fn remote_list(
+    url: &str,
+    cache: &Path,
+    max_age: Duration,
+    fetch: impl FnOnce(&str) -> io::Result<String>,
+) -> io::Result<PathBuf> {
+    let age = fs::metadata(cache).and_then(|m| m.modified()).ok().and_then(|t| t.elapsed().ok());
+    if age.is_some_and(|a| a < max_age) {
+        return Ok(cache.to_path_buf());
+    }
+    match fetch(url) {
+        Ok(body) => {
+            if let Some(dir) = cache.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            fs::write(cache, body)?;
+            Ok(cache.to_path_buf())
+        }
+        Err(e) if age.is_some() => {
+            eprintln!("{YELLOW}{ICON_WARN}Cannot fetch {url} ({e}), using cached copy{RESET}");
+            Ok(cache.to_path_buf())
+        }
+        Err(e) => Err(e),
     }
 }
 
-fn cmd_install(pkg_path: &Path, dry_run: bool) {
-    let pkgs = load_packages(pkg_path);
+// ── System queries ──────────────────────────────────────────────────
+
+// --dpkg-status: answer package queries from a copied status file instead of this system
+static DPKG_STATUS: OnceLock<PathBuf> = OnceLock::new();
+
+// Only read-only views may see another machine's state; anything that plans apt-get or apt-mark
+// calls from it would act on this host
+fn reads_dpkg_status(cmd: &str) -> bool {
+    matches!(cmd, "status" | "s" | "diff" | "d" | "list" | "ls" | "check")
+}
+
+fn read_dpkg_status(path: &Path) -> String {
+    fs::read_to_string(path).unwrap_or_else(|e| {
+        eprintln!("{RED}Cannot read {}: {e}{RESET}", path.display());
+        std::process::exit(1);
+    })
+}
+
+// apt tooling missing (non-Debian box, slim container) is a user-facing error, not a panic
+fn tool_error(tool: &str, err: &io::Error) -> String {
+    if err.kind() == io::ErrorKind::NotFound {
+        format!("{tool} not found — is this a Debian/Ubuntu system?")
+    } else {
+        format!("failed to run {tool}: {err}")
+    }
+}
+
+fn run_tool<T>(tool: &str, result: io::Result<T>) -> T {
+    result.unwrap_or_else(|e| {
+        eprintln!("{RED}{}{RESET}", tool_error(tool, &e));
+        std::process::exit(1);
+    })
+}
+
+// Debian control stanzas (dpkg status, apt extended_states) → field maps; continuation lines are skipped
+fn parse_control_stanzas(contents: &str) -> Vec<BTreeMap<&str, &str>> {
+    contents
+        .split("\n\n")
+        .map(|stanza| {
+            stanza
+                .lines()
+                .filter(|l| !l.starts_with([' ', '\t']))
+                .filter_map(|l| l.split_once(':'))
+                .map(|(k, v)| (k.trim(), v.trim()))
+                .collect::<BTreeMap<_, _>>()
+        })
+        .filter(|fields| fields.contains_key("Package"))
+        .collect()
+}
+
+// Status stanzas in the same `pkg\tarch\tstatus\tversion` rows dpkg_query produces
+fn status_query_rows(contents: &str) -> String {
+    parse_control_stanzas(contents)
+        .iter()
+        .map(|f| {
+            let field = |k| f.get(k).copied().unwrap_or("");
+            format!("{}\t{}\t{}\t{}\n", field("Package"), field("Architecture"), field("Status"), field("Version"))
+        })
+        .collect()
+}
+
+// Installed per the status file, minus what apt's extended_states (if copied alongside) marks automatic
+fn status_manual_packages(status: &str, extended_states: Option<&str>) -> BTreeSet<String> {
+    let auto = extended_states.map(extended_states_auto).unwrap_or_default();
+    parse_control_stanzas(status)
+        .iter()
+        .filter(|f| f.get("Status").is_some_and(|s| is_installed_status(s)))
+        .filter_map(|f| f.get("Package").copied())
+        .filter(|p| !auto.contains(*p))
+        .map(String::from)
+        .collect()
+}
+
+fn extended_states_auto(contents: &str) -> BTreeSet<String> {
+    parse_control_stanzas(contents)
+        .iter()
+        .filter(|f| f.get("Auto-Installed") == Some(&"1"))
+        .filter_map(|f| f.get("Package").map(|p| p.to_string()))
+        .collect()
+}
+
+fn extended_states_beside(status_path: &Path) -> Option<String> {
+    fs::read_to_string(status_path.parent()?.join("extended_states")).ok()
+}
+
+// Packages apt would autoremove once nothing depends on them
+fn system_auto_packages() -> BTreeSet<String> {
+    if let Some(path) = DPKG_STATUS.get() {
+        return extended_states_beside(path).map(|e| extended_states_auto(&e)).unwrap_or_default();
+    }
+    let Ok(output) = Command::new("apt-mark").arg("showauto").output() else {
+        return BTreeSet::new();
+    };
+    String::from_utf8_lossy(&output.stdout).lines().map(str::trim).filter(|l| !l.is_empty()).map(String::from).collect()
+}
+
+fn system_manual_packages() -> BTreeSet<String> {
+    if let Some(path) = DPKG_STATUS.get() {
+        return status_manual_packages(&read_dpkg_status(path), extended_states_beside(path).as_deref());
+    }
+    let output = run_tool("apt-mark", Command::new("apt-mark").arg("showmanual").output());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    stdout
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+// How an unqualified curated name matches dpkg's per-architecture rows
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum ArchMode {
+    Native(String), // --dpkg-query-arch=native: only the native arch (or `all`) counts
+    Any,            // the default (or --dpkg-query-arch=all): any installed arch counts
+}
+
+// Any architecture counts unless `--dpkg-query-arch=native` asks for the strict match, so an
+// i386-only `libfoo` stays installed for multiarch users as it always was
+fn arch_mode(flag: Option<&str>) -> ArchMode {
+    if flag != Some("native") {
+        return ArchMode::Any;
+    }
+    Command::new("dpkg")
+        .arg("--print-architecture")
+        .output()
+        .ok()
+        .map(|o| String::from_utf8_lossy(&o.stdout).trim().to_string())
+        .filter(|a| !a.is_empty())
+        .map_or(ArchMode::Any, ArchMode::Native)
+}
+
+// The subset of pkgs that is installed
+fn installed_set(pkgs: &BTreeSet<String>, arch: &ArchMode) -> BTreeSet<String> {
+    parse_installed(&dpkg_query(pkgs), arch).intersection(pkgs).cloned().collect()
+}
+
+// Installed packages mapped to their version, in a single dpkg-query call
+fn installed_versions(pkgs: &BTreeSet<String>, arch: &ArchMode) -> BTreeMap<String, String> {
+    parse_installed_versions(&dpkg_query(pkgs), arch)
+}
+
+const DPKG_STATUS_PATH: &str = "/var/lib/dpkg/status";
+
+// Past this many names, one read of the status file beats dpkg-query with a huge argv
+const STATUS_FILE_FAST_PATH: usize = 500;
+
+fn dpkg_query(pkgs: &BTreeSet<String>) -> String {
     if pkgs.is_empty() {
-        println!("{YELLOW}📭 No curated packages to install.{RESET}");
-        return;
+        return String::new();
+    }
+    if let Some(path) = DPKG_STATUS.get() {
+        return status_query_rows(&read_dpkg_status(path));
+    }
+    if pkgs.len() > STATUS_FILE_FAST_PATH
+        && let Ok(status) = fs::read_to_string(DPKG_STATUS_PATH)
+    {
+        return status_query_rows(&status);
+    }
+    run_dpkg_query(pkgs)
+}
+
+// Names per dpkg-query call, well under ARG_MAX even for long package names
+const DPKG_QUERY_CHUNK: usize = 500;
+
+fn run_dpkg_query(pkgs: &BTreeSet<String>) -> String {
+    run_dpkg_query_with(|| Command::new("dpkg-query"), pkgs)
+}
+
+// `dpkg_query` builds the bare command, so tests can swap in a stub
+fn run_dpkg_query_with(dpkg_query: impl Fn() -> Command, pkgs: &BTreeSet<String>) -> String {
+    query_in_chunks(pkgs, DPKG_QUERY_CHUNK, |chunk| {
+        let query = dpkg_query()
+            .args(["-W", "-f=${Package}\t${Architecture}\t${Status}\t${Version}\n"])
+            .args(chunk)
+            .stderr(Stdio::null())
+            .output();
+        let output = run_tool("dpkg-query", query);
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    })
+}
+
+// Rows are per package, so the chunks' outputs simply concatenate
+fn query_in_chunks(pkgs: &BTreeSet<String>, size: usize, mut run: impl FnMut(&[&String]) -> String) -> String {
+    let pkgs: Vec<&String> = pkgs.iter().collect();
+    pkgs.chunks(size).map(&mut run).collect()
+}
+
+fn parse_installed(output: &str, arch: &ArchMode) -> BTreeSet<String> {
+    parse_installed_versions(output, arch).into_keys().collect()
+}
+
+// dpkg status is `want flag state`; the package is on disk whatever the want (install, hold, ...)
+fn is_installed_status(status: &str) -> bool {
+    status.split_whitespace().nth(2) == Some("installed")
+}
+
+// Rows are `pkg\tarch\tstatus[\tversion]`; yields (pkg, arch, status, version) for installed rows
+fn installed_rows(output: &str) -> impl Iterator<Item = (&str, &str, &str, &str)> {
+    output.lines().filter_map(|line| {
+        let mut fields = line.split('\t');
+        let (pkg, pkg_arch, status) = (fields.next()?, fields.next()?, fields.next()?);
+        (!pkg.is_empty() && is_installed_status(status)).then(|| (pkg, pkg_arch, status, fields.next().unwrap_or("")))
+    })
+}
+
+// Each installed row answers to `pkg:arch`, and to bare `pkg` when the arch mode accepts it
+fn row_keys(pkg: &str, pkg_arch: &str, arch: &ArchMode) -> Vec<String> {
+    let bare = match arch {
+        ArchMode::Any => true,
+        ArchMode::Native(native) => pkg_arch == native || pkg_arch == "all",
+    };
+    let mut keys = Vec::new();
+    if bare {
+        keys.push(pkg.to_string());
+    }
+    if !pkg_arch.is_empty() {
+        keys.push(format!("{pkg}:{pkg_arch}"));
     }
-    let installed = installed_set(&pkgs);
-    let missing: Vec<&str> = pkgs
+    keys
+}
+
+fn parse_installed_versions(output: &str, arch: &ArchMode) -> BTreeMap<String, String> {
+    installed_rows(output)
+        .flat_map(|(pkg, pkg_arch, _, version)| row_keys(pkg, pkg_arch, arch).into_iter().map(move |k| (k, version.to_string())))
+        .collect()
+}
+
+// Installed packages pinned with `apt-mark hold`
+fn parse_held(output: &str, arch: &ArchMode) -> BTreeSet<String> {
+    installed_rows(output)
+        .filter(|(_, _, status, _)| status.starts_with("hold "))
+        .flat_map(|(pkg, pkg_arch, _, _)| row_keys(pkg, pkg_arch, arch))
+        .collect()
+}
+
+// ── JSON ────────────────────────────────────────────────────────────
+
+fn json_str(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            '\r' => out.push_str("\\r"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn status_json(pkgs: &BTreeSet<String>, installed: &BTreeSet<String>) -> String {
+    let names = |installed_side: bool| -> String {
+        let items: Vec<String> = pkgs.iter().filter(|p| installed.contains(*p) == installed_side).map(|p| json_str(p)).collect();
+        format!("[{}]", items.join(", "))
+    };
+    format!("{{\"curated\": {}, \"installed\": {}, \"missing\": {}}}", pkgs.len(), names(true), names(false))
+}
+
+fn list_json(pkgs: &BTreeSet<String>, versions: &BTreeMap<String, String>) -> String {
+    let items: Vec<String> = pkgs
         .iter()
-        .filter(|p| !installed.contains(*p))
-        .map(String::as_str)
+        .map(|p| match versions.get(p) {
+            Some(v) => format!("  {{\"name\": {}, \"installed\": true, \"version\": {}}}", json_str(p), json_str(v)),
+            None => format!("  {{\"name\": {}, \"installed\": false}}", json_str(p)),
+        })
         .collect();
-    if missing.is_empty() {
-        println!("{GREEN}✨ All {} curated packages are already installed!{RESET}", pkgs.len());
-        return;
+    if items.is_empty() { "[]".to_string() } else { format!("[\n{}\n]", items.join(",\n")) }
+}
+
+// ── Export ──────────────────────────────────────────────────────────
+
+// `ansible.builtin.apt` tasks; `default_release` is per task, so each release gets its own.
+// Names are emitted as JSON strings, which YAML reads as plain double-quoted scalars.
+fn ansible_tasks(list: &PkgList) -> String {
+    let mut by_release: BTreeMap<Option<&str>, Vec<String>> = BTreeMap::new();
+    for (p, entry) in list {
+        // The apt module takes `name=version` pins as-is; releases go in default_release
+        let name = entry.version.as_ref().map_or_else(|| p.clone(), |v| format!("{p}={v}"));
+        by_release.entry(entry.release.as_deref()).or_default().push(name);
+    }
+    let mut out = String::new();
+    for (release, names) in by_release {
+        match release {
+            Some(r) => out.push_str(&format!("- name: Install curated packages from {r}\n")),
+            None => out.push_str("- name: Install curated packages\n"),
+        }
+        out.push_str("  ansible.builtin.apt:\n    name:\n");
+        for n in names {
+            out.push_str(&format!("      - {}\n", json_str(&n)));
+        }
+        out.push_str("    state: present\n");
+        if let Some(r) = release {
+            out.push_str(&format!("    default_release: {}\n", json_str(r)));
+        }
+    }
+    out
+}
+
+// One RUN layer, a package per line so list changes diff cleanly; apt's lists are dropped after
+fn dockerfile_run(list: &PkgList) -> String {
+    if list.is_empty() {
+        return String::new();
+    }
+    let mut out = String::from("RUN apt-get update && apt-get install -y \\\n");
+    for (p, entry) in list {
+        out.push_str(&format!("    {} \\\n", install_spec(p, entry)));
+    }
+    out.push_str(" && rm -rf /var/lib/apt/lists/*\n");
+    out
+}
+
+// For `dpkg --set-selections`, which knows nothing of releases or version pins
+fn dpkg_selections(list: &PkgList) -> String {
+    list.keys().map(|p| format!("{p}\tinstall\n")).collect()
+}
+
+// Every entry with the fields it has set; tags always, as an array
+fn export_json(list: &PkgList) -> String {
+    let items: Vec<String> = list
+        .iter()
+        .map(|(p, entry)| {
+            let mut fields = vec![format!("\"name\": {}", json_str(p))];
+            for (key, value) in [("release", &entry.release), ("version", &entry.version), ("section", &entry.section)] {
+                if let Some(v) = value {
+                    fields.push(format!("\"{key}\": {}", json_str(v)));
+                }
+            }
+            let tags: Vec<String> = entry.tags.iter().map(|t| json_str(t)).collect();
+            fields.push(format!("\"tags\": [{}]", tags.join(", ")));
+            format!("  {{{}}}", fields.join(", "))
+        })
+        .collect();
+    if items.is_empty() { "[]".to_string() } else { format!("[\n{}\n]", items.join(",\n")) }
+}
+
+fn cmd_export(pkg_path: &Path, format: Option<&str>) -> ExitCode {
+    let list = load_list(pkg_path);
+    match format.unwrap_or("plain") {
+        "plain" => {
+            for (p, entry) in &list {
+                println!("{}", install_spec(p, entry));
+            }
+        }
+        "ansible" => print!("{}", ansible_tasks(&list)),
+        "selections" => print!("{}", dpkg_selections(&list)),
+        "dockerfile" => print!("{}", dockerfile_run(&list)),
+        "json" => println!("{}", export_json(&list)),
+        other => {
+            eprintln!("{RED}Unknown export format: {other} (expected plain, selections, json, dockerfile or ansible){RESET}");
+            return ExitCode::FAILURE;
+        }
+    }
+    ExitCode::SUCCESS
+}
+
+// ── Protected packages ──────────────────────────────────────────────
+
+const BUILTIN_PROTECTED: &[&str] = &[
+    "apt", "base-files", "bash", "coreutils", "dpkg", "init", "libc6", "login", "passwd", "sudo", "systemd",
+];
+
+// Built-ins + $APT_SYNC_PROTECTED + the running kernel + dpkg's Essential packages
+fn protected_packages() -> BTreeSet<String> {
+    let mut set: BTreeSet<String> = BUILTIN_PROTECTED.iter().map(|p| (*p).to_string()).collect();
+    if let Ok(extra) = env::var("APT_SYNC_PROTECTED") {
+        set.extend(parse_protected_list(&extra));
+    }
+    if let Some(kernel) = running_kernel_package() {
+        set.insert(kernel);
+    }
+    set.extend(essential_packages());
+    set
+}
+
+fn parse_protected_list(list: &str) -> BTreeSet<String> {
+    list.split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|p| !p.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+fn running_kernel_package() -> Option<String> {
+    let output = Command::new("uname").arg("-r").output().ok()?;
+    let release = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    (!release.is_empty()).then(|| format!("linux-image-{release}"))
+}
+
+fn essential_packages() -> BTreeSet<String> {
+    Command::new("dpkg-query")
+        .args(["-W", "-f=${Package}\t${Essential}\n"])
+        .stderr(Stdio::null())
+        .output()
+        .map_or_else(|_| BTreeSet::new(), |o| parse_essential(&String::from_utf8_lossy(&o.stdout)))
+}
+
+fn parse_essential(output: &str) -> BTreeSet<String> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let (pkg, essential) = line.split_once('\t')?;
+            (essential.trim() == "yes").then(|| pkg.to_string())
+        })
+        .collect()
+}
+
+// Splits removal candidates into (allowed, protected)
+fn plan_removal<'a>(candidates: &[&'a str], protected: &BTreeSet<String>) -> (Vec<&'a str>, Vec<&'a str>) {
+    candidates.iter().partition(|p| !protected.contains(**p))
+}
+
+fn guard_removal<'a>(candidates: &[&'a str], protected: &BTreeSet<String>) -> Vec<&'a str> {
+    let (allowed, blocked) = plan_removal(candidates, protected);
+    for p in &blocked {
+        eprintln!("{YELLOW}{ICON_SHIELD}Refusing to remove protected package: {p}{RESET}");
+    }
+    allowed
+}
+
+// ── Apt runner ──────────────────────────────────────────────────────
+
+// `Uid:\treal\teffective\tsaved\tfs` from /proc/self/status
+fn parse_euid(status: &str) -> Option<u32> {
+    status.lines().find_map(|l| l.strip_prefix("Uid:"))?.split_whitespace().nth(1)?.parse().ok()
+}
+
+fn is_root() -> bool {
+    fs::read_to_string("/proc/self/status").ok().as_deref().and_then(parse_euid).is_some_and(|uid| uid == 0)
+}
+
+// Set on the sudo re-run, so a sudo that still isn't root can't loop
+const REEXEC_ENV: &str = "APT_SYNC_SUDO";
+
+// Skips the root gate, for an apt-get that doesn't need it (test stubs, fakeroot, containers)
+const NO_ROOT_CHECK_ENV: &str = "APT_SYNC_NO_ROOT_CHECK";
+
+// sudo drops the environment, so the resolved list path rides along through `env`
+fn sudo_reexec_command(exe: &Path, args: &[String], pkg_path: &Path) -> Command {
+    let mut cmd = Command::new("sudo");
+    cmd.args(["--", "env", &format!("{REEXEC_ENV}=1")]);
+    cmd.arg(format!("APT_SYNC_FILE={}", pkg_path.display()));
+    cmd.arg(exe).args(args);
+    cmd
+}
+
+// Commands that run apt-get or apt-mark; a missing package argument fails on usage first
+fn needs_root(cmd: &str, dry_run: bool, no_names: bool) -> bool {
+    match cmd {
+        "install" | "i" | "sync" | "upgrade" => !dry_run,
+        "uninstall" | "un" | "mark-auto" => !dry_run && !no_names,
+        _ => false,
+    }
+}
+
+// For commands that run apt-get: None when already root, else the exit of a sudo re-run
+// (when someone is at the terminal to type a password) or a clear error
+fn require_root(cmd: &str, pkg_path: &Path) -> Option<ExitCode> {
+    if is_root() || env::var_os(NO_ROOT_CHECK_ENV).is_some_and(|v| v == "1") {
+        return None;
+    }
+    let args: Vec<String> = env::args().skip(1).collect();
+    if env::var_os(REEXEC_ENV).is_none()
+        && io::stdin().is_terminal()
+        && on_path("sudo")
+        && let Ok(exe) = env::current_exe()
+    {
+        eprintln!("{DIM}`{cmd}` needs root, re-running with sudo…{RESET}");
+        let status = sudo_reexec_command(&exe, &args, pkg_path).status();
+        return Some(match status.ok().and_then(|s| s.code()) {
+            Some(0) => ExitCode::SUCCESS,
+            Some(code) => ExitCode::from(u8::try_from(code).unwrap_or(1)),
+            None => ExitCode::FAILURE,
+        });
+    }
+    let tool = if cmd == "mark-auto" { "apt-mark" } else { "apt-get" };
+    eprintln!("{RED}{ICON_ERROR}`{cmd}` runs {tool}, which needs root — try `sudo apt-sync {}`{RESET}", args.join(" "));
+    Some(ExitCode::FAILURE)
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum AptOutcome {
+    Success,
+    Locked,
+    Failed,
+}
+
+fn is_lock_error(stderr: &str) -> bool {
+    stderr.contains("Could not get lock") || stderr.contains("Unable to acquire the dpkg frontend lock")
+}
+
+// `--jobs N`: APT has no download-count knob, so N becomes the HTTP pipeline depth
+// (requests in flight per server), with one download queue per access method instead of per host
+fn apt_jobs_options(jobs: u32) -> [String; 4] {
+    ["-o".into(), "Acquire::Queue-Mode=access".into(), "-o".into(), format!("Acquire::http::Pipeline-Depth={jobs}")]
+}
+
+const MAX_JOBS: u32 = 64;
+
+fn parse_jobs(value: &str) -> Result<u32, String> {
+    match value.parse() {
+        Ok(n) if (1..=MAX_JOBS).contains(&n) => Ok(n),
+        _ => Err(format!("--jobs must be a number from 1 to {MAX_JOBS}: {value}")),
+    }
+}
+
+fn apt_get_install_command(pkgs: &[&str], jobs: Option<u32>, reinstall: bool) -> Command {
+    let mut cmd = Command::new("apt-get");
+    cmd.arg("install");
+    if reinstall {
+        cmd.arg("--reinstall");
+    }
+    cmd.arg("-y");
+    if let Some(jobs) = jobs {
+        cmd.args(apt_jobs_options(jobs));
+    }
+    cmd.args(pkgs);
+    cmd
+}
+
+fn apt_get_install(pkgs: &[&str], detect_lock: bool, jobs: Option<u32>, reinstall: bool) -> AptOutcome {
+    let mut cmd = apt_get_install_command(pkgs, jobs, reinstall);
+    if !detect_lock {
+        let status = run_tool("apt-get", cmd.status());
+        return if status.success() { AptOutcome::Success } else { AptOutcome::Failed };
+    }
+    // stderr is captured to spot lock contention, then echoed so the user still sees it
+    let output = run_tool("apt-get", cmd.stdout(Stdio::inherit()).stderr(Stdio::piped()).output());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    eprint!("{stderr}");
+    if output.status.success() {
+        AptOutcome::Success
+    } else if is_lock_error(&stderr) {
+        AptOutcome::Locked
+    } else {
+        AptOutcome::Failed
+    }
+}
+
+// `remove` keeps config files, `purge` deletes them too
+fn apt_get_remove_command(pkgs: &[&str], purge: bool) -> Command {
+    let mut cmd = Command::new("apt-get");
+    cmd.args([if purge { "purge" } else { "remove" }, "-y"]).args(pkgs);
+    cmd
+}
+
+// `name[:arch][=version|/release]` → `name[:arch]`, the form dpkg-query understands
+fn spec_name(spec: &str) -> &str {
+    spec.split_once(['=', '/']).map_or(spec, |(n, _)| n)
+}
+
+// `name[:arch][=version|/release]` → (name, arch); pins are what make duplicates differ
+fn spec_key(spec: &str) -> (&str, Option<&str>) {
+    let unpinned = spec_name(spec);
+    unpinned.split_once(':').map_or((unpinned, None), |(n, a)| (n, Some(a)))
+}
+
+// Collapses specs naming the same package, keeping a version-pinned one when present.
+// Returns the kept specs plus (dropped, kept) pairs for reporting.
+fn dedupe_install_specs<'a>(specs: &[&'a str]) -> (Vec<&'a str>, Vec<(&'a str, &'a str)>) {
+    let mut kept: Vec<&str> = Vec::new();
+    let mut dropped = Vec::new();
+    for &spec in specs {
+        match kept.iter_mut().find(|k| spec_key(k) == spec_key(spec)) {
+            None => kept.push(spec),
+            Some(k) if spec.contains(['=', '/']) && !k.contains(['=', '/']) => {
+                dropped.push((*k, spec));
+                *k = spec;
+            }
+            Some(k) => dropped.push((spec, *k)),
+        }
+    }
+    (kept, dropped)
+}
+
+fn retry_on_lock(
+    mut run: impl FnMut() -> AptOutcome,
+    max_wait: Duration,
+    mut sleep: impl FnMut(Duration),
+) -> AptOutcome {
+    let mut waited = Duration::ZERO;
+    let mut delay = Duration::from_secs(2);
+    loop {
+        let outcome = run();
+        if outcome != AptOutcome::Locked || waited >= max_wait {
+            return outcome;
+        }
+        let step = delay.min(max_wait - waited);
+        eprintln!("{YELLOW}{ICON_WAIT}dpkg lock held by another process, retrying in {}s...{RESET}", step.as_secs());
+        sleep(step);
+        waited += step;
+        delay = (delay * 2).min(Duration::from_secs(30));
+    }
+}
+
+// ── Gzip ────────────────────────────────────────────────────────────
+
+// Oldest first, as given; unreadable or corrupt files are skipped, like zcat's errors were
+fn read_gz_logs(paths: &[PathBuf]) -> String {
+    let mut buf = String::new();
+    for path in paths {
+        let mut text = Vec::new();
+        // MultiGzDecoder reads every member, for logs that were appended to after compressing
+        let read = fs::File::open(path).and_then(|f| MultiGzDecoder::new(f).read_to_end(&mut text));
+        if read.is_ok() {
+            buf.push_str(&String::from_utf8_lossy(&text));
+        }
+    }
+    buf
+}
+
+// ── Apt history ─────────────────────────────────────────────────────
+
+struct HistoryEntry {
+    date: String,
+    commandline: String,
+    requested_by: Option<String>,
+    installed: Vec<String>,
+    removed: Vec<String>, // `Remove:` — config files stay behind
+    purged: Vec<String>,  // `Purge:` — config files deleted too
+}
+
+// Rotated logs, oldest first (highest number = oldest)
+fn rotated_history_logs() -> Vec<PathBuf> {
+    rotated_logs("/var/log/apt", "history")
+}
+
+fn rotated_logs(dir: &str, prefix: &str) -> Vec<PathBuf> {
+    let mut gz_paths: Vec<PathBuf> = fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(Result::ok)
+        .map(|e| e.path())
+        .filter(|p| p.file_name().is_some_and(|n| { let s = n.to_string_lossy(); s.starts_with(prefix) && s.ends_with(".gz") }))
+        .collect();
+    gz_paths.sort_by(|a, b| b.cmp(a));
+    gz_paths
+}
+
+// ── dpkg.log ────────────────────────────────────────────────────────
+
+// One `<date> <time> <action> <pkg:arch> <old-version> <new-version>` line
+#[derive(Debug, PartialEq, Eq)]
+struct DpkgLogEvent {
+    date: String, // `YYYY-MM-DD HH:MM:SS`
+    action: String,
+    package: String, // arch stripped, like history names
+    old_version: String,
+    new_version: String,
+}
+
+// Rotated .gz logs, then dpkg.log.1 and dpkg.log; unreadable ones are skipped
+fn read_dpkg_logs() -> String {
+    let mut buf = String::new();
+    buf.push_str(&read_gz_logs(&rotated_logs("/var/log", "dpkg.log")));
+    for path in ["/var/log/dpkg.log.1", "/var/log/dpkg.log"] {
+        buf.push_str(&fs::read_to_string(path).unwrap_or_default());
+    }
+    buf
+}
+
+// Only package actions; `status`, `configure` and `trigproc` lines are progress noise
+fn parse_dpkg_log(log: &str) -> Vec<DpkgLogEvent> {
+    log.lines()
+        .filter_map(|line| {
+            let mut words = line.split_whitespace();
+            let (day, time, action) = (words.next()?, words.next()?, words.next()?);
+            if !matches!(action, "install" | "upgrade" | "remove" | "purge") {
+                return None;
+            }
+            let (package, old_version, new_version) = (words.next()?, words.next()?, words.next()?);
+            Some(DpkgLogEvent {
+                date: format!("{day} {time}"),
+                action: action.to_string(),
+                package: strip_arch(package).to_string(),
+                old_version: old_version.to_string(),
+                new_version: new_version.to_string(),
+            })
+        })
+        .collect()
+}
+
+// An unreadable log needs a sudo hint; a missing one just means no history
+#[derive(Debug, PartialEq, Eq)]
+enum LogRead {
+    Read(String),
+    Denied,
+    Missing,
+}
+
+fn classify_log_read(result: io::Result<String>) -> LogRead {
+    match result {
+        Ok(contents) => LogRead::Read(contents),
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => LogRead::Denied,
+        Err(_) => LogRead::Missing,
+    }
+}
+
+// Returns the combined log and whether the current history.log was permission-denied
+fn read_history_logs(gz_paths: &[PathBuf]) -> (String, bool) {
+    let mut buf = read_gz_logs(gz_paths);
+
+    match classify_log_read(fs::read_to_string("/var/log/apt/history.log")) {
+        LogRead::Read(current) => buf.push_str(&current),
+        LogRead::Denied => return (buf, true),
+        LogRead::Missing => {}
+    }
+    (buf, false)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum HistorySource {
+    Auto, // /var/log/apt, falling back to the journal when it yields nothing
+    File,
+    Journal,
+}
+
+// sudo's journal records of apt installs, for hosts where /var/log/apt is empty or unreadable
+fn read_journal_history() -> Vec<HistoryEntry> {
+    Command::new("journalctl")
+        .args(["_COMM=sudo", "--no-pager", "-o", "short-iso"])
+        .output()
+        .map_or_else(|_| Vec::new(), |o| parse_journal_history(&String::from_utf8_lossy(&o.stdout)))
+}
+
+// "2025-08-10T10:00:00+1000 host sudo[42]: alice : TTY=pts/0 ; PWD=/src ; USER=root ; COMMAND=/usr/bin/apt install jq"
+// Only the packages named on the command line are known, not what apt pulled in with them.
+fn parse_journal_history(journal: &str) -> Vec<HistoryEntry> {
+    journal
+        .lines()
+        .filter_map(|line| {
+            let (head, fields) = line.split_once("]: ")?;
+            let (day, time) = head.split_whitespace().next()?.split_once('T')?;
+            let time = time.get(..8)?;
+            let user = fields.split(" : ").next()?.trim();
+            let mut words = fields[fields.find("COMMAND=")? + 8..].split_whitespace();
+            let tool = words.next()?.rsplit('/').next()?;
+            if !matches!(tool, "apt" | "apt-get" | "aptitude") {
+                return None;
+            }
+            let args: Vec<&str> = words.collect();
+            let start = args.iter().position(|w| *w == "install")? + 1;
+            let installed: Vec<String> = args[start..]
+                .iter()
+                .filter(|w| !w.starts_with('-'))
+                .map(|w| strip_arch(spec_name(w)).to_string())
+                .collect();
+            (!installed.is_empty()).then(|| HistoryEntry {
+                date: format!("{day}  {time}"),
+                commandline: format!("{tool} {}", args.join(" ")),
+                requested_by: (!user.is_empty()).then(|| user.to_string()),
+                installed,
+                removed: Vec::new(),
+                purged: Vec::new(),
+            })
+        })
+        .collect()
+}
+
+fn parse_history(log: &str) -> Vec<HistoryEntry> {
+    log.split("\n\n")
+        .filter_map(|block| {
+            let mut date = "";
+            let mut cmdline = "";
+            let mut requested = None;
+            let (mut installed, mut removed, mut purged) = (Vec::new(), Vec::new(), Vec::new());
+            for line in block.lines() {
+                if let Some(d) = line.strip_prefix("Start-Date: ") { date = d.trim(); }
+                else if let Some(c) = line.strip_prefix("Commandline: ") { cmdline = c.trim(); }
+                else if let Some(r) = line.strip_prefix("Requested-By: ") { requested = Some(r.trim().to_string()); }
+                else if let Some(pkgs) = line.strip_prefix("Install: ") { installed = parse_history_packages(pkgs); }
+                else if let Some(pkgs) = line.strip_prefix("Remove: ") { removed = parse_history_packages(pkgs); }
+                else if let Some(pkgs) = line.strip_prefix("Purge: ") { purged = parse_history_packages(pkgs); }
+            }
+            (!installed.is_empty() || !removed.is_empty() || !purged.is_empty()).then(|| HistoryEntry {
+                date: date.to_string(),
+                commandline: cmdline.to_string(),
+                requested_by: requested,
+                installed,
+                removed,
+                purged,
+            })
+        })
+        .collect()
+}
+
+// `Requested-By:` is usually `name (uid)` but can be a bare `(uid)`; name the uid when we can
+fn requested_by_display(raw: &str, lookup: impl FnOnce(u32) -> Option<String>) -> String {
+    let uid = raw.strip_prefix('(').and_then(|r| r.strip_suffix(')')).unwrap_or(raw);
+    match uid.parse() {
+        Ok(uid) => lookup(uid).map_or_else(|| raw.to_string(), |name| format!("{name} ({uid})")),
+        Err(_) => raw.to_string(),
+    }
+}
+
+// getent also covers LDAP/sssd users that aren't in /etc/passwd
+fn passwd_user(uid: u32) -> Option<String> {
+    let output = Command::new("getent").args(["passwd", &uid.to_string()]).output().ok()?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let name = stdout.split(':').next()?.trim();
+    (output.status.success() && !name.is_empty()).then(|| name.to_string())
+}
+
+fn parse_history_packages(pkgs_line: &str) -> Vec<String> {
+    pkgs_line
+        .split("), ")
+        .filter_map(|entry| {
+            let name = entry.split(':').next().filter(|n| !n.is_empty())?;
+            (!entry.contains("automatic")).then(|| name.to_string())
+        })
+        .collect()
+}
+
+fn strip_arch(pkg: &str) -> &str {
+    pkg.split_once(':').map_or(pkg, |(name, _)| name)
+}
+
+// Most recent first, since log order isn't chronological (`history.log.10.gz` sorts before `.9.gz`);
+// history names carry no arch, so `libc6:amd64` is looked up as `libc6`
+fn find_install_history<'a>(entries: &'a [HistoryEntry], pkg: &str) -> Vec<&'a HistoryEntry> {
+    let pkg = strip_arch(pkg);
+    let mut hits: Vec<&HistoryEntry> = entries.iter().filter(|e| e.installed.iter().any(|p| p == pkg)).collect();
+    hits.sort_by_cached_key(|e| std::cmp::Reverse(normalize_apt_date(&e.date)));
+    hits
+}
+
+// Exactly `YYYY-MM-DD` naming a real day, for --since / --until: the bounds are compared as
+// strings against padded dates, so `2025-8-001` must not slip through
+fn valid_day(day: &str) -> bool {
+    let b = day.as_bytes();
+    if b.len() != 10 || !b.iter().enumerate().all(|(i, c)| if i == 4 || i == 7 { *c == b'-' } else { c.is_ascii_digit() }) {
+        return false;
+    }
+    let num = |from: usize, to: usize| day[from..to].parse::<i64>().unwrap_or(0);
+    let (y, m, d) = (num(0, 4), num(5, 7), num(8, 10));
+    let leap = y % 4 == 0 && (y % 100 != 0 || y % 400 == 0);
+    let month_days = match m {
+        2 if leap => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        1..=12 => 31,
+        _ => 0,
+    };
+    (1..=month_days).contains(&d)
+}
+
+// Compares calendar days, so both bounds are inclusive whatever the time of day
+fn in_date_range(date: &str, since: Option<&str>, until: Option<&str>) -> bool {
+    let normalized = normalize_apt_date(date);
+    let day = normalized.split(' ').next().unwrap_or_default();
+    since.is_none_or(|s| day >= s) && until.is_none_or(|u| day <= u)
+}
+
+// Keeps the first `limit` hits; returns how many were dropped
+fn limit_hits<T>(hits: &mut Vec<T>, limit: Option<usize>) -> usize {
+    let keep = limit.unwrap_or(usize::MAX).min(hits.len());
+    hits.drain(keep..).count()
+}
+
+// Limits newest-first events (so the newest are kept), then flips them for oldest-first display
+fn limit_and_order<T>(events: &mut Vec<T>, limit: Option<usize>, oldest_first: bool) -> usize {
+    let older = limit_hits(events, limit);
+    if oldest_first {
+        events.reverse();
+    }
+    older
+}
+
+// Whichever of --oldest-first / --newest-first comes last wins over the config
+fn why_oldest_first(args: &[String], config_default: bool) -> bool {
+    args.iter()
+        .rev()
+        .find_map(|a| match a.as_str() {
+            "--oldest-first" => Some(true),
+            "--newest-first" => Some(false),
+            _ => None,
+        })
+        .unwrap_or(config_default)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Removal {
+    Removed,
+    Purged,
+}
+
+fn find_removal_history<'a>(entries: &'a [HistoryEntry], pkg: &str) -> Vec<(&'a HistoryEntry, Removal)> {
+    let pkg = strip_arch(pkg);
+    entries
+        .iter()
+        .filter_map(|e| {
+            if e.purged.iter().any(|p| p == pkg) {
+                Some((e, Removal::Purged))
+            } else {
+                e.removed.iter().any(|p| p == pkg).then_some((e, Removal::Removed))
+            }
+        })
+        .collect()
+}
+
+fn removal_line(entry: &HistoryEntry, kind: Removal) -> String {
+    let date = entry.date.split_whitespace().next().unwrap_or(&entry.date);
+    match kind {
+        Removal::Removed => format!("  {YELLOW}{ICON_REMOVED}removed {date}{RESET}  {DIM}{}{RESET}", entry.commandline),
+        Removal::Purged => format!("  {RED}{ICON_PURGED}purged {date}{RESET}  {DIM}{}{RESET}", entry.commandline),
+    }
+}
+
+// One line of `why --timeline`, from apt's history or dpkg.log
+#[derive(Debug, PartialEq, Eq)]
+struct TimelineEvent {
+    date: String, // `YYYY-MM-DD HH:MM:SS`, so events sort as strings
+    source: &'static str,
+    what: String,
+}
+
+// apt knows who asked and why; dpkg also sees upgrades and the dependencies apt marks automatic
+fn build_timeline(entries: &[HistoryEntry], dpkg: &[DpkgLogEvent], pkg: &str) -> Vec<TimelineEvent> {
+    let pkg = strip_arch(pkg);
+    let mut events = Vec::new();
+    for e in entries {
+        for (list, verb) in [(&e.installed, "installed"), (&e.removed, "removed"), (&e.purged, "purged")] {
+            if list.iter().any(|p| p == pkg) {
+                let date = e.date.split_whitespace().collect::<Vec<_>>().join(" ");
+                events.push(TimelineEvent { date, source: "apt", what: format!("{verb} by `{}`", e.commandline) });
+            }
+        }
+    }
+    for d in dpkg.iter().filter(|d| d.package == pkg) {
+        let what = match d.action.as_str() {
+            "install" => format!("install {}", d.new_version),
+            "upgrade" => format!("upgrade {} → {}", d.old_version, d.new_version),
+            action => format!("{action} {}", d.old_version),
+        };
+        events.push(TimelineEvent { date: d.date.clone(), source: "dpkg", what });
+    }
+    // Stable, so apt's event stays ahead of the dpkg lines it caused
+    events.sort_by(|a, b| a.date.cmp(&b.date));
+    events
+}
+
+fn timeline_line(event: &TimelineEvent) -> String {
+    format!("  {GREEN}{}{RESET}  {DIM}{:<6}{RESET} {}", event.date, format!("[{}]", event.source), event.what)
+}
+
+fn format_pkg_list(pkgs: &[&str]) -> String {
+    const MAX: usize = 10;
+    let s = pkgs[..pkgs.len().min(MAX)].join(", ");
+    if pkgs.len() > MAX { format!("{s} + {} more", pkgs.len() - MAX) } else { s }
+}
+
+fn same_day_neighbors<'a>(
+    entries: &'a [HistoryEntry],
+    entry: &HistoryEntry,
+    name: &str,
+    sibling_set: &BTreeSet<&str>,
+) -> Vec<&'a str> {
+    let day = entry.date.split_whitespace().next().unwrap_or("");
+    entries
+        .iter()
+        .filter(|e| {
+            e.date.split_whitespace().next().unwrap_or("") == day
+                && !(e.date == entry.date && e.commandline == entry.commandline)
+        })
+        .flat_map(|e| e.installed.iter().map(String::as_str))
+        .filter(|p| *p != name && !sibling_set.contains(p))
+        .collect::<BTreeSet<&str>>()
+        .into_iter()
+        .collect()
+}
+
+// ── Shell history and journal context ───────────────────────────────
+
+struct ShellHistoryEntry {
+    timestamp: i64,
+    command: String,
+    cwd: Option<String>, // only atuin records where a command ran
+}
+
+fn read_journal_pwd(apt_date: &str, commandline: &str) -> Option<String> {
+    let normalized: String = apt_date.split_whitespace().collect::<Vec<_>>().join(" ");
+
+    // Query journal with ±60s window around the apt command
+    let output = Command::new("journalctl")
+        .args([
+            "_COMM=sudo",
+            "--no-pager",
+            &format!("--since={normalized} -5seconds"),
+            &format!("--until={normalized} +60seconds"),
+        ])
+        .output()
+        .ok()?;
+
+    let journal = String::from_utf8_lossy(&output.stdout);
+    parse_journal_pwd(&journal, commandline)
+}
+
+fn abbreviate_home(path: &str, home: &str) -> String {
+    path.strip_prefix(home).map_or_else(|| path.to_string(), |rel| format!("~{rel}"))
+}
+
+fn parse_journal_pwd(journal_output: &str, commandline: &str) -> Option<String> {
+    let pkg_names: Vec<&str> = commandline
+        .split_whitespace()
+        .skip_while(|w| w.starts_with('-') || matches!(*w, "apt-get" | "apt" | "aptitude" | "install"))
+        .filter(|w| !w.starts_with('-'))
+        .collect();
+    if pkg_names.is_empty() {
+        return None;
+    }
+    let home = env::var("HOME").ok()?;
+
+    for line in journal_output.lines() {
+        // sudo journal format: "... ]: PWD=... ; USER=... ; COMMAND=..."
+        let fields = line.rsplit_once("]: ").map_or(line, |(_, f)| f);
+        let pwd = fields.split(" ; ").find_map(|f| f.strip_prefix("PWD="));
+        let command = fields.find("COMMAND=").map(|i| &fields[i + 8..]);
+
+        if let (Some(p), Some(c)) = (pwd, command)
+            && c.contains("apt")
+            && pkg_names.iter().any(|pkg| c.contains(pkg))
+        {
+            return Some(abbreviate_home(p, &home));
+        }
+    }
+    None
+}
+
+// The entries inside any of the (start, end) epoch windows, plus the file or database they came from
+fn read_shell_history(windows: &[(i64, i64)]) -> (Vec<ShellHistoryEntry>, Option<PathBuf>) {
+    if windows.is_empty() {
+        return (Vec::new(), None);
+    }
+    let histfile = env::var("HISTFILE").ok();
+    let home = env::var("HOME").ok();
+    // atuin keeps the real history in SQLite and usually leaves the flat files stale
+    if let Some(h) = home.as_deref() {
+        let db = atuin_db_path(env::var("XDG_DATA_HOME").ok().as_deref(), h);
+        if db.is_file()
+            && let Some(entries) = read_atuin_history(&db, windows)
+        {
+            return (entries, Some(db));
+        }
+    }
+    read_first_history(histfile.as_deref(), home.as_deref()).map_or_else(
+        || (Vec::new(), None),
+        |(path, c)| {
+            let entries =
+                if is_fish_history(&c) { parse_fish_history(&c, Some(windows)) } else { parse_shell_history(&c, Some(windows)) };
+            (entries, Some(path))
+        },
+    )
+}
+
+// $HISTFILE is often exported before the shell has written it, so fall through to the defaults
+fn read_first_history(histfile: Option<&str>, home: Option<&str>) -> Option<(PathBuf, String)> {
+    history_candidates(histfile, home).into_iter().find_map(|p| Some((p.clone(), fs::read_to_string(p).ok()?)))
+}
+
+// $HISTFILE, then fish, then zsh, then bash
+fn history_candidates(histfile: Option<&str>, home: Option<&str>) -> Vec<PathBuf> {
+    let defaults = home.into_iter().flat_map(|h| {
+        [".zsh_history", ".bash_history"].iter().map(move |f| PathBuf::from(h).join(f))
+    });
+    let fish = home.map(|h| fish_history_path(env::var("XDG_DATA_HOME").ok().as_deref(), h, env::var("fish_history").ok().as_deref()));
+    histfile.map(PathBuf::from).into_iter().chain(fish).chain(defaults).collect()
+}
+
+// `$fish_history` names the session (default `fish`), stored as `<session>_history`
+fn fish_history_path(data_home: Option<&str>, home: &str, session: Option<&str>) -> PathBuf {
+    let data = data_home.filter(|d| !d.is_empty()).map_or_else(|| PathBuf::from(home).join(".local/share"), PathBuf::from);
+    data.join("fish").join(format!("{}_history", session.filter(|s| !s.is_empty()).unwrap_or("fish")))
+}
+
+fn atuin_db_path(data_home: Option<&str>, home: &str) -> PathBuf {
+    let data = data_home.filter(|d| !d.is_empty()).map_or_else(|| PathBuf::from(home).join(".local/share"), PathBuf::from);
+    data.join("atuin").join("history.db")
+}
+
+// Unit/record separators, since commands can hold tabs and newlines
+const ATUIN_FIELD_SEP: char = '\x1f';
+const ATUIN_ROW_SEP: char = '\x1e';
+
+// Queried through the sqlite3 CLI; None when it's missing or the query fails, so the
+// flat history files are used instead
+fn read_atuin_history(db: &Path, windows: &[(i64, i64)]) -> Option<Vec<ShellHistoryEntry>> {
+    // atuin stores nanoseconds
+    let start = windows.iter().map(|w| w.0).min()?.saturating_mul(1_000_000_000);
+    let end = windows.iter().map(|w| w.1).max()?.saturating_add(1).saturating_mul(1_000_000_000);
+    let output = Command::new("sqlite3")
+        .args(["-readonly", "-batch", "-noheader"])
+        .args(["-separator", &ATUIN_FIELD_SEP.to_string(), "-newline", &ATUIN_ROW_SEP.to_string()])
+        .arg(db)
+        .arg(format!(
+            "SELECT timestamp, command, cwd FROM history WHERE timestamp >= {start} AND timestamp < {end} ORDER BY timestamp"
+        ))
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    output.status.success().then(|| parse_atuin_rows(&String::from_utf8_lossy(&output.stdout), Some(windows)))
+}
+
+fn parse_atuin_rows(rows: &str, windows: Option<&[(i64, i64)]>) -> Vec<ShellHistoryEntry> {
+    rows.split(ATUIN_ROW_SEP)
+        .filter_map(|row| {
+            let mut fields = row.splitn(3, ATUIN_FIELD_SEP);
+            let timestamp = fields.next()?.trim().parse::<i64>().ok()? / 1_000_000_000;
+            let command = fields.next()?.to_string();
+            let cwd = fields.next().filter(|c| !c.is_empty()).map(String::from);
+            in_windows(timestamp, windows).then_some(ShellHistoryEntry { timestamp, command, cwd })
+        })
+        .collect()
+}
+
+// Where the closest history entry in the window ran, for when the journal has no PWD
+fn history_cwd(history: &[ShellHistoryEntry], target_epoch: i64, before_secs: i64, after_secs: i64) -> Option<&str> {
+    history
+        .iter()
+        .filter(|e| (-before_secs..=after_secs).contains(&(e.timestamp - target_epoch)))
+        .filter_map(|e| Some(((e.timestamp - target_epoch).abs(), e.cwd.as_deref()?)))
+        .min_by_key(|(d, _)| *d)
+        .map(|(_, cwd)| cwd)
+}
+
+fn is_fish_history(contents: &str) -> bool {
+    contents.lines().find(|l| !l.trim().is_empty()).is_some_and(|l| l.starts_with("- cmd: "))
+}
+
+fn in_windows(timestamp: i64, windows: Option<&[(i64, i64)]>) -> bool {
+    windows.is_none_or(|w| w.iter().any(|(start, end)| (*start..=*end).contains(&timestamp)))
+}
+
+// Records of `- cmd: <command>` followed by `  when: <epoch>`
+fn parse_fish_history(contents: &str, windows: Option<&[(i64, i64)]>) -> Vec<ShellHistoryEntry> {
+    let mut entries = Vec::new();
+    let mut cmd = None;
+    for line in contents.lines() {
+        if let Some(c) = line.strip_prefix("- cmd: ") {
+            cmd = Some(c);
+        } else if let Some(when) = line.trim_start().strip_prefix("when: ")
+            && let (Some(c), Ok(timestamp)) = (cmd.take(), when.trim().parse())
+            && in_windows(timestamp, windows)
+        {
+            let command = c.replace("\\n", "\n").replace("\\\\", "\\");
+            entries.push(ShellHistoryEntry { timestamp, command, cwd: None });
+        }
+    }
+    entries
+}
+
+// zsh's `: <epoch>:0;cmd`, or bash's `#<epoch>` line ahead of the command (HISTTIMEFORMAT).
+// With `windows`, entries outside all of them are skipped without being copied.
+fn parse_shell_history(contents: &str, windows: Option<&[(i64, i64)]>) -> Vec<ShellHistoryEntry> {
+    let mut entries = Vec::new();
+    let mut bash_epoch = None;
+    for line in contents.lines() {
+        if let Some(epoch) = line.strip_prefix('#').and_then(|e| e.trim().parse().ok()) {
+            bash_epoch = Some(epoch);
+            continue;
+        }
+        // A bash command without its own timestamp line stays unplaced
+        let Some((timestamp, cmd)) = history_line(line).or_else(|| Some((bash_epoch.take()?, line))) else { continue };
+        if in_windows(timestamp, windows) {
+            entries.push(ShellHistoryEntry { timestamp, command: cmd.to_string(), cwd: None });
+        }
+    }
+    entries
+}
+
+// `: <epoch>:<duration>;<command>` → (epoch, command)
+fn history_line(line: &str) -> Option<(i64, &str)> {
+    let rest = line.strip_prefix(": ")?;
+    let (epoch_part, cmd) = rest.split_once(';')?;
+    Some((epoch_part.split(':').next()?.parse().ok()?, cmd))
+}
+
+// `2026-02-10  12:11:38` (apt), single-spaced, or ISO 8601 `2026-02-10T12:11:38`, any trailing
+// zone kept → `2026-02-10 12:11:38[ zone]`
+fn normalize_apt_date(apt_date: &str) -> String {
+    let mut parts: Vec<&str> = apt_date.split_whitespace().collect();
+    if let Some(first) = parts.first().copied()
+        && let Some((day, time)) = first.split_once(['T', 't'])
+        && day.len() == 10
+        && time.starts_with(|c: char| c.is_ascii_digit())
+    {
+        parts.splice(0..1, [day, time]);
+    }
+    parts.join(" ")
+}
+
+// apt writes local wall-clock time; an explicit zone (`Z`, `UTC`, `+0100`, `+01:00`) wins when present
+fn apt_date_to_epoch(apt_date: &str) -> Option<i64> {
+    let normalized = normalize_apt_date(apt_date);
+    let mut parts = normalized.split(' ');
+    let (day, time) = (parts.next()?, parts.next()?);
+    let (time, mut zone) = match time.find(['Z', 'z', '+', '-']) {
+        Some(i) => (&time[..i], Some(&time[i..])),
+        None => (time, None),
+    };
+    if let Some(z) = parts.next() {
+        zone = Some(z);
+    }
+    if parts.next().is_some() {
+        return None;
+    }
+
+    let mut ymd = day.split('-').map(|p| p.parse::<i64>().ok());
+    let (y, m, d) = (ymd.next()??, ymd.next()??, ymd.next()??);
+    let mut hms = time.split(':').map(|p| p.parse::<i64>().ok());
+    let (hh, mm) = (hms.next()??, hms.next()??);
+    let ss = hms.next().unwrap_or(Some(0))?;
+    if ymd.next().is_some() || hms.next().is_some() || !(1..=12).contains(&m) || !(1..=31).contains(&d) {
+        return None;
+    }
+    if hh > 23 || mm > 59 || ss > 60 {
+        return None;
+    }
+    let wall = days_from_civil(y, m, d) * 86400 + hh * 3600 + mm * 60 + ss;
+
+    let offset = match zone {
+        Some(z) => zone_offset(z)?,
+        None => {
+            // Offset at the guessed instant, re-checked once so DST edges land right
+            let tzif = local_tzif();
+            let guess = tzif.as_deref().and_then(|t| tzif_offset(t, wall)).unwrap_or(0);
+            tzif.as_deref().and_then(|t| tzif_offset(t, wall - guess)).unwrap_or(guess)
+        }
+    };
+    Some(wall - offset)
+}
+
+// Days since 1970-01-01 in the proleptic Gregorian calendar
+fn days_from_civil(y: i64, m: i64, d: i64) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * ((m + 9) % 12) + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+// `Z` / `UTC` / `+0100` / `-05:30` / `+01` → seconds east of UTC
+fn zone_offset(zone: &str) -> Option<i64> {
+    if zone.eq_ignore_ascii_case("z") || zone.eq_ignore_ascii_case("utc") || zone.eq_ignore_ascii_case("gmt") {
+        return Some(0);
+    }
+    let sign = match zone.as_bytes().first()? {
+        b'+' => 1,
+        b'-' => -1,
+        _ => return None,
+    };
+    let digits: String = zone[1..].chars().filter(|c| *c != ':').collect();
+    if !digits.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let (h, m) = match digits.len() {
+        2 => (digits.parse::<i64>().ok()?, 0),
+        4 => (digits[..2].parse::<i64>().ok()?, digits[2..].parse::<i64>().ok()?),
+        _ => return None,
+    };
+    Some(sign * (h * 3600 + m * 60))
+}
+
+// The zoneinfo file for $TZ (`Europe/Berlin`, `:/path`), else /etc/localtime; None means UTC
+fn local_tzif() -> Option<Vec<u8>> {
+    match env::var("TZ") {
+        Ok(tz) => {
+            let tz = tz.strip_prefix(':').unwrap_or(&tz);
+            if tz.is_empty() {
+                return None;
+            }
+            let path = if tz.starts_with('/') {
+                PathBuf::from(tz)
+            } else {
+                Path::new("/usr/share/zoneinfo").join(tz)
+            };
+            fs::read(path).ok()
+        }
+        Err(_) => fs::read("/etc/localtime").ok(),
+    }
+}
+
+// UTC offset in effect at `utc` per a TZif file (RFC 8536): the last transition at or before it,
+// else the first local time type. Beyond the table the last transition holds, which is right
+// for the pre-generated transitions Debian and Ubuntu ship.
+fn tzif_offset(data: &[u8], utc: i64) -> Option<i64> {
+    let be32 = |at: usize| -> Option<u32> { Some(u32::from_be_bytes(data.get(at..at + 4)?.try_into().ok()?)) };
+    let counts = |at: usize| -> Option<[usize; 6]> {
+        if data.get(at..at + 4)? != b"TZif" {
+            return None;
+        }
+        let mut c = [0; 6];
+        for (i, n) in c.iter_mut().enumerate() {
+            *n = be32(at + 20 + i * 4)? as usize;
+        }
+        Some(c)
+    };
+
+    let [isut, isstd, leap, time, typ, chars] = counts(0)?;
+    let (mut base, mut counts_now, mut width) = (0, [isut, isstd, leap, time, typ, chars], 4);
+    if *data.get(4)? >= b'2' {
+        // Skip the 32-bit block and use the 64-bit one that follows
+        let v1 = 44 + time * 5 + typ * 6 + chars + leap * 8 + isstd + isut;
+        counts_now = counts(v1)?;
+        base = v1;
+        width = 8;
+    }
+    let [_, _, _, time, typ, _] = counts_now;
+    if typ == 0 {
+        return None;
+    }
+    let times = base + 44;
+    let indices = times + time * width;
+    let types = indices + time;
+
+    let transition = |i: usize| -> Option<i64> {
+        let at = times + i * width;
+        Some(if width == 8 {
+            i64::from_be_bytes(data.get(at..at + 8)?.try_into().ok()?)
+        } else {
+            i64::from(i32::from_be_bytes(data.get(at..at + 4)?.try_into().ok()?))
+        })
+    };
+    let mut kind = 0;
+    for i in 0..time {
+        if transition(i)? > utc {
+            break;
+        }
+        kind = *data.get(indices + i)? as usize;
+    }
+    if kind >= typ {
+        return None;
+    }
+    Some(i64::from(be32(types + kind * 6)? as i32))
+}
+
+fn find_nearby_commands(
+    history: &[ShellHistoryEntry],
+    target_epoch: i64,
+    before_secs: i64,
+    after_secs: i64,
+    show_all: bool,
+    only: &[String],
+) -> Vec<String> {
+    let mut nearby: Vec<_> = history
+        .iter()
+        .filter_map(|e| {
+            let offset = e.timestamp - target_epoch;
+            (-before_secs..=after_secs).contains(&offset).then_some((e, offset.abs()))
+        })
+        .collect();
+    nearby.sort_by_key(|(_, d)| *d);
+    let mut picked: Vec<_> = nearby
+        .iter()
+        .copied()
+        .filter(|(e, _)| !e.command.contains("apt-get install") && !e.command.contains("apt install"))
+        .filter(|(e, _)| only.is_empty() || only.iter().any(|p| e.command.starts_with(p.as_str())))
+        .filter(|(e, _)| {
+            show_all
+                || !only.is_empty()
+                || !matches!(
+                    e.command.split_whitespace().next().unwrap_or(""),
+                    "ls" | "clear" | "exit" | "pwd" | "echo" | "cat" | "true" | "history" | ""
+                )
+        })
+        .take(5)
+        .collect();
+    // The last `cd` before the install says where the work happened; never let the cap drop it
+    let cd = nearby
+        .iter()
+        .copied()
+        .filter(|(e, _)| e.timestamp <= target_epoch && is_cd(&e.command))
+        .max_by_key(|(e, _)| e.timestamp);
+    if let Some(cd) = cd
+        && !picked.iter().any(|(e, _)| std::ptr::eq(*e, cd.0))
+    {
+        picked.truncate(4);
+        picked.push(cd);
+        picked.sort_by_key(|(_, d)| *d);
+    }
+    picked
+        .into_iter()
+        .map(|(e, _)| match cd {
+            Some((c, _)) if std::ptr::eq(e, c) => format!("{}  (working dir)", e.command),
+            _ => e.command.clone(),
+        })
+        .collect()
+}
+
+// Comma separated so a prefix can span words, e.g. `git clone, make, cargo build`
+fn parse_prefix_list(list: &str) -> Vec<String> {
+    list.split(',').map(str::trim).filter(|p| !p.is_empty()).map(String::from).collect()
+}
+
+fn is_cd(command: &str) -> bool {
+    matches!(command.split_whitespace().next(), Some("cd" | "pushd"))
+}
+
+// Bare numbers are minutes; `30s` / `5m` make the unit explicit
+fn parse_duration_secs(s: &str) -> Option<i64> {
+    if let Some(secs) = s.strip_suffix('s') {
+        return secs.parse().ok();
+    }
+    s.strip_suffix('m').unwrap_or(s).parse::<i64>().ok().map(|m| m * 60)
+}
+
+fn format_duration_secs(secs: i64) -> String {
+    if secs % 60 == 0 { format!("{}m", secs / 60) } else { format!("{secs}s") }
+}
+
+// ── Commands ────────────────────────────────────────────────────────
+
+const DEFAULT_LARGE_LIST: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StatusFormat {
+    Human,
+    Porcelain, // stable `CODE name` lines for scripts
+    Json,
+}
+
+// Which rows the human status view prints; the summary always counts both states
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum StatusFilter {
+    #[default]
+    All,
+    Missing,   // --missing-only
+    Installed, // --installed-only
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct StatusView {
+    group_by_state: bool,
+    filter: StatusFilter,
+}
+
+fn cmd_status(
+    pkg_path: &Path,
+    arch: &ArchMode,
+    watch: Option<Duration>,
+    large_list: usize,
+    format: StatusFormat,
+    view: StatusView,
+    group: Option<&str>,
+) {
+    let load_packages = |path| -> BTreeSet<String> { load_group(path, group).into_keys().collect() };
+    let load_list = |path| load_group(path, group);
+    let count = load_packages(pkg_path).len();
+    if count > large_list {
+        eprintln!("{DIM}Querying {count} packages, this may take a moment…{RESET}");
+    }
+    if format == StatusFormat::Json {
+        let pkgs = load_packages(pkg_path);
+        println!("{}", status_json(&pkgs, &installed_set(&pkgs, arch)));
+        return;
+    }
+    if format == StatusFormat::Porcelain {
+        let pkgs = load_packages(pkg_path);
+        let output = dpkg_query(&pkgs);
+        let installed: BTreeSet<String> = parse_installed(&output, arch).intersection(&pkgs).cloned().collect();
+        for line in porcelain_lines(&pkgs, &installed, &parse_held(&output, arch), &system_auto_packages()) {
+            println!("{line}");
+        }
+        return;
+    }
+    let Some(interval) = watch else {
+        print_status(&load_list(pkg_path), arch, view);
+        return;
+    };
+    if !io::stdout().is_terminal() {
+        eprintln!("{DIM}--watch needs a terminal, showing status once{RESET}");
+        print_status(&load_list(pkg_path), arch, view);
+        return;
+    }
+    // Re-read the list each tick so edits made mid-install show up; Ctrl-C exits
+    loop {
+        let pkgs = load_list(pkg_path);
+        if color_enabled() {
+            print!("\x1b[2J\x1b[H");
+        } else {
+            println!();
+        }
+        if print_status(&pkgs, arch, view) == 0 {
+            return;
+        }
+        eprintln!("\n  {DIM}Refreshing every {}s — Ctrl-C to stop{RESET}", interval.as_secs());
+        io::stdout().flush().unwrap();
+        thread::sleep(interval);
+    }
+}
+
+// Prints the status view and returns the number of missing packages
+fn print_status(list: &PkgList, arch: &ArchMode, view: StatusView) -> usize {
+    if list.is_empty() {
+        eprintln!("{YELLOW}{ICON_EMPTY}No curated packages yet. Use `apt-sync add <pkg>` to get started!{RESET}");
+        return 0;
+    }
+    let pkgs: BTreeSet<String> = list.keys().cloned().collect();
+    let versions = installed_versions(&pkgs, arch);
+    let installed: BTreeSet<String> = versions.keys().filter(|k| pkgs.contains(*k)).cloned().collect();
+    for line in status_lines(&pkgs, &installed, &pin_mismatches(list, &versions), view) {
+        println!("{line}");
+    }
+    let n_missing = pkgs.len() - installed.len();
+    if n_missing > 0 {
+        eprintln!("  {DIM}Run `apt-sync install` to install missing packages{RESET}");
+    }
+    n_missing
+}
+
+// Porcelain v1 — never change: `I` installed, `M` missing, `H` held, `A` installed but
+// marked automatic (at risk from autoremove), then a space and the curated name
+fn porcelain_lines(
+    pkgs: &BTreeSet<String>,
+    installed: &BTreeSet<String>,
+    held: &BTreeSet<String>,
+    auto: &BTreeSet<String>,
+) -> Vec<String> {
+    pkgs.iter()
+        .map(|p| {
+            let code = if !installed.contains(p) {
+                'M'
+            } else if held.contains(p) {
+                'H'
+            } else if auto.contains(p) {
+                'A'
+            } else {
+                'I'
+            };
+            format!("{code} {p}")
+        })
+        .collect()
+}
+
+fn status_lines(
+    pkgs: &BTreeSet<String>,
+    installed: &BTreeSet<String>,
+    off_pin: &BTreeMap<&str, (&str, &str)>,
+    view: StatusView,
+) -> Vec<String> {
+    let mut lines = vec![format!("{BOLD}{CYAN}{ICON_PACKAGE}apt-sync status{RESET}  {DIM}({} curated){RESET}\n", pkgs.len())];
+    let line = |p: &String| match off_pin.get(p.as_str()) {
+        Some((pin, version)) => format!("  {YELLOW}{ICON_WARN}{p}{RESET}  {DIM}({version} installed, pinned to {pin}){RESET}"),
+        None if installed.contains(p) => format!("  {GREEN}{ICON_OK}{p}{RESET}"),
+        None => format!("  {RED}{ICON_NO}{p}{RESET}  {DIM}(not installed){RESET}"),
+    };
+    let shown = |p: &&String| match view.filter {
+        StatusFilter::All => true,
+        StatusFilter::Missing => !installed.contains(*p),
+        StatusFilter::Installed => installed.contains(*p),
+    };
+    if view.group_by_state {
+        // Every installed package, then a blank line and every missing one
+        let (present, missing): (Vec<&String>, Vec<&String>) =
+            pkgs.iter().filter(shown).partition(|p| installed.contains(*p));
+        lines.extend(present.iter().map(|p| line(p)));
+        if !present.is_empty() && !missing.is_empty() {
+            lines.push(String::new());
+        }
+        lines.extend(missing.iter().map(|p| line(p)));
+    } else {
+        lines.extend(pkgs.iter().filter(shown).map(line));
+    }
+    let n_missing = pkgs.len() - installed.len();
+    lines.push(String::new());
+    let mut summary = format!("  {GREEN}{} installed{RESET}  {RED}{n_missing} missing{RESET}", installed.len());
+    if !off_pin.is_empty() {
+        summary.push_str(&format!("  {YELLOW}{} off pin{RESET}", off_pin.len()));
+    }
+    lines.push(summary);
+    lines
+}
+
+fn cmd_list(pkg_path: &Path, json: bool, arch: &ArchMode, by_section: bool, group: Option<&str>) {
+    let list = load_group(pkg_path, group);
+    let pkgs: BTreeSet<String> = list.keys().cloned().collect();
+    if json {
+        println!("{}", list_json(&pkgs, &installed_versions(&pkgs, arch)));
+        return;
+    }
+    if by_section && !pkgs.is_empty() {
+        for line in section_list_lines(&list) {
+            println!("{line}");
+        }
+        return;
+    }
+    write_list(&mut io::stdout().lock(), &mut io::stderr().lock(), &pkgs).expect("failed to write list");
+}
+
+fn section_list_lines(list: &PkgList) -> Vec<String> {
+    let mut lines = Vec::new();
+    for (section, entries) in by_section(list) {
+        if !lines.is_empty() {
+            lines.push(String::new());
+        }
+        lines.push(format!("{BOLD}## {}{RESET}", section.unwrap_or("(no section)")));
+        lines.extend(entries.iter().map(|(p, _)| p.to_string()));
+    }
+    lines
+}
+
+// Names go to `out` so `apt-sync list | xargs ...` stays clean; notices go to `err`
+fn write_list(out: &mut impl Write, err: &mut impl Write, pkgs: &BTreeSet<String>) -> io::Result<()> {
+    if pkgs.is_empty() {
+        return writeln!(err, "{YELLOW}{ICON_EMPTY}No curated packages yet.{RESET}");
+    }
+    for p in pkgs {
+        writeln!(out, "{p}")?;
+    }
+    Ok(())
+}
+
+// `@tag` arguments tag every package added alongside them
+fn apply_modify<'a>(pkgs: &mut PkgList, names: &'a [String], add: bool) -> (Vec<&'a str>, Vec<&'a str>) {
+    let (tags, names): (Vec<&String>, Vec<&String>) = names.iter().partition(|n| n.starts_with('@'));
+    let tags: BTreeSet<String> = tags.iter().map(|t| t[1..].to_string()).filter(|t| !t.is_empty()).collect();
+    let mut changed = Vec::new();
+    let mut unchanged = Vec::new();
+    for name in names {
+        let (base, release, version) = split_spec(name);
+        let ok = if add {
+            let existed = pkgs.contains_key(base);
+            let entry = pkgs.entry(base.to_string()).or_default();
+            let before = (entry.tags.len(), entry.release.clone(), entry.version.clone());
+            entry.tags.extend(tags.iter().cloned());
+            // A release and a version pin can't both apply; the newest one asked for wins
+            if release.is_some() || version.is_some() {
+                entry.release = release.map(String::from);
+                entry.version = version.map(String::from);
+            }
+            !existed || (entry.tags.len(), entry.release.clone(), entry.version.clone()) != before
+        } else {
+            pkgs.remove(base).is_some()
+        };
+        if ok { changed.push(name.as_str()) } else { unchanged.push(name.as_str()) }
+    }
+    (changed, unchanged)
+}
+
+// `apt-sync add "$(cat list)"` passes one newline-separated blob; read it like a packages file
+fn expand_pasted_args(args: &[String]) -> Vec<String> {
+    args.iter()
+        .flat_map(|a| {
+            if a.contains('\n') {
+                parse_list(a).iter().map(|(p, entry)| install_spec(p, entry)).collect()
+            } else {
+                vec![a.clone()]
+            }
+        })
+        .collect()
+}
+
+fn cmd_modify(pkg_path: &Path, names: &[String], add: bool) -> ExitCode {
+    cmd_modify_in(pkg_path, names, add, None)
+}
+
+// Reported before any success message, so a failed save never claims to have changed anything
+fn write_failed(path: &Path, err: &io::Error) -> ExitCode {
+    eprintln!("{RED}could not write {}: {err}{RESET}", path.display());
+    ExitCode::FAILURE
+}
+
+// `add --section Name` also files the packages under `## Name`
+fn cmd_modify_in(pkg_path: &Path, names: &[String], add: bool, section: Option<&str>) -> ExitCode {
+    let names = &expand_pasted_args(names);
+    let stamp = file_stamp(pkg_path);
+    let mut pkgs = load_list(pkg_path);
+    let (mut changed, mut unchanged) = apply_modify(&mut pkgs, names, add);
+    if let Some(section) = section {
+        let moved = assign_section(&mut pkgs, names, section);
+        unchanged.retain(|u| !moved.contains(u));
+        changed.extend(moved.into_iter().filter(|m| !changed.contains(m)).collect::<Vec<_>>());
+    }
+    let saved = save_merging(pkg_path, stamp, &pkgs, |fresh| {
+        apply_modify(fresh, names, add);
+        if let Some(section) = section {
+            assign_section(fresh, names, section);
+        }
+    });
+    if let Err(e) = saved {
+        return write_failed(pkg_path, &e);
+    }
+    let (sym, color, verb, skip_msg) = if add {
+        (ICON_ADD, GREEN, "Added", "already listed")
+    } else {
+        (ICON_DEL, RED, "Removed", "not in list")
+    };
+    for c in &changed {
+        println!("  {color}{sym}{c}{RESET}");
+    }
+    for u in &unchanged {
+        eprintln!("  {DIM}  {u} ({skip_msg}){RESET}");
+    }
+    if !changed.is_empty() {
+        println!("\n{CYAN}{ICON_NOTE}{verb} {} package(s) from packages.txt{RESET}", changed.len());
+    }
+    ExitCode::SUCCESS
+}
+
+const REMOVE_CONFIRM_THRESHOLD: usize = 5;
+
+#[derive(Debug, PartialEq, Eq)]
+enum RemoveGate {
+    Proceed,
+    Ask,
+    Refuse, // too many to remove unattended without --yes
+}
+
+fn remove_gate(count: usize, yes: bool, interactive: bool) -> RemoveGate {
+    match (count > REMOVE_CONFIRM_THRESHOLD && !yes, interactive) {
+        (false, _) => RemoveGate::Proceed,
+        (true, true) => RemoveGate::Ask,
+        (true, false) => RemoveGate::Refuse,
+    }
+}
+
+fn cmd_remove(pkg_path: &Path, names: &[String], yes: bool) -> ExitCode {
+    let names = expand_pasted_args(names);
+    let pkgs: Vec<&str> = names.iter().map(String::as_str).filter(|n| !n.starts_with('@')).collect();
+    match remove_gate(pkgs.len(), yes, io::stdin().is_terminal()) {
+        RemoveGate::Proceed => {}
+        RemoveGate::Refuse => {
+            eprintln!("{RED}Refusing to remove {} packages without a terminal — pass --yes{RESET}", pkgs.len());
+            return ExitCode::FAILURE;
+        }
+        RemoveGate::Ask => {
+            println!("{BOLD}About to remove {} packages:{RESET} {}", pkgs.len(), pkgs.join(", "));
+            if !confirm_yes() {
+                eprintln!("{DIM}Nothing removed.{RESET}");
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    cmd_modify(pkg_path, &names, false)
+}
+
+// [Y/n] prompt; anything but an explicit no continues
+fn confirm_yes() -> bool {
+    print!("{CYAN}Continue?{RESET} [Y/n] ");
+    io::stdout().flush().unwrap();
+    let mut line = String::new();
+    io::stdin().read_line(&mut line).unwrap_or(0);
+    !matches!(line.trim().to_lowercase().as_str(), "n" | "no")
+}
+
+// [y/N] prompt for destructive steps; only an explicit yes continues
+fn confirm_no() -> bool {
+    print!("{CYAN}Continue?{RESET} [y/N] ");
+    io::stdout().flush().unwrap();
+    let mut line = String::new();
+    io::stdin().read_line(&mut line).unwrap_or(0);
+    matches!(line.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+// Curated entries for packages that are no longer installed
+fn stale_entries(pkgs: &BTreeSet<String>, installed: &BTreeSet<String>) -> Vec<String> {
+    pkgs.difference(installed).cloned().collect()
+}
+
+// `remove --stale`: list cleanup only, apt is never called
+fn cmd_prune(pkg_path: &Path, arch: &ArchMode, yes: bool) -> ExitCode {
+    let pkgs = load_packages(pkg_path);
+    let stale = stale_entries(&pkgs, &installed_set(&pkgs, arch));
+    if stale.is_empty() {
+        println!("{GREEN}{ICON_DONE}Every curated package is installed, nothing to prune{RESET}");
+        return ExitCode::SUCCESS;
+    }
+    println!("{BOLD}{YELLOW}{ICON_BROOM}{} curated package(s) not installed:{RESET}", stale.len());
+    for p in &stale {
+        println!("  {DIM}{p}{RESET}");
+    }
+    if !yes {
+        if !io::stdin().is_terminal() {
+            eprintln!("{RED}Refusing to prune without a terminal — pass --yes{RESET}");
+            return ExitCode::FAILURE;
+        }
+        if !confirm_yes() {
+            eprintln!("{DIM}Nothing removed.{RESET}");
+            return ExitCode::FAILURE;
+        }
+    }
+    cmd_modify(pkg_path, &stale, false)
+}
+
+// (installed, not installed) in the order given
+fn split_installed<'a>(names: &'a [String], installed: &BTreeSet<String>) -> (Vec<&'a str>, Vec<&'a str>) {
+    names.iter().map(String::as_str).partition(|n| installed.contains(*n))
+}
+
+fn cmd_uninstall(pkg_path: &Path, names: &[String], dry_run: bool, purge: bool, forget: bool, arch: &ArchMode) -> ExitCode {
+    let requested: BTreeSet<String> = names.iter().cloned().collect();
+    let (installed, absent) = split_installed(names, &installed_set(&requested, arch));
+    for n in &absent {
+        eprintln!("{DIM}  {n} (not installed, skipped){RESET}");
+    }
+    let targets = guard_removal(&installed, &protected_packages());
+    let verb = if purge { "purge" } else { "remove" };
+    if !targets.is_empty() {
+        println!("{BOLD}{YELLOW}{ICON_BROOM}{} {} package(s){RESET}\n", if purge { "Purging" } else { "Removing" }, targets.len());
+        for t in &targets {
+            println!("  {RED}{ICON_DEL}{t}{RESET}");
+        }
+        println!();
+    }
+    if dry_run {
+        println!("{YELLOW}{ICON_DRY_RUN}Dry run — nothing was removed{RESET}");
+        if !targets.is_empty() {
+            println!("{DIM}Would run: apt-get {verb} -y {}{RESET}", targets.join(" "));
+        }
+        if forget {
+            println!("{DIM}Would drop from packages.txt: {}{RESET}", names.join(" "));
+        }
+        return ExitCode::SUCCESS;
+    }
+    let mut ok = targets.is_empty() || run_tool("apt-get", apt_get_remove_command(&targets, purge).status()).success();
+    if !ok {
+        println!("\n{RED}{ICON_ERROR}apt-get exited with errors{RESET}");
+    }
+    if forget {
+        // Only names that really are gone now; a failed or protected removal stays curated
+        let still: BTreeSet<String> = installed_set(&requested, arch);
+        let gone: Vec<String> = names.iter().filter(|n| !still.contains(*n)).cloned().collect();
+        if !gone.is_empty() && cmd_modify(pkg_path, &gone, false) != ExitCode::SUCCESS {
+            ok = false;
+        }
+    }
+    if ok { ExitCode::SUCCESS } else { ExitCode::FAILURE }
+}
+
+// Specs still not installed after apt-get ran, whatever its exit status said
+fn residual_missing<'a>(specs: &[&'a str], installed_after: &BTreeSet<String>) -> Vec<&'a str> {
+    specs.iter().copied().filter(|s| !installed_after.contains(spec_name(s))).collect()
+}
+
+struct InstallOpts {
+    dry_run: bool,
+    wait_secs: Option<u64>,    // --wait: retry while the dpkg lock is held
+    only: Option<Vec<String>>, // --only / --group
+    jobs: Option<u32>,
+    reinstall: bool,
+    yes: bool,                 // skip the [Y/n] prompt
+}
+
+// Asks only when someone is there to answer; --yes and piped stdin go straight ahead
+fn install_needs_confirmation(yes: bool, interactive: bool) -> bool {
+    !yes && interactive
+}
+
+fn cmd_install(pkg_path: &Path, arch: &ArchMode, opts: &InstallOpts) -> ExitCode {
+    let (dry_run, wait_secs, only, jobs) = (opts.dry_run, opts.wait_secs, opts.only.as_deref(), opts.jobs);
+    let list = load_list(pkg_path);
+    let pkgs: BTreeSet<String> = list.keys().cloned().collect();
+    if pkgs.is_empty() {
+        eprintln!("{YELLOW}{ICON_EMPTY}No curated packages to install.{RESET}");
+        return ExitCode::SUCCESS;
+    }
+    let installed = installed_set(&pkgs, arch);
+    if opts.reinstall {
+        return reinstall_curated(&list, &installed, arch, opts);
+    }
+    let specs = match missing_specs(&list, &installed, only) {
+        Ok(specs) => specs,
+        Err(uncurated) => {
+            eprintln!("{RED}Not in the curated list: {}{RESET}", uncurated.join(", "));
+            return ExitCode::FAILURE;
+        }
+    };
+    let missing: Vec<&str> = specs.iter().map(String::as_str).collect();
+    if missing.is_empty() {
+        match only {
+            Some(names) => println!("{GREEN}{ICON_DONE}All {} requested packages are already installed!{RESET}", names.len()),
+            None => println!("{GREEN}{ICON_DONE}All {} curated packages are already installed!{RESET}", pkgs.len()),
+        }
+        return ExitCode::SUCCESS;
+    }
+    let (missing, dropped) = dedupe_install_specs(&missing);
+    for (dup, kept) in &dropped {
+        eprintln!("{DIM}  ↳ {dup} duplicates {kept}, installing {kept} only{RESET}");
+    }
+    println!("{BOLD}{CYAN}{ICON_ROCKET}Installing {} missing package(s){RESET}\n", missing.len());
+    for m in &missing {
+        println!("  {CYAN}• {m}{RESET}");
+    }
+    println!();
+    if dry_run {
+        println!("{YELLOW}{ICON_DRY_RUN}Dry run — nothing was installed{RESET}");
+        let jobs = jobs.map(|j| apt_jobs_options(j).join(" ") + " ").unwrap_or_default();
+        println!("{DIM}Would run: apt-get install -y {jobs}{}{RESET}", missing.join(" "));
+        return ExitCode::SUCCESS;
+    }
+    if install_needs_confirmation(opts.yes, io::stdin().is_terminal()) && !confirm_yes() {
+        eprintln!("{DIM}Nothing installed.{RESET}");
+        return ExitCode::FAILURE;
+    }
+    let outcome = match wait_secs {
+        Some(secs) => retry_on_lock(|| apt_get_install(&missing, true, jobs, false), Duration::from_secs(secs), thread::sleep),
+        None => apt_get_install(&missing, false, jobs, false),
+    };
+    // apt can exit nonzero over a trigger warning yet install everything, so trust dpkg
+    let names: BTreeSet<String> = missing.iter().map(|s| spec_name(s).to_string()).collect();
+    let residual = residual_missing(&missing, &installed_set(&names, arch));
+    match (outcome, residual.is_empty()) {
+        (AptOutcome::Success, true) => println!("\n{GREEN}{ICON_DONE}Done! All packages installed.{RESET}"),
+        (_, true) => println!("\n{GREEN}{ICON_DONE}Done! apt-get reported errors, but all packages are installed.{RESET}"),
+        (AptOutcome::Locked, false) => println!("\n{RED}{ICON_ERROR}Gave up waiting for the dpkg lock{RESET}"),
+        (_, false) => {
+            println!("\n{RED}{ICON_ERROR}apt-get exited with errors; {} package(s) still missing:{RESET}", residual.len());
+            for r in &residual {
+                println!("  {RED}{ICON_NO}{r}{RESET}");
+            }
+        }
+    }
+    if residual.is_empty() { ExitCode::SUCCESS } else { ExitCode::FAILURE }
+}
+
+// `install --reinstall`: the installed curated packages (or the --only subset) get a clean
+// reinstall of their current version, so no release or version pin is passed along
+fn reinstall_curated(list: &PkgList, installed: &BTreeSet<String>, arch: &ArchMode, opts: &InstallOpts) -> ExitCode {
+    let jobs = opts.jobs;
+    let targets = match reinstall_targets(list, installed, opts.only.as_deref()) {
+        Ok(targets) => targets,
+        Err(uncurated) => {
+            eprintln!("{RED}Not in the curated list: {}{RESET}", uncurated.join(", "));
+            return ExitCode::FAILURE;
+        }
+    };
+    if targets.is_empty() {
+        println!("{YELLOW}{ICON_EMPTY}None of the requested curated packages are installed — nothing to reinstall{RESET}");
+        return ExitCode::SUCCESS;
+    }
+    println!("{BOLD}{CYAN}{ICON_ROCKET}Reinstalling {} package(s){RESET}\n", targets.len());
+    for t in &targets {
+        println!("  {CYAN}• {t}{RESET}");
+    }
+    println!();
+    if opts.dry_run {
+        println!("{YELLOW}{ICON_DRY_RUN}Dry run — nothing was reinstalled{RESET}");
+        let jobs = jobs.map(|j| apt_jobs_options(j).join(" ") + " ").unwrap_or_default();
+        println!("{DIM}Would run: apt-get install --reinstall -y {jobs}{}{RESET}", targets.join(" "));
+        return ExitCode::SUCCESS;
+    }
+    if install_needs_confirmation(opts.yes, io::stdin().is_terminal()) && !confirm_yes() {
+        eprintln!("{DIM}Nothing reinstalled.{RESET}");
+        return ExitCode::FAILURE;
+    }
+    let outcome = match opts.wait_secs {
+        Some(secs) => retry_on_lock(|| apt_get_install(&targets, true, jobs, true), Duration::from_secs(secs), thread::sleep),
+        None => apt_get_install(&targets, false, jobs, true),
+    };
+    let names: BTreeSet<String> = targets.iter().map(|t| t.to_string()).collect();
+    let residual = residual_missing(&targets, &installed_set(&names, arch));
+    match outcome {
+        AptOutcome::Success => {
+            println!("\n{GREEN}{ICON_DONE}Reinstalled {} package(s): {}{RESET}", targets.len(), targets.join(", "));
+        }
+        AptOutcome::Locked => println!("\n{RED}{ICON_ERROR}Gave up waiting for the dpkg lock{RESET}"),
+        AptOutcome::Failed => {
+            println!("\n{RED}{ICON_ERROR}apt-get exited with errors; the reinstall may be incomplete{RESET}");
+            for r in &residual {
+                println!("  {RED}{ICON_NO}{r} is no longer installed{RESET}");
+            }
+        }
+    }
+    if outcome == AptOutcome::Success { ExitCode::SUCCESS } else { ExitCode::FAILURE }
+}
+
+fn reinstall_targets<'a>(
+    list: &'a PkgList,
+    installed: &BTreeSet<String>,
+    only: Option<&[String]>,
+) -> Result<Vec<&'a str>, Vec<String>> {
+    if let Some(names) = only {
+        let uncurated: Vec<String> = names.iter().filter(|n| !list.contains_key(*n)).cloned().collect();
+        if !uncurated.is_empty() {
+            return Err(uncurated);
+        }
+    }
+    Ok(list
+        .keys()
+        .filter(|p| installed.contains(*p))
+        .filter(|p| only.is_none_or(|names| names.contains(p)))
+        .map(String::as_str)
+        .collect())
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct SyncPlan {
+    install: Vec<String>,   // specs for missing curated packages
+    remove: Vec<String>,    // manual system packages that aren't curated
+    protected: Vec<String>, // would be removed, but are protected
+}
+
+// `apt-get -s remove` (no root needed) → every package apt would take, dependents included
+fn simulate_removal(names: &[&str], purge: bool) -> BTreeSet<String> {
+    let output = Command::new("apt-get")
+        .args(["-s", if purge { "purge" } else { "remove" }])
+        .args(names)
+        .stderr(Stdio::null())
+        .output();
+    parse_simulated_removals(&String::from_utf8_lossy(&run_tool("apt-get", output).stdout))
+}
+
+// `Remv pkg [version]` / `Purg pkg [version]` lines, arch qualifiers dropped
+fn parse_simulated_removals(output: &str) -> BTreeSet<String> {
+    output
+        .lines()
+        .filter_map(|l| l.strip_prefix("Remv ").or_else(|| l.strip_prefix("Purg ")))
+        .filter_map(|rest| rest.split_whitespace().next())
+        .map(|p| strip_arch(p).to_string())
+        .collect()
+}
+
+// What apt would remove beyond the plan: (curated or protected, so sync must stop; the rest)
+fn removal_collateral(
+    simulated: &BTreeSet<String>,
+    planned: &[String],
+    curated: &BTreeSet<String>,
+    protected: &BTreeSet<String>,
+) -> (Vec<String>, Vec<String>) {
+    let curated: BTreeSet<&str> = curated.iter().map(|p| strip_arch(p)).collect();
+    simulated
+        .iter()
+        .filter(|p| !planned.iter().any(|r| strip_arch(r) == p.as_str()))
+        .cloned()
+        .partition(|p| curated.contains(p.as_str()) || protected.contains(p))
+}
+
+fn sync_plan(list: &PkgList, installed: &BTreeSet<String>, system: &BTreeSet<String>, protected: &BTreeSet<String>) -> SyncPlan {
+    let specs = missing_specs(list, installed, None).unwrap_or_default();
+    let specs: Vec<&str> = specs.iter().map(String::as_str).collect();
+    let uncurated: Vec<&str> = system.iter().filter(|p| !list.contains_key(*p)).map(String::as_str).collect();
+    let (remove, blocked) = plan_removal(&uncurated, protected);
+    SyncPlan {
+        install: dedupe_install_specs(&specs).0.into_iter().map(String::from).collect(),
+        remove: remove.into_iter().map(String::from).collect(),
+        protected: blocked.into_iter().map(String::from).collect(),
+    }
+}
+
+// install + remove: makes the system match the curated list, after one prompt
+fn cmd_sync(pkg_path: &Path, dry_run: bool, purge: bool, yes: bool, arch: &ArchMode) -> ExitCode {
+    let list = load_list(pkg_path);
+    let pkgs: BTreeSet<String> = list.keys().cloned().collect();
+    let protected = protected_packages();
+    let plan = sync_plan(&list, &installed_set(&pkgs, arch), &system_manual_packages(), &protected);
+    for p in &plan.protected {
+        eprintln!("{YELLOW}{ICON_SHIELD}Refusing to remove protected package: {p}{RESET}");
+    }
+    if plan.install.is_empty() && plan.remove.is_empty() {
+        println!("{GREEN}{ICON_DONE}System and curated list are in perfect sync!{RESET}");
+        return ExitCode::SUCCESS;
+    }
+    let remove_verb = if purge { "purge" } else { "remove" };
+    if !plan.install.is_empty() {
+        println!("{BOLD}{CYAN}{ICON_ROCKET}Install {} missing package(s){RESET}", plan.install.len());
+        for p in &plan.install {
+            println!("  {GREEN}{ICON_ADD}{p}{RESET}");
+        }
+    }
+    if !plan.remove.is_empty() {
+        let names: Vec<&str> = plan.remove.iter().map(String::as_str).collect();
+        let (blocked, dependents) = removal_collateral(&simulate_removal(&names, purge), &plan.remove, &pkgs, &protected);
+        if !blocked.is_empty() {
+            eprintln!(
+                "{RED}{ICON_ERROR}apt would also {remove_verb} curated or protected package(s) that depend on these: {}{RESET}",
+                blocked.join(", ")
+            );
+            eprintln!("{DIM}Nothing changed. Curate the uncurated packages they need, or remove them by hand.{RESET}");
+            return ExitCode::FAILURE;
+        }
+        println!("{BOLD}{YELLOW}{ICON_BROOM}{} {} uncurated package(s){RESET}", if purge { "Purge" } else { "Remove" }, plan.remove.len());
+        for p in &plan.remove {
+            println!("  {RED}{ICON_DEL}{p}{RESET}");
+        }
+        if !dependents.is_empty() {
+            println!("{BOLD}{YELLOW}…and {} package(s) that depend on them{RESET}", dependents.len());
+            for p in &dependents {
+                println!("  {RED}{ICON_DEL}{p}{RESET}");
+            }
+        }
+    }
+    println!();
+    if dry_run {
+        println!("{YELLOW}{ICON_DRY_RUN}Dry run — nothing was installed or removed{RESET}");
+        if !plan.install.is_empty() {
+            println!("{DIM}Would run: apt-get install -y {}{RESET}", plan.install.join(" "));
+        }
+        if !plan.remove.is_empty() {
+            println!("{DIM}Would run: apt-get {remove_verb} -y {}{RESET}", plan.remove.join(" "));
+        }
+        return ExitCode::SUCCESS;
+    }
+    if !yes {
+        if !io::stdin().is_terminal() {
+            eprintln!("{RED}Refusing to sync without a terminal — pass --yes{RESET}");
+            return ExitCode::FAILURE;
+        }
+        // Removing packages is the risky half, so only an explicit yes goes ahead
+        let confirmed = if plan.remove.is_empty() { confirm_yes() } else { confirm_no() };
+        if !confirmed {
+            eprintln!("{DIM}Nothing changed.{RESET}");
+            return ExitCode::FAILURE;
+        }
+    }
+    let mut ok = true;
+    if !plan.install.is_empty() {
+        let specs: Vec<&str> = plan.install.iter().map(String::as_str).collect();
+        ok &= apt_get_install(&specs, false, None, false) == AptOutcome::Success;
+    }
+    if !plan.remove.is_empty() {
+        let names: Vec<&str> = plan.remove.iter().map(String::as_str).collect();
+        ok &= run_tool("apt-get", apt_get_remove_command(&names, purge).status()).success();
+    }
+    if ok {
+        println!("\n{GREEN}{ICON_DONE}Done! System matches the curated list.{RESET}");
+        ExitCode::SUCCESS
+    } else {
+        println!("\n{RED}{ICON_ERROR}apt-get exited with errors; run `apt-sync diff` to see what's left{RESET}");
+        ExitCode::FAILURE
+    }
+}
+
+// `apt-cache policy` → name → (installed, candidate), for packages that have both
+fn parse_policy(output: &str) -> BTreeMap<String, (String, String)> {
+    let mut out = BTreeMap::new();
+    let (mut name, mut installed) = (None, None);
+    for line in output.lines() {
+        if !line.starts_with(' ') {
+            name = line.strip_suffix(':').map(String::from);
+            installed = None;
+        } else if let Some(v) = line.trim().strip_prefix("Installed: ") {
+            installed = Some(v.to_string()).filter(|v| v != "(none)");
+        } else if let Some(candidate) = line.trim().strip_prefix("Candidate: ")
+            && candidate != "(none)"
+            && let (Some(n), Some(i)) = (name.clone(), installed.clone())
+        {
+            out.insert(n, (i, candidate.to_string()));
+        }
+    }
+    out
+}
+
+// (name, installed, candidate) for each package apt would move to a different version
+fn newer_candidates(policy: &BTreeMap<String, (String, String)>) -> Vec<(&str, &str, &str)> {
+    policy.iter().filter(|(_, (i, c))| i != c).map(|(n, (i, c))| (n.as_str(), i.as_str(), c.as_str())).collect()
+}
+
+fn apt_policy(names: &[&String]) -> BTreeMap<String, (String, String)> {
+    let output = run_tool("apt-cache", Command::new("apt-cache").arg("policy").args(names).output());
+    parse_policy(&String::from_utf8_lossy(&output.stdout))
+}
+
+fn version_change_line(name: &str, from: &str, to: &str) -> String {
+    format!("  {CYAN}• {name}{RESET}  {DIM}{from} → {to}{RESET}")
+}
+
+// Read-only `upgrade`: exits 1 when a curated package has a newer candidate, for cron checks
+fn cmd_outdated(pkg_path: &Path, arch: &ArchMode) -> ExitCode {
+    let pkgs = load_packages(pkg_path);
+    let installed: Vec<&String> = installed_set(&pkgs, arch).iter().filter_map(|p| pkgs.get(p)).collect();
+    if installed.is_empty() {
+        println!("{GREEN}{ICON_DONE}No installed curated packages to check{RESET}");
+        return ExitCode::SUCCESS;
+    }
+    let policy = apt_policy(&installed);
+    let newer = newer_candidates(&policy);
+    if newer.is_empty() {
+        println!("{GREEN}{ICON_DONE}All {} installed curated packages are up to date!{RESET}", installed.len());
+        return ExitCode::SUCCESS;
+    }
+    for (n, from, to) in &newer {
+        println!("{}", version_change_line(n, from, to));
+    }
+    println!("\n  {YELLOW}{} of {} installed curated package(s) outdated{RESET}", newer.len(), installed.len());
+    eprintln!("  {DIM}Run `apt-sync upgrade` to upgrade them{RESET}");
+    ExitCode::FAILURE
+}
+
+fn apt_get_upgrade_command(pkgs: &[&str]) -> Command {
+    let mut cmd = Command::new("apt-get");
+    cmd.args(["install", "--only-upgrade", "-y"]).args(pkgs);
+    cmd
+}
+
+// Like `apt-get upgrade`, but only for installed curated packages; never installs anything new
+fn cmd_upgrade(pkg_path: &Path, dry_run: bool, arch: &ArchMode) -> ExitCode {
+    let list = load_list(pkg_path);
+    let pkgs: BTreeSet<String> = list.keys().cloned().collect();
+    let installed = installed_set(&pkgs, arch);
+    // Upgrading a version-pinned package would move it off its pin
+    let (pinned, names): (Vec<&String>, Vec<&String>) = installed.iter().partition(|p| list[*p].version.is_some());
+    for p in &pinned {
+        eprintln!("{DIM}  {p} is pinned to a version, skipped{RESET}");
+    }
+    if names.is_empty() {
+        println!("{GREEN}{ICON_DONE}No installed curated packages to upgrade{RESET}");
+        return ExitCode::SUCCESS;
+    }
+    let policy = apt_policy(&names);
+    let newer = newer_candidates(&policy);
+    if newer.is_empty() {
+        println!("{GREEN}{ICON_DONE}All {} installed curated packages are up to date!{RESET}", names.len());
+        return ExitCode::SUCCESS;
+    }
+    println!("{BOLD}{CYAN}{ICON_ROCKET}{} curated package(s) have a newer candidate{RESET}\n", newer.len());
+    for (n, from, to) in &newer {
+        println!("{}", version_change_line(n, from, to));
+    }
+    println!();
+    let targets: Vec<&str> = newer.iter().map(|(n, _, _)| *n).collect();
+    if dry_run {
+        println!("{YELLOW}{ICON_DRY_RUN}Dry run — nothing was upgraded{RESET}");
+        println!("{DIM}Would run: apt-get install --only-upgrade -y {}{RESET}", targets.join(" "));
+        return ExitCode::SUCCESS;
+    }
+    if run_tool("apt-get", apt_get_upgrade_command(&targets).status()).success() {
+        println!("\n{GREEN}{ICON_DONE}Done! Upgraded {} package(s).{RESET}", targets.len());
+        ExitCode::SUCCESS
+    } else {
+        println!("\n{RED}{ICON_ERROR}apt-get exited with errors{RESET}");
+        ExitCode::FAILURE
+    }
+}
+
+// Install specs for uninstalled curated packages, narrowed to `only` when given.
+// Err lists requested names that aren't curated.
+fn missing_specs(list: &PkgList, installed: &BTreeSet<String>, only: Option<&[String]>) -> Result<Vec<String>, Vec<String>> {
+    if let Some(names) = only {
+        let uncurated: Vec<String> = names.iter().filter(|n| !list.contains_key(*n)).cloned().collect();
+        if !uncurated.is_empty() {
+            return Err(uncurated);
+        }
+    }
+    Ok(list
+        .iter()
+        .filter(|(p, _)| !installed.contains(*p))
+        .filter(|(p, _)| only.is_none_or(|names| names.contains(p)))
+        .map(|(p, entry)| install_spec(p, entry))
+        .collect())
+}
+
+// 0 = curated and installed, 1 = curated only, 2 = installed only, 3 = neither
+fn check_code(curated: bool, installed: bool) -> u8 {
+    match (curated, installed) {
+        (true, true) => 0,
+        (true, false) => 1,
+        (false, true) => 2,
+        (false, false) => 3,
+    }
+}
+
+fn cmd_check(pkg_path: &Path, name: &str, arch: &ArchMode) -> ExitCode {
+    let curated = load_packages(pkg_path).contains(name);
+    let installed = installed_set(&BTreeSet::from([name.to_string()]), arch).contains(name);
+    let (c_color, c_icon, c_text) = if curated { (GREEN, ICON_OK, "curated") } else { (DIM, ICON_NO, "not curated") };
+    let (i_color, i_icon, i_text) = if installed { (GREEN, ICON_OK, "installed") } else { (RED, ICON_NO, "not installed") };
+    println!("{BOLD}{name}{RESET}  {c_color}{c_icon}{c_text}{RESET}  {i_color}{i_icon}{i_text}{RESET}");
+    ExitCode::from(check_code(curated, installed))
+}
+
+fn tag_counts(pkgs: &PkgList) -> BTreeMap<&str, usize> {
+    let mut counts = BTreeMap::new();
+    for tag in pkgs.values().flat_map(|e| &e.tags) {
+        *counts.entry(tag.as_str()).or_insert(0) += 1;
+    }
+    counts
+}
+
+// Returns how many packages carried `old`
+fn rename_tag(pkgs: &mut PkgList, old: &str, new: &str) -> usize {
+    let mut n = 0;
+    for entry in pkgs.values_mut() {
+        if entry.tags.remove(old) {
+            entry.tags.insert(new.to_string());
+            n += 1;
+        }
+    }
+    n
+}
+
+fn remove_tag(pkgs: &mut PkgList, tag: &str) -> usize {
+    pkgs.values_mut().map(|e| e.tags.remove(tag)).filter(|removed| *removed).count()
+}
+
+fn cmd_tags(pkg_path: &Path, args: &[String]) -> ExitCode {
+    let args: Vec<&str> = args.iter().map(|a| a.strip_prefix('@').unwrap_or(a)).collect();
+    let stamp = file_stamp(pkg_path);
+    let mut pkgs = load_list(pkg_path);
+    let n = match args.as_slice() {
+        [] => {
+            let counts = tag_counts(&pkgs);
+            if counts.is_empty() {
+                eprintln!("{YELLOW}{ICON_TAG}No tags yet. Use `apt-sync add <pkg> @tag` to tag packages.{RESET}");
+            }
+            for (tag, n) in counts {
+                println!("  {CYAN}@{tag}{RESET}  {DIM}({n} package(s)){RESET}");
+            }
+            return ExitCode::SUCCESS;
+        }
+        ["rename", old, new] => {
+            let n = rename_tag(&mut pkgs, old, new);
+            if let Err(e) = save_merging(pkg_path, stamp, &pkgs, |fresh| { rename_tag(fresh, old, new); }) {
+                return write_failed(pkg_path, &e);
+            }
+            println!("{CYAN}{ICON_TAG}Renamed @{old} → @{new} on {n} package(s){RESET}");
+            n
+        }
+        ["remove", tag] => {
+            let n = remove_tag(&mut pkgs, tag);
+            if let Err(e) = save_merging(pkg_path, stamp, &pkgs, |fresh| { remove_tag(fresh, tag); }) {
+                return write_failed(pkg_path, &e);
+            }
+            println!("{CYAN}{ICON_TAG}Removed @{tag} from {n} package(s){RESET}");
+            n
+        }
+        _ => {
+            eprintln!("{RED}Usage: apt-sync tags [rename <old> <new> | remove <tag>]{RESET}");
+            return ExitCode::FAILURE;
+        }
+    };
+    if n == 0 {
+        eprintln!("{DIM}No packages had that tag{RESET}");
+    }
+    ExitCode::SUCCESS
+}
+
+fn apt_mark_auto_command(names: &[String]) -> Command {
+    let mut cmd = Command::new("apt-mark");
+    cmd.arg("auto").args(names);
+    cmd
+}
+
+fn cmd_mark_auto(names: &[String], dry_run: bool) -> ExitCode {
+    if dry_run {
+        println!("{YELLOW}{ICON_DRY_RUN}Dry run — nothing was marked{RESET}");
+        println!("{DIM}Would run: apt-mark auto {}{RESET}", names.join(" "));
+        return ExitCode::SUCCESS;
+    }
+    let status = run_tool("apt-mark", apt_mark_auto_command(names).status());
+    if status.success() {
+        println!("\n{CYAN}{ICON_MARK}Marked {} package(s) as automatically installed{RESET}", names.len());
+        ExitCode::SUCCESS
+    } else {
+        println!("\n{RED}{ICON_ERROR}apt-mark exited with errors{RESET}");
+        ExitCode::FAILURE
+    }
+}
+
+fn cmd_recommends(pkg_path: &Path, dry_run: bool, arch: &ArchMode) -> ExitCode {
+    let curated = load_packages(pkg_path);
+    let names: Vec<&str> = curated.iter().map(String::as_str).collect();
+    let mut recommends = BTreeMap::new();
+    // Batched to keep the argument list well under ARG_MAX
+    for chunk in names.chunks(200) {
+        let depends = Command::new("apt-cache")
+            .args(["depends", "--recommends", "--no-depends", "--no-suggests", "--no-conflicts"])
+            .args(["--no-breaks", "--no-replaces", "--no-enhances", "--no-pre-depends"])
+            .args(chunk)
+            .output();
+        let output = run_tool("apt-cache", depends);
+        recommends.extend(parse_recommends(&String::from_utf8_lossy(&output.stdout)));
+    }
+    let candidates: BTreeSet<String> = recommends.values().flatten().filter(|r| !curated.contains(*r)).cloned().collect();
+    let installed = installed_set(&candidates, arch);
+    let missing: BTreeSet<&String> = candidates.difference(&installed).collect();
+    if missing.is_empty() {
+        println!("{GREEN}{ICON_DONE}Every recommended package is installed!{RESET}");
+        return ExitCode::SUCCESS;
+    }
+
+    println!("{BOLD}{CYAN}{ICON_IDEA}{} recommended package(s) not installed{RESET}\n", missing.len());
+    for (pkg, recs) in &recommends {
+        let recs: Vec<&str> = recs.iter().filter(|r| missing.contains(r)).map(String::as_str).collect();
+        if !recs.is_empty() {
+            println!("  {BOLD}{pkg}{RESET} {DIM}recommends{RESET} {}", recs.join(", "));
+        }
+    }
+    println!();
+    let missing: Vec<String> = missing.into_iter().cloned().collect();
+    if dry_run {
+        println!("{YELLOW}{ICON_DRY_RUN}Dry run — nothing was installed or curated{RESET}");
+        println!("{DIM}Would run: apt-get install -y {}{RESET}", missing.join(" "));
+        return ExitCode::SUCCESS;
+    }
+
+    print!("{CYAN}[i]nstall, [c]urate, [b]oth, or [N]othing?{RESET} ");
+    io::stdout().flush().unwrap();
+    let mut line = String::new();
+    io::stdin().read_line(&mut line).unwrap_or(0);
+    let choice = line.trim().to_lowercase();
+    // Listing and curating don't need root, so only an install answer asks for it (a sudo re-run asks again)
+    if matches!(choice.as_str(), "i" | "b")
+        && let Some(code) = require_root("recommends", pkg_path)
+    {
+        return code;
+    }
+    if matches!(choice.as_str(), "c" | "b") && cmd_modify(pkg_path, &missing, true) != ExitCode::SUCCESS {
+        return ExitCode::FAILURE;
+    }
+    if matches!(choice.as_str(), "i" | "b") {
+        let specs: Vec<&str> = missing.iter().map(String::as_str).collect();
+        if apt_get_install(&specs, false, None, false) != AptOutcome::Success {
+            println!("\n{RED}{ICON_ERROR}apt-get exited with errors{RESET}");
+            return ExitCode::FAILURE;
+        }
+    }
+    ExitCode::SUCCESS
+}
+
+// `apt-cache depends --recommends` output → package → recommended names.
+// `|Recommends:` lines are leading alternatives (only the last one of a group is
+// suggested) and `<name>` is a virtual package, so both are skipped.
+fn parse_recommends(output: &str) -> BTreeMap<String, Vec<String>> {
+    let mut map: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    let mut current = None;
+    for line in output.lines() {
+        if !line.starts_with(' ') {
+            let name = line.trim();
+            current = (!name.is_empty()).then(|| name.to_string());
+            continue;
+        }
+        let (Some(pkg), Some(rec)) = (&current, line.strip_prefix("  Recommends: ")) else { continue };
+        let rec = rec.trim();
+        if !rec.starts_with('<') {
+            let recs = map.entry(pkg.clone()).or_default();
+            if !recs.iter().any(|r| r == rec) {
+                recs.push(rec.to_string());
+            }
+        }
+    }
+    map
+}
+
+const DIFF_SUMMARY_THRESHOLD: usize = 50;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DiffListing {
+    Auto, // summarize sections longer than DIFF_SUMMARY_THRESHOLD
+    Summary,
+    Full,
+}
+
+const SYSTEM_DIFF_HEADERS: [(Glyph, &str); 2] = [(ICON_SEARCH, "On system but not curated"), (ICON_LIST, "Curated but not on system")];
+
+fn cmd_diff(pkg_path: &Path, listing: DiffListing, against: Option<&Path>) -> ExitCode {
+    let curated = load_packages(pkg_path);
+    let Some(other_path) = against else {
+        let system = system_manual_packages();
+        let (on_system_only, in_list_only) = compute_diff(&system, &curated);
+        if on_system_only.is_empty() && in_list_only.is_empty() {
+            println!("{GREEN}{ICON_DONE}System and curated list are in perfect sync!{RESET}");
+            return ExitCode::SUCCESS;
+        }
+        let (lines, summarized) = diff_lines(&on_system_only, &in_list_only, SYSTEM_DIFF_HEADERS, listing);
+        for line in lines {
+            println!("{line}");
+        }
+        for hint in diff_hints(!on_system_only.is_empty(), !in_list_only.is_empty(), summarized) {
+            eprintln!("{DIM}{hint}{RESET}");
+        }
+        return ExitCode::SUCCESS;
+    };
+
+    if !other_path.exists() {
+        eprintln!("{RED}Cannot read {}: no such file{RESET}", other_path.display());
+        return ExitCode::FAILURE;
+    }
+    let other = load_packages(other_path);
+    let (mine_only, other_only) = compute_diff(&curated, &other);
+    if mine_only.is_empty() && other_only.is_empty() {
+        println!("{GREEN}{ICON_DONE}{} and {} list the same packages{RESET}", pkg_path.display(), other_path.display());
+        return ExitCode::SUCCESS;
+    }
+    let headers = [format!("Only in {}", pkg_path.display()), format!("Only in {}", other_path.display())];
+    let (lines, summarized) = diff_lines(&mine_only, &other_only, [(ICON_FILE, &headers[0]), (ICON_FILE, &headers[1])], listing);
+    for line in lines {
+        println!("{line}");
+    }
+    if summarized {
+        eprintln!("{DIM}Use `--full` to list everything{RESET}");
+    }
+    ExitCode::SUCCESS
+}
+
+// Only suggests the commands that would fix the drift actually found
+fn diff_hints(uncurated: bool, missing: bool, summarized: bool) -> Vec<&'static str> {
+    let mut hints = Vec::new();
+    if uncurated {
+        hints.push("Use `apt-sync add <pkg>` or `apt-sync snap` to curate packages found on the system");
+    }
+    if missing {
+        hints.push("Use `apt-sync install` to install curated packages that are missing");
+    }
+    if summarized {
+        hints.push("Use `--full` to list everything");
+    }
+    hints
+}
+
+// (only in `a`, only in `b`)
+fn compute_diff<'a>(a: &'a BTreeSet<String>, b: &'a BTreeSet<String>) -> (Vec<&'a String>, Vec<&'a String>) {
+    (a.difference(b).collect(), b.difference(a).collect())
+}
+
+// Returns the listing and whether any section was summarized
+fn diff_lines(left_only: &[&String], right_only: &[&String], headers: [(Glyph, &str); 2], listing: DiffListing) -> (Vec<String>, bool) {
+    let mut lines = Vec::new();
+    let mut summarized = false;
+    for (items, (header_icon, header), icon, color) in [
+        (left_only, headers[0], ICON_UNCURATED, YELLOW),
+        (right_only, headers[1], ICON_NO, RED),
+    ] {
+        if items.is_empty() {
+            continue;
+        }
+        let summarize = match listing {
+            DiffListing::Auto => items.len() > DIFF_SUMMARY_THRESHOLD,
+            DiffListing::Summary => true,
+            DiffListing::Full => false,
+        };
+        if summarize {
+            lines.push(format!("{BOLD}{color}{header_icon}{header}{RESET} {DIM}({} packages, summarized){RESET}", items.len()));
+            summarized = true;
+        } else {
+            lines.push(format!("{BOLD}{color}{header_icon}{header}{RESET} {DIM}({} packages){RESET}\n", items.len()));
+            lines.extend(items.iter().map(|p| format!("  {color}{icon}{p}{RESET}")));
+        }
+        lines.push(String::new());
+    }
+    (lines, summarized)
+}
+
+fn cmd_fmt(pkg_path: &Path) -> ExitCode {
+    let contents = match fs::read_to_string(pkg_path) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("{RED}Cannot read {}: {e}{RESET}", pkg_path.display());
+            return ExitCode::FAILURE;
+        }
+    };
+    let formatted = format_packages_text(&contents);
+    if formatted == contents {
+        println!("{DIM}{} is already formatted{RESET}", pkg_path.display());
+        return ExitCode::SUCCESS;
+    }
+    if let Err(e) = write_atomically(pkg_path, formatted.as_bytes()) {
+        return write_failed(pkg_path, &e);
+    }
+    println!("{GREEN}{ICON_OK}Formatted {}{RESET}", pkg_path.display());
+    ExitCode::SUCCESS
+}
+
+const ENV_TOOLS: &[&str] = &["apt-get", "apt-mark", "apt-cache", "dpkg-query", "journalctl", "curl", "sqlite3"];
+
+fn on_path(bin: &str) -> bool {
+    env::var_os("PATH").is_some_and(|paths| env::split_paths(&paths).any(|d| d.join(bin).is_file()))
+}
+
+fn cmd_env(config: &Config, config_arg: Option<&str>, window_secs: i64) {
+    let pkg = resolve_pkg_file(env::var("APT_SYNC_FILE").ok(), config);
+    let config_path = config_arg.map_or_else(|| config_dir().join(CONFIG_FILENAME), PathBuf::from);
+    let histfile = env::var("HISTFILE").ok();
+    let home = env::var("HOME").ok();
+    let history = history_candidates(histfile.as_deref(), home.as_deref()).into_iter().find(|p| p.is_file());
+    let tools: Vec<(&str, bool)> = ENV_TOOLS.iter().map(|t| (*t, on_path(t))).collect();
+    for line in env_lines(&pkg, &config_path, history.as_deref(), (color_enabled(), window_secs), &tools) {
+        println!("{line}");
+    }
+}
+
+// `key: value` lines, one setting each, so the output can be pasted into a bug report
+fn env_lines(
+    pkg: &(PathBuf, &str),
+    config_path: &Path,
+    history: Option<&Path>,
+    (color, window_secs): (bool, i64), // as resolved for this run: flags, NO_COLOR, the terminal
+    tools: &[(&str, bool)],
+) -> Vec<String> {
+    let config_state = if config_path.is_file() { "" } else { " (not present)" };
+    let mut lines = vec![
+        format!("packages file: {} (from {})", pkg.0.display(), pkg.1),
+        format!("config file: {}{config_state}", config_path.display()),
+        "apt frontend: apt-get".to_string(),
+        format!("color: {}", if color { "on (ANSI)" } else { "off" }),
+        format!("why window: {} before/after", format_duration_secs(window_secs)),
+        format!("shell history: {}", history.map_or_else(|| "none found".to_string(), |p| p.display().to_string())),
+    ];
+    for (tool, present) in tools {
+        lines.push(format!("{tool}: {}", if *present { "found" } else { "missing" }));
+    }
+    lines
+}
+
+fn cmd_init(config: &Config, from_system: bool, force: bool) -> ExitCode {
+    let path = explicit_pkg_path(config).unwrap_or_else(|| config_dir().join(PKG_FILENAME));
+    if path.exists() && !force {
+        eprintln!("{RED}{} already exists — use --force to overwrite{RESET}", path.display());
+        return ExitCode::FAILURE;
+    }
+    let mut seed = BTreeSet::new();
+    if from_system {
+        let system = system_manual_packages();
+        print!("{CYAN}Seed with {} manually installed package(s)?{RESET} [y/N] ", system.len());
+        io::stdout().flush().unwrap();
+        let mut line = String::new();
+        if io::stdin().read_line(&mut line).is_ok() && matches!(line.trim().to_lowercase().as_str(), "y" | "yes") {
+            seed = system;
+        }
+    }
+    match init_packages_file(&path, &seed, force) {
+        Ok(()) => {
+            println!("{GREEN}{ICON_DONE}Created {}{RESET}  {DIM}({} package(s)){RESET}", path.display(), seed.len());
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("{RED}Cannot create {}: {e}{RESET}", path.display());
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum SnapEnd {
+    Done,
+    Quit,
+    Interrupted, // stdin closed mid-review
+}
+
+// An accepted package that can't be saved ends the review; nothing after it is asked
+fn snap_review(
+    mut reader: impl BufRead,
+    uncurated: &[&String],
+    mut accept: impl FnMut(&str) -> io::Result<()>,
+) -> io::Result<SnapEnd> {
+    for pkg in uncurated {
+        print!("  {CYAN}{pkg}{RESET}  [y/n/q] ");
+        io::stdout().flush().unwrap();
+        let mut line = String::new();
+        if !matches!(reader.read_line(&mut line), Ok(n) if n > 0) {
+            return Ok(SnapEnd::Interrupted);
+        }
+        match line.trim().to_lowercase().as_str() {
+            "y" | "yes" => accept(pkg)?,
+            "q" | "quit" => return Ok(SnapEnd::Quit),
+            _ => {}
+        }
+    }
+    Ok(SnapEnd::Done)
+}
+
+fn add_one(pkg_path: &Path, name: &str) -> io::Result<()> {
+    let names = [name.to_string()];
+    let stamp = file_stamp(pkg_path);
+    let mut pkgs = load_list(pkg_path);
+    apply_modify(&mut pkgs, &names, true);
+    save_merging(pkg_path, stamp, &pkgs, |fresh| { apply_modify(fresh, &names, true); }).map(|_| ())
+}
+
+// Splits selections into uncurated names to adopt and names that weren't up for review
+fn partition_selections<'a>(selections: &'a BTreeSet<String>, uncurated: &[&String]) -> (Vec<String>, Vec<&'a str>) {
+    let (accepted, rejected): (Vec<&String>, Vec<&String>) = selections.iter().partition(|s| uncurated.contains(s));
+    (accepted.into_iter().cloned().collect(), rejected.into_iter().map(String::as_str).collect())
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Import {
+    list: PkgList,
+    added: Vec<String>,
+    present: usize,
+    dropped: Vec<String>, // curated but not manual on this system; replace mode only
+}
+
+// The manual system packages as the new list; existing entries keep their tags, pins and section
+fn import_plan(list: &PkgList, system: &BTreeSet<String>, merge: bool) -> Import {
+    let mut new = list.clone();
+    if !merge {
+        new.retain(|p, _| system.contains(p));
+    }
+    let added: Vec<String> = system.iter().filter(|p| !list.contains_key(*p)).cloned().collect();
+    for p in &added {
+        new.insert(p.clone(), PkgEntry::default());
+    }
+    let dropped = list.keys().filter(|p| !new.contains_key(*p)).cloned().collect();
+    Import { list: new, present: system.len() - added.len(), added, dropped }
+}
+
+// `snap` without the questions: every manual package goes in, replacing the list unless --merge
+fn cmd_import(pkg_path: &Path, merge: bool, dry_run: bool) -> ExitCode {
+    let system = system_manual_packages();
+    let plan = import_plan(&load_list(pkg_path), &system, merge);
+    for p in &plan.added {
+        println!("  {GREEN}{ICON_ADD}{p}{RESET}");
+    }
+    for p in &plan.dropped {
+        println!("  {RED}{ICON_DEL}{p}{RESET}  {DIM}(not a manual system package){RESET}");
+    }
+    let summary = format!("{} added, {} already present", plan.added.len(), plan.present);
+    let summary = if merge { summary } else { format!("{summary}, {} dropped", plan.dropped.len()) };
+    if dry_run {
+        println!("\n{YELLOW}{ICON_DRY_RUN}Dry run — would import: {summary}{RESET}");
+        return ExitCode::SUCCESS;
+    }
+    if plan.added.is_empty() && plan.dropped.is_empty() {
+        println!("{GREEN}{ICON_DONE}packages.txt already matches the manual system packages ({summary}){RESET}");
+        return ExitCode::SUCCESS;
+    }
+    if let Err(e) = save_packages(pkg_path, &plan.list) {
+        return write_failed(pkg_path, &e);
+    }
+    println!("\n{GREEN}{ICON_DONE}Imported: {summary}{RESET}");
+    ExitCode::SUCCESS
+}
+
+fn cmd_snap(pkg_path: &Path, incremental: bool, selections: Option<&Path>) -> ExitCode {
+    let system = system_manual_packages();
+    let curated = load_packages(pkg_path);
+    let uncurated: Vec<&String> = system.difference(&curated).collect();
+
+    if let Some(path) = selections {
+        let Ok(contents) = fs::read_to_string(path) else {
+            eprintln!("{RED}Cannot read selections from {}{RESET}", path.display());
+            return ExitCode::FAILURE;
+        };
+        let selected = parse_packages(&contents);
+        let (accepted, rejected) = partition_selections(&selected, &uncurated);
+        for r in &rejected {
+            let why = if curated.contains(*r) { "already curated" } else { "not a manual system package" };
+            eprintln!("  {DIM}  {r} ({why}){RESET}");
+        }
+        if accepted.is_empty() {
+            eprintln!("{DIM}No packages added.{RESET}");
+            return ExitCode::SUCCESS;
+        }
+        return cmd_modify(pkg_path, &accepted, true);
+    }
+
+    if uncurated.is_empty() {
+        println!("{GREEN}{ICON_DONE}All manual system packages are already curated!{RESET}");
+        return ExitCode::SUCCESS;
+    }
+
+    println!("{BOLD}{CYAN}{ICON_SNAP}Snapshot — {} uncurated manual packages{RESET}\n", uncurated.len());
+    println!(
+        "{DIM}For each package, type {RESET}{BOLD}y{RESET}{DIM} to add, \
+         {RESET}{BOLD}n{RESET}{DIM} to skip, \
+         {RESET}{BOLD}q{RESET}{DIM} to quit:{RESET}\n"
+    );
+
+    let mut to_add = Vec::new();
+    let end = snap_review(io::stdin().lock(), &uncurated, |pkg| {
+        if incremental {
+            add_one(pkg_path, pkg)?;
+        }
+        to_add.push(pkg.to_string());
+        Ok(())
+    });
+    let end = match end {
+        Ok(end) => end,
+        Err(e) => return write_failed(pkg_path, &e),
+    };
+
+    if end == SnapEnd::Interrupted {
+        eprintln!("\n{YELLOW}{ICON_WARN}Input ended before the review finished{RESET}");
+        if incremental && !to_add.is_empty() {
+            eprintln!("{DIM}{} accepted package(s) were saved; run `apt-sync snap` again to resume{RESET}", to_add.len());
+        }
+    }
+    if to_add.is_empty() {
+        eprintln!("\n{DIM}No packages added.{RESET}");
+        return ExitCode::SUCCESS;
+    }
+    if !incremental {
+        return cmd_modify(pkg_path, &to_add, true);
+    }
+    println!("\n{CYAN}{ICON_NOTE}Added {} package(s) to packages.txt{RESET}", to_add.len());
+    ExitCode::SUCCESS
+}
+
+struct WhyOpts {
+    before_secs: i64,
+    after_secs: i64,
+    show_all: bool,
+    only: Vec<String>, // prefix allowlist for nearby commands; overrides the denylist and --all
+    siblings: bool,
+    group_siblings: bool,
+    same_day: bool,
+    quiet: bool,
+    format: Option<String>,
+    csv: bool,
+    timeline: bool,
+    source: HistorySource,
+    cmdline_width: Option<usize>, // None = --full-cmdline
+    dir: Option<DirOutput>,       // --open / --print-dir
+    hook: Option<PathBuf>,        // $APT_SYNC_WHY_HOOK
+    verbose: bool,
+    json: bool,
+    limit: Option<usize>, // --limit=N: most recent events per package
+    since: Option<String>, // --since / --until: `YYYY-MM-DD`, inclusive
+    until: Option<String>,
+    removals_only: bool,
+    oldest_first: bool, // display order only; --limit still keeps the most recent events
+}
+
+// A CLI can't move its parent shell, so `why --open` prints a `cd` for the user to run
+// and `--print-dir` prints just the path for `cd "$(apt-sync why pkg --print-dir)"`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DirOutput {
+    Cd,
+    Bare,
+}
+
+// parse_journal_pwd abbreviates $HOME to `~`, which `cd "..."` wouldn't expand
+fn expand_home(pwd: &str, home: Option<&str>) -> String {
+    match (pwd.strip_prefix('~'), home) {
+        (Some(rest), Some(home)) if rest.is_empty() || rest.starts_with('/') => format!("{home}{rest}"),
+        _ => pwd.to_string(),
+    }
+}
+
+// Newest install of any of `names` with a recorded directory; history is sorted first, since
+// rotated logs and journal entries don't arrive in date order
+fn latest_install_dir(entries: &[HistoryEntry], names: &[&str], pwd_of: impl Fn(&HistoryEntry) -> Option<String>) -> Option<String> {
+    let mut hits: Vec<&HistoryEntry> = names.iter().flat_map(|n| find_install_history(entries, n)).collect();
+    hits.sort_by_cached_key(|e| std::cmp::Reverse(normalize_apt_date(&e.date)));
+    hits.into_iter().find_map(pwd_of)
+}
+
+fn dir_output_line(dir: &str, mode: DirOutput) -> String {
+    match mode {
+        DirOutput::Cd => format!("cd '{}'", dir.replace('\'', r"'\''")),
+        DirOutput::Bare => dir.to_string(),
+    }
+}
+
+// $COLUMNS when the shell exports it, else the classic 80
+fn terminal_width() -> usize {
+    env::var("COLUMNS").ok().and_then(|c| c.parse().ok()).filter(|&w| w > 0).unwrap_or(80)
+}
+
+fn truncate_chars(s: &str, max: usize) -> String {
+    if s.chars().count() <= max {
+        return s.to_string();
+    }
+    let mut out: String = s.chars().take(max.saturating_sub(1)).collect();
+    out.push('…');
+    out
+}
+
+fn cmd_why(names: &[String], opts: &WhyOpts, arch: &ArchMode) {
+    let names: Vec<&str> = names.iter().map(|n| strip_arch(n)).collect();
+    let mut entries = Vec::new();
+    if opts.source != HistorySource::Journal {
+        let gz_paths = rotated_history_logs();
+        let _progress = StatusLine::show(
+            &format!("reading apt history ({} logs)...", gz_paths.len() + 1),
+            !opts.quiet && gz_paths.len() >= PROGRESS_LOG_THRESHOLD && io::stdout().is_terminal(),
+        );
+        let (log, denied) = read_history_logs(&gz_paths);
+        entries = parse_history(&log);
+        if denied {
+            eprintln!(
+                "{YELLOW}{ICON_WARN}/var/log/apt/history.log isn't readable by this user, so recent installs are missing \
+                 — try `sudo apt-sync why`{RESET}"
+            );
+        }
+    }
+    let journal = opts.source == HistorySource::Journal || (opts.source == HistorySource::Auto && entries.is_empty());
+    if journal {
+        entries = read_journal_history();
+    }
+    let mut users: BTreeMap<u32, Option<String>> = BTreeMap::new();
+    for entry in &mut entries {
+        if let Some(raw) = entry.requested_by.take() {
+            entry.requested_by =
+                Some(requested_by_display(&raw, |uid| users.entry(uid).or_insert_with(|| passwd_user(uid)).clone()));
+        }
+    }
+    let in_range = |e: &HistoryEntry| in_date_range(&e.date, opts.since.as_deref(), opts.until.as_deref());
+    let history = |name: &str| {
+        let mut hits = find_install_history(&entries, name);
+        hits.retain(|e| in_range(e));
+        let older = limit_and_order(&mut hits, opts.limit, opts.oldest_first);
+        (hits, older)
+    };
+    if opts.timeline {
+        let dpkg = parse_dpkg_log(&read_dpkg_logs());
+        for (i, name) in names.iter().enumerate() {
+            if i > 0 {
+                println!();
+            }
+            let events = build_timeline(&entries, &dpkg, name);
+            if events.is_empty() {
+                eprintln!("{DIM}{name}: no history found{RESET}");
+                continue;
+            }
+            println!("{BOLD}{CYAN}{name}{RESET}");
+            for event in &events {
+                println!("{}", timeline_line(event));
+            }
+        }
+        return;
+    }
+    if let Some(mode) = opts.dir {
+        let pwd = latest_install_dir(&entries, &names, |e| read_journal_pwd(&e.date, &e.commandline));
+        match pwd {
+            Some(pwd) => println!("{}", dir_output_line(&expand_home(&pwd, env::var("HOME").ok().as_deref()), mode)),
+            None => eprintln!("{DIM}No working directory recorded for {}{RESET}", names.join(", ")),
+        }
+        return;
+    }
+    if opts.csv {
+        println!("package,date,commandline,requested_by,siblings,pwd");
+        for name in &names {
+            for entry in history(name).0 {
+                let pwd = read_journal_pwd(&entry.date, &entry.commandline);
+                println!("{}", why_csv_row(name, entry, pwd.as_deref()));
+            }
+        }
+        return;
+    }
+    if let Some(ref tmpl) = opts.format {
+        for name in &names {
+            for entry in history(name).0 {
+                let pwd = read_journal_pwd(&entry.date, &entry.commandline);
+                println!("{}", format_why_event(tmpl, name, entry, pwd.as_deref()));
+            }
+        }
+        return;
+    }
+    // One date lookup per event; the windows around them bound how much history gets parsed
+    let epochs: BTreeMap<&str, i64> = names
+        .iter()
+        .flat_map(|n| history(n).0)
+        .filter_map(|e| Some((e.date.as_str(), apt_date_to_epoch(&e.date)?)))
+        .collect();
+    let windows: Vec<(i64, i64)> = epochs.values().map(|t| (t - opts.before_secs, t + opts.after_secs)).collect();
+    let (shell_history, history_path) = read_shell_history(&windows);
+    // Working directory from journal (else atuin's cwd), shell history context
+    let context = |entry: &HistoryEntry| {
+        let epoch = epochs.get(entry.date.as_str()).copied();
+        let pwd = read_journal_pwd(&entry.date, &entry.commandline).or_else(|| {
+            let cwd = history_cwd(&shell_history, epoch?, opts.before_secs, opts.after_secs)?;
+            Some(env::var("HOME").map_or_else(|_| cwd.to_string(), |home| abbreviate_home(cwd, &home)))
+        });
+        let nearby = epoch
+            .map(|epoch| find_nearby_commands(&shell_history, epoch, opts.before_secs, opts.after_secs, opts.show_all, &opts.only))
+            .unwrap_or_default();
+        (pwd, nearby)
+    };
+    if opts.json {
+        let events: Vec<String> = names
+            .iter()
+            .flat_map(|name| history(name).0.into_iter().map(move |entry| (name, entry)))
+            .map(|(name, entry)| {
+                let (pwd, nearby) = context(entry);
+                why_json_event(&entries, entry, name, pwd.as_deref(), &nearby)
+            })
+            .collect();
+        println!("{}", if events.is_empty() { "[]".to_string() } else { format!("[\n{}\n]", events.join(",\n")) });
+        return;
+    }
+    let installed = installed_set(&names.iter().map(|n| n.to_string()).collect(), arch);
+    // Tells "nothing matched" apart from "nothing to search"
+    if !opts.quiet && !windows.is_empty() {
+        let home = env::var("HOME").ok();
+        println!("{}", why_sources_line(opts.before_secs, opts.after_secs, history_path.as_deref(), journal, home.as_deref()));
+    }
+
+    for (i, name) in names.iter().enumerate() {
+        if i > 0 {
+            println!();
+        }
+        let mut hits = find_install_history(&entries, name);
+        hits.retain(|e| in_range(e));
+        let mut removals = find_removal_history(&entries, name);
+        removals.retain(|(e, _)| in_range(e));
+        let mut events = why_events(&hits, &removals, opts.removals_only);
+        if events.is_empty() {
+            let what = if opts.removals_only { "removal" } else { "install" };
+            eprintln!("{DIM}{name}: no {what} history found{RESET}");
+            continue;
+        }
+        let older = limit_and_order(&mut events, opts.limit, opts.oldest_first);
+        let hits: Vec<&HistoryEntry> = events.iter().filter(|(_, kind)| kind.is_none()).map(|(e, _)| *e).collect();
+        println!("{BOLD}{CYAN}{name}{RESET}");
+        println!("{}", why_installed_line(installed.contains(*name)));
+        for (entry, kind) in &events {
+            if let Some(kind) = kind {
+                println!("{}", removal_line(entry, *kind));
+                continue;
+            }
+            let (pwd, nearby) = context(entry);
+            for line in why_entry_lines(&entries, entry, name, opts, pwd.as_deref(), &nearby) {
+                println!("{line}");
+            }
+            if let Some(ref hook) = opts.hook {
+                for line in why_hook_lines(hook, name, &entry.date, opts.verbose) {
+                    println!("{line}");
+                }
+            }
+        }
+        if older > 0 {
+            println!("  {DIM}… and {older} older event(s){RESET}");
+        }
+        if opts.group_siblings {
+            let grouped = grouped_siblings(&hits, name);
+            if !grouped.is_empty() {
+                let ranked: Vec<String> =
+                    grouped.iter().map(|(p, n)| if *n > 1 { format!("{p} ×{n}") } else { p.to_string() }).collect();
+                let ranked: Vec<&str> = ranked.iter().map(String::as_str).collect();
+                println!("  {DIM}with (across {} installs): {}{RESET}", hits.len(), format_pkg_list(&ranked));
+            }
+        }
+    }
+}
+
+// Installs and removals interleaved newest first, so the package's whole lifecycle reads in order
+fn why_events<'a>(
+    hits: &[&'a HistoryEntry],
+    removals: &[(&'a HistoryEntry, Removal)],
+    removals_only: bool,
+) -> Vec<(&'a HistoryEntry, Option<Removal>)> {
+    let installs = hits.iter().filter(|_| !removals_only).map(|e| (*e, None));
+    let mut events: Vec<_> = installs.chain(removals.iter().map(|(e, kind)| (*e, Some(*kind)))).collect();
+    events.sort_by_cached_key(|(e, _)| std::cmp::Reverse(normalize_apt_date(&e.date)));
+    events
+}
+
+// $APT_SYNC_WHY_HOOK gets `<pkg> <date>` per event; its stdout is extra context from
+// sources apt-sync can't know about. A failing hook is skipped, noisily only with --verbose
+fn why_hook_lines(hook: &Path, name: &str, date: &str, verbose: bool) -> Vec<String> {
+    let date = date.split_whitespace().collect::<Vec<_>>().join(" ");
+    let output = Command::new(hook).args([name, &date]).stdin(Stdio::null()).stderr(Stdio::null()).output();
+    let failure = match output {
+        Ok(out) if out.status.success() => {
+            return String::from_utf8_lossy(&out.stdout)
+                .lines()
+                .filter(|l| !l.trim().is_empty())
+                .map(|l| format!("     {DIM}{}{RESET}", l.trim_end()))
+                .collect();
+        }
+        Ok(out) => out.status.to_string(),
+        Err(e) => e.to_string(),
+    };
+    if verbose {
+        eprintln!("{YELLOW}{ICON_WARN}why hook {} failed for {name}: {failure}{RESET}", hook.display());
+    }
+    Vec::new()
+}
+
+fn why_sources_line(before_secs: i64, after_secs: i64, history: Option<&Path>, journal: bool, home: Option<&str>) -> String {
+    let history = match (history, home) {
+        (Some(path), Some(home)) => abbreviate_home(&path.to_string_lossy(), home),
+        (Some(path), None) => path.display().to_string(),
+        (None, _) => "none found".to_string(),
+    };
+    format!(
+        "{DIM}window: {} before, {} after · shell history: {history} · apt log: {}{RESET}",
+        format_duration_secs(before_secs),
+        format_duration_secs(after_secs),
+        if journal { "journal" } else { "/var/log/apt/history.log" }
+    )
+}
+
+// Siblings from every hit, most frequent co-installs first
+fn grouped_siblings<'a>(hits: &[&'a HistoryEntry], name: &str) -> Vec<(&'a str, usize)> {
+    let mut counts: BTreeMap<&str, usize> = BTreeMap::new();
+    for entry in hits {
+        let sibs: BTreeSet<&str> = entry.installed.iter().map(String::as_str).filter(|p| *p != name).collect();
+        for p in sibs {
+            *counts.entry(p).or_default() += 1;
+        }
+    }
+    let mut ranked: Vec<(&str, usize)> = counts.into_iter().collect();
+    ranked.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+    ranked
+}
+
+// One line per event for grep/awk; missing fields expand to an empty string
+fn format_why_event(tmpl: &str, name: &str, entry: &HistoryEntry, pwd: Option<&str>) -> String {
+    let date = entry.date.split_whitespace().collect::<Vec<_>>().join(" ");
+    let siblings: Vec<&str> = entry.installed.iter().map(String::as_str).filter(|p| *p != name).collect();
+    let siblings = siblings.join(",");
+    let tmpl = tmpl.replace("\\t", "\t").replace("\\n", "\n");
+    // Single pass so values containing `{...}` are never re-expanded
+    let mut out = String::new();
+    let mut rest = tmpl.as_str();
+    while let Some(start) = rest.find('{') {
+        out.push_str(&rest[..start]);
+        let after = &rest[start..];
+        let Some(end) = after.find('}') else {
+            rest = after;
+            break;
+        };
+        let value = match &after[1..end] {
+            "pkg" => name,
+            "date" => &date,
+            "cmdline" => &entry.commandline,
+            "user" => entry.requested_by.as_deref().unwrap_or(""),
+            "pwd" => pwd.unwrap_or(""),
+            "siblings" => &siblings,
+            _ => &after[..=end],
+        };
+        out.push_str(value);
+        rest = &after[end + 1..];
+    }
+    out.push_str(rest);
+    out
+}
+
+// RFC 4180: quote fields holding a comma, quote or newline, doubling inner quotes
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn why_csv_row(name: &str, entry: &HistoryEntry, pwd: Option<&str>) -> String {
+    let date = entry.date.split_whitespace().collect::<Vec<_>>().join(" ");
+    let siblings: Vec<&str> = entry.installed.iter().map(String::as_str).filter(|p| *p != name).collect();
+    [name, &date, &entry.commandline, entry.requested_by.as_deref().unwrap_or(""), &siblings.join(";"), pwd.unwrap_or("")]
+        .map(csv_field)
+        .join(",")
+}
+
+// Ties the history to the present; history without the package now means something removed it later
+fn why_installed_line(installed: bool) -> String {
+    if installed {
+        format!("  {GREEN}{ICON_OK}currently installed{RESET}")
+    } else {
+        format!("  {RED}{ICON_NO}no longer installed{RESET}")
+    }
+}
+
+// The decorated layout's context as one object; fields with no value are left out
+fn why_json_event(entries: &[HistoryEntry], entry: &HistoryEntry, name: &str, pwd: Option<&str>, nearby: &[String]) -> String {
+    let array = |items: &[&str]| format!("[{}]", items.iter().map(|i| json_str(i)).collect::<Vec<_>>().join(", "));
+    let date = entry.date.split_whitespace().collect::<Vec<_>>().join(" ");
+    let sibs: Vec<&str> = entry.installed.iter().map(String::as_str).filter(|p| *p != name).collect();
+    let same_day = same_day_neighbors(entries, entry, name, &sibs.iter().copied().collect());
+    let nearby: Vec<&str> = nearby.iter().map(String::as_str).collect();
+    let mut fields = vec![
+        format!("\"package\": {}", json_str(name)),
+        format!("\"date\": {}", json_str(&date)),
+        format!("\"commandline\": {}", json_str(&entry.commandline)),
+    ];
+    if let Some(ref user) = entry.requested_by {
+        fields.push(format!("\"requested_by\": {}", json_str(user)));
+    }
+    fields.push(format!("\"siblings\": {}", array(&sibs)));
+    fields.push(format!("\"same_day\": {}", array(&same_day)));
+    if let Some(pwd) = pwd {
+        fields.push(format!("\"pwd\": {}", json_str(pwd)));
+    }
+    fields.push(format!("\"nearby_commands\": {}", array(&nearby)));
+    format!("  {{{}}}", fields.join(", "))
+}
+
+fn why_entry_lines(
+    entries: &[HistoryEntry],
+    entry: &HistoryEntry,
+    name: &str,
+    opts: &WhyOpts,
+    pwd: Option<&str>,
+    nearby: &[String],
+) -> Vec<String> {
+    let mut lines = Vec::new();
+    let date = entry.date.split_whitespace().next().unwrap_or(&entry.date);
+    // `  📅 YYYY-MM-DD  ` takes 17 columns (the emoji is double width); plain output needs fewer
+    let cmdline = match opts.cmdline_width {
+        Some(width) => truncate_chars(&entry.commandline, width.saturating_sub(date.chars().count() + 7).max(20)),
+        None => entry.commandline.clone(),
+    };
+    lines.push(format!("  {GREEN}{ICON_DATE}{date}{RESET}  {DIM}{cmdline}{RESET}"));
+    if let Some(ref user) = entry.requested_by {
+        lines.push(format!("     {DIM}by {user}{RESET}"));
+    }
+    if let Some(pwd) = pwd {
+        lines.push(format!("     {DIM}in: {pwd}{RESET}"));
+    }
+
+    let sibs: Vec<&str> = entry.installed.iter().map(String::as_str).filter(|p| *p != name).collect();
+    if opts.siblings && !opts.group_siblings && !sibs.is_empty() {
+        lines.push(format!("     {DIM}with: {}{RESET}", format_pkg_list(&sibs)));
+    }
+    if opts.same_day {
+        let sibling_set: BTreeSet<&str> = sibs.iter().copied().collect();
+        let neighbors = same_day_neighbors(entries, entry, name, &sibling_set);
+        if !neighbors.is_empty() {
+            lines.push(format!("     {DIM}also that day: {}{RESET}", format_pkg_list(&neighbors)));
+        }
+    }
+
+    if !nearby.is_empty() {
+        lines.push(format!("     {DIM}around then:{RESET}"));
+        for cmd in nearby {
+            lines.push(format!("       {DIM}{cmd}{RESET}"));
+        }
+    }
+    lines
+}
+
+// ── Help ────────────────────────────────────────────────────────────
+
+fn print_help() {
+    println!(
+        "\n\
+{BOLD}{CYAN}{ICON_PACKAGE}apt-sync{RESET} — curated APT package manager\n\
+\n\
+{BOLD}USAGE:{RESET}\n    \
+    apt-sync <command> [options]\n\
+\n\
+{BOLD}COMMANDS:{RESET}\n    \
+    {GREEN}init{RESET}             Create a starter packages.txt ({DIM}--from-system{RESET} to seed, {DIM}--force{RESET} to overwrite)\n    \
+    {GREEN}status{RESET}  {DIM}(s){RESET}     Show installed/missing curated packages\n    \
+    {GREEN}list{RESET}    {DIM}(ls){RESET}    List all curated packages\n    \
+    {GREEN}add{RESET}     {DIM}(a){RESET}     Add package(s) to curated list ({DIM}@tag{RESET} args tag them, {DIM}--section <name>{RESET} files them)\n    \
+    {GREEN}remove{RESET}  {DIM}(rm){RESET}    Remove package(s) from curated list ({DIM}--stale{RESET}: every one not installed)\n    \
+    {GREEN}install{RESET} {DIM}(i){RESET}     Install missing curated packages\n    \
+    {GREEN}upgrade{RESET}          Upgrade installed curated packages that have a newer candidate\n    \
+    {GREEN}outdated{RESET}         List curated packages with a newer candidate (exit 1 if any)\n    \
+    {GREEN}uninstall{RESET} {DIM}(un){RESET}  Remove package(s) from the system ({DIM}--forget{RESET} also drops them from the list)\n    \
+    {GREEN}diff{RESET}    {DIM}(d){RESET}     Compare system packages vs curated list\n    \
+    {GREEN}sync{RESET}             Install missing curated packages and remove uncurated manual ones\n    \
+    {GREEN}snap{RESET}             Interactively pick from system packages\n    \
+    {GREEN}import{RESET}           Replace the list with all manual system packages ({DIM}--merge{RESET} to add to it)\n    \
+    {GREEN}why{RESET}     {DIM}(w){RESET}     Show install history for package(s), incl. removals and purges\n    \
+    {GREEN}check{RESET}            Check if one package is curated/installed (exit 0 = both)\n    \
+    {GREEN}mark-auto{RESET}        Mark package(s) as auto-installed in apt (list untouched)\n    \
+    {GREEN}tags{RESET}             List tags; {DIM}tags rename <old> <new>{RESET}, {DIM}tags remove <tag>{RESET}\n    \
+    {GREEN}recommends{RESET}       List uninstalled recommends of curated packages; offer to install/curate\n    \
+    {GREEN}export{RESET}           Print the list for other tools ({DIM}--format=selections|json|dockerfile|ansible{RESET})\n    \
+    {GREEN}env{RESET}              Show resolved settings: packages file and why, config, tools on PATH\n    \
+    {GREEN}fmt{RESET}              Tidy packages.txt: sort entries, trim whitespace, collapse blank lines\n\
+\n\
+{BOLD}OPTIONS:{RESET}\n    \
+    {YELLOW}--dry-run{RESET}        Show what would happen (install, reinstall, import, upgrade, uninstall, sync, mark-auto, recommends)\n    \
+    {YELLOW}--wait[=N]{RESET}       Retry while the dpkg lock is held, up to N seconds (install only, default: 300)\n    \
+    {YELLOW}--jobs <N>{RESET}       Keep N downloads in flight per server, 1-64 (install only)\n    \
+    {YELLOW}--only <pkg...>{RESET}  Install just these curated packages (install only)\n    \
+    {YELLOW}--reinstall{RESET}      Reinstall the installed curated packages instead (install only; with {DIM}--only{RESET})\n    \
+    {YELLOW}--sort-into{RESET}      File new packages alphabetically within their section (add; {DIM}sort_into = true{RESET} in config)\n    \
+    {YELLOW}--incremental{RESET}    Save each accepted package immediately (snap only)\n    \
+    {YELLOW}--from-selections <file>{RESET}  Adopt the uncurated packages listed in <file> without prompting (snap only)\n    \
+    {YELLOW}--against <file>{RESET} Compare against another list file instead of the system (diff only)\n    \
+    {YELLOW}--summary{RESET}        Only print counts (diff only; automatic above 50 packages)\n    \
+    {YELLOW}--full{RESET}           Always list every package (diff only)\n    \
+    {YELLOW}--porcelain{RESET}      Stable `CODE name` lines: I installed, M missing, H held, A auto (status only)\n    \
+    {YELLOW}--watch{RESET}          Refresh until everything is installed (status only)\n    \
+    {YELLOW}--interval=N{RESET}     Seconds between --watch refreshes (default: 2)\n    \
+    {YELLOW}--missing-only{RESET}   Only list missing packages; {DIM}--installed-only{RESET} the inverse (status only)\n    \
+    {YELLOW}--group-by-state{RESET} Installed packages first, then missing ones (status only)\n    \
+    {YELLOW}--group=NAME{RESET}     Only the packages in section NAME, or {DIM}default{RESET} for unsectioned ones (status, list, install)\n    \
+    {YELLOW}--yes, -y{RESET}        Don't ask before installing, or removing more than 5 packages (install, remove, sync)\n    \
+    {YELLOW}--purge{RESET}          Purge instead of remove, config files included (uninstall, sync)\n    \
+    {YELLOW}--forget{RESET}         Also drop uninstalled packages from packages.txt (uninstall only)\n    \
+    {YELLOW}--window=N{RESET}       Minutes before/after install to search history (why only, default: 5)\n    \
+    {YELLOW}--before=N{RESET}       Minutes before install to search, overrides --window (why only, e.g. 10 or 30s)\n    \
+    {YELLOW}--after=N{RESET}        Minutes after install to search, overrides --window (why only, e.g. 1 or 30s)\n    \
+    {YELLOW}--all{RESET}            Show all commands in history window (why only, default: interesting only)\n    \
+    {YELLOW}--no-siblings{RESET}    Hide packages installed in the same transaction (why only)\n    \
+    {YELLOW}--no-same-day{RESET}    Hide packages installed later/earlier that day (why only)\n    \
+    {YELLOW}--csv{RESET}            One CSV row per event: package,date,commandline,requested_by,siblings,pwd (why only)\n    \
+    {YELLOW}--json{RESET}           Install events as a JSON array, with siblings, pwd and nearby commands (why only)\n    \
+    {YELLOW}--limit=N{RESET}        Only the N most recent events per package (why only)\n    \
+    {YELLOW}--since=DATE{RESET}     Only events on or after DATE (YYYY-MM-DD); {DIM}--until=DATE{RESET} on or before (why only)\n    \
+    {YELLOW}--removals-only{RESET}  Only the events that removed or purged the package (why only)\n    \
+    {YELLOW}--oldest-first{RESET}   List events oldest first; {DIM}--newest-first{RESET} is the default (why only)\n    \
+    {YELLOW}--source=SRC{RESET}     Where to read install history: file, journal, or auto (why only)\n    \
+    {YELLOW}--full-cmdline{RESET}   Don't truncate long apt commandlines to the terminal width (why only)\n    \
+    {YELLOW}--timeline{RESET}       Merge apt history and dpkg.log into one dated timeline (why only)\n    \
+    {YELLOW}--open{RESET}           Print a {DIM}cd{RESET} to where the latest install ran (why only)\n    \
+    {YELLOW}--print-dir{RESET}      Print just that directory, for {DIM}cd \"$(apt-sync why <pkg> --print-dir)\"{RESET} (why only)\n    \
+    {YELLOW}--group-siblings{RESET} One siblings list across all installs, most frequent first (why only)\n    \
+    {YELLOW}--format=TMPL{RESET}    One line per event: {{pkg}} {{date}} {{cmdline}} {{user}} {{pwd}} {{siblings}} (why only)\n    \
+    {YELLOW}--by-section{RESET}     Group output under the file's ## headers (list only)\n    \
+    {YELLOW}--json{RESET}           Machine-readable output with installed state (list, status)\n    \
+    {YELLOW}--dpkg-query-arch=native{RESET}  Only count a package installed for the native arch (default: any arch)\n    \
+    {YELLOW}--dpkg-status <file>{RESET}  Read package state from a copied dpkg status file (status, diff, list, check)\n    \
+    {YELLOW}--config <path>{RESET}  Read config from <path> instead of ~/.config/apt-sync/config.toml\n    \
+    {YELLOW}--no-color{RESET}       Plain text output (also when {DIM}NO_COLOR{RESET} is set or output is piped)\n    \
+    {YELLOW}--quiet, -q{RESET}      Suppress progress output and why's window/sources line\n    \
+    {YELLOW}--verbose{RESET}        Report failures of {DIM}$APT_SYNC_WHY_HOOK{RESET} (why only)\n    \
+    {YELLOW}--help, -h{RESET}       Show this help\n    \
+    {YELLOW}--version, -V{RESET}    Print the apt-sync version\n\
+\n\
+{BOLD}CONFIG:{RESET}\n    \
+    Packages file: {DIM}$APT_SYNC_FILE{RESET}, {DIM}file = \"...\"{RESET} in config.toml, or {DIM}~/.config/apt-sync/packages.txt{RESET}\n    \
+    An {DIM}http(s)://{RESET} location is fetched (cached 5 min) and read-only\n",
+    );
+}
+
+// ── Main ────────────────────────────────────────────────────────────
+
+fn main() -> ExitCode {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let no_color = args.iter().any(|a| a == "--no-color");
+    args.retain(|a| a != "--no-color");
+    let tty = io::stdout().is_terminal();
+    let _ = COLOR.set(tty && wants_color(env::var_os("NO_COLOR").as_deref(), no_color));
+    let _ = FANCY.set(tty);
+    let values = ["--config", "--against", "--from-selections", "--section", "--jobs", "--dpkg-status"]
+        .map(|flag| take_flag_value(&mut args, flag));
+    if let Some(Err(e)) = values.iter().find(|v| v.is_err()) {
+        eprintln!("{RED}{e}{RESET}");
+        return ExitCode::FAILURE;
+    }
+    let [config_arg, against, selections, section, jobs, dpkg_status] = values.map(Result::unwrap_or_default);
+    if args.iter().any(|a| a == "--version" || a == "-V") {
+        println!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+        return ExitCode::SUCCESS;
+    }
+    if args.is_empty() || args.iter().any(|a| a == "--help" || a == "-h") {
+        print_help();
+        return ExitCode::SUCCESS;
+    }
+
+    let config = match load_config(config_arg.as_deref().map(Path::new)) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("{RED}Cannot read config {}: {e}{RESET}", config_arg.unwrap_or_default());
+            return ExitCode::FAILURE;
+        }
+    };
+    if let Some(header) = &config.header {
+        let _ = HEADER.set(header.clone());
+    }
+    let pkg_path = pkg_file_path(&config);
+    let cmd = args[0].as_str();
+    let rest = &args[1..];
+    if let Some(status) = dpkg_status {
+        if !reads_dpkg_status(cmd) {
+            eprintln!("{RED}--dpkg-status only works with status, diff, list and check — `{cmd}` acts on this system{RESET}");
+            return ExitCode::FAILURE;
+        }
+        let _ = DPKG_STATUS.set(PathBuf::from(status));
+    }
+    let _ = SORT_INTO.set(config.sort_into || rest.iter().any(|a| a == "--sort-into"));
+
+    // A URL list is fetched into the cache and read from there, never written back
+    let pkg_path = match pkg_path.to_str().filter(|p| is_url(p)) {
+        Some(url) if !matches!(cmd, "why" | "w" | "mark-auto" | "env") => {
+            if modifies_list(cmd, rest) {
+                eprintln!("{RED}{url} is a remote list — `{cmd}` needs a local packages file{RESET}");
+                return ExitCode::FAILURE;
+            }
+            match remote_list(url, &remote_cache_path(url), REMOTE_MAX_AGE, fetch_url) {
+                Ok(path) => path,
+                Err(e) => {
+                    eprintln!("{RED}Cannot fetch {url}: {e}{RESET}");
+                    return ExitCode::FAILURE;
+                }
+            }
+        }
+        _ => pkg_path,
+    };
+    // Released when main returns, i.e. after the command's save. A sudo re-run skips it: its
+    // parent holds the lock while it waits, and writes nothing after
+    let _lock = if modifies_list(cmd, rest) && env::var_os(REEXEC_ENV).is_none() {
+        match lock_list(&pkg_path, LIST_LOCK_WAIT) {
+            Ok(lock) => lock,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                eprintln!(
+                    "{RED}{} is being edited by another apt-sync (waited {}s) — try again once it finishes{RESET}",
+                    pkg_path.display(),
+                    LIST_LOCK_WAIT.as_secs()
+                );
+                return ExitCode::FAILURE;
+            }
+            Err(e) => {
+                eprintln!("{RED}Cannot lock {}: {e}{RESET}", pkg_path.display());
+                return ExitCode::FAILURE;
+            }
+        }
+    } else {
+        None
+    };
+    let dry_run = rest.iter().any(|a| a == "--dry-run");
+    let group = rest.iter().find_map(|a| a.strip_prefix("--group=")).filter(|g| !g.is_empty());
+    if let Some(g) = group
+        && matches!(cmd, "status" | "s" | "list" | "ls" | "install" | "i")
+        && load_group(&pkg_path, group).is_empty()
+    {
+        let list = load_list(&pkg_path);
+        eprintln!("{RED}No packages in group `{g}` (groups: {}){RESET}", group_names(&list).join(", "));
+        return ExitCode::FAILURE;
+    }
+
+    // History window for why: --before/--after override --window on their side
+    let flag_secs = |flag: &str| rest.iter().find_map(|a| parse_duration_secs(a.strip_prefix(flag)?));
+    let window_secs = flag_secs("--window=").unwrap_or(5 * 60);
+    let show_all = rest.iter().any(|a| a == "--all");
+    let quiet = rest.iter().any(|a| a == "--quiet" || a == "-q");
+    let json = rest.iter().any(|a| a == "--json");
+    let arch = || arch_mode(rest.iter().find_map(|a| a.strip_prefix("--dpkg-query-arch=")));
+    let wait_secs = rest.iter().find_map(|a| {
+        if a == "--wait" { Some(300) } else { a.strip_prefix("--wait=")?.parse().ok() }
+    });
+
+    let rest_no_flags: Vec<String> = rest
+        .iter()
+        .filter(|a| !a.starts_with('-'))
+        .cloned()
+        .collect();
+
+    // Read-only commands and dry runs never need root
+    if needs_root(cmd, dry_run, rest_no_flags.is_empty())
+        && let Some(code) = require_root(cmd, &pkg_path)
+    {
+        return code;
+    }
+
+    match cmd {
+        "status" | "s" => {
+            let interval = rest
+                .iter()
+                .find_map(|a| a.strip_prefix("--interval=")?.trim_end_matches('s').parse().ok())
+                .unwrap_or(2);
+            let watch = rest.iter().any(|a| a == "--watch").then(|| Duration::from_secs(interval));
+            let format = if rest.iter().any(|a| a == "--porcelain") {
+                StatusFormat::Porcelain
+            } else if json {
+                StatusFormat::Json
+            } else {
+                StatusFormat::Human
+            };
+            let large_list = config.large_list.unwrap_or(DEFAULT_LARGE_LIST);
+            let view = StatusView {
+                group_by_state: rest.iter().any(|a| a == "--group-by-state"),
+                filter: if rest.iter().any(|a| a == "--missing-only") {
+                    StatusFilter::Missing
+                } else if rest.iter().any(|a| a == "--installed-only") {
+                    StatusFilter::Installed
+                } else {
+                    StatusFilter::All
+                },
+            };
+            cmd_status(&pkg_path, &arch(), watch, large_list, format, view, group);
+        }
+        "list" | "ls" => cmd_list(&pkg_path, json, &arch(), rest.iter().any(|a| a == "--by-section"), group),
+        "remove" | "rm" if rest.iter().any(|a| a == "--stale") => {
+            return cmd_prune(&pkg_path, &arch(), rest.iter().any(|a| a == "--yes" || a == "-y"));
+        }
+        // The exit code describes a single package, so extra names would go unchecked
+        "check" if rest_no_flags.len() != 1 => {
+            eprintln!("{RED}Usage: apt-sync check <pkg>  (one package at a time){RESET}");
+            return ExitCode::FAILURE;
+        }
+        "add" | "a" | "remove" | "rm" | "uninstall" | "un" | "why" | "w" | "mark-auto" if rest_no_flags.is_empty() => {
+            let name = match cmd { "a" => "add", "rm" => "remove", "un" => "uninstall", "w" => "why", c => c };
+            eprintln!("{RED}Usage: apt-sync {name} <pkg...>{RESET}");
+            return ExitCode::FAILURE;
+        }
+        "add" | "a" => return cmd_modify_in(&pkg_path, &rest_no_flags, true, section.as_deref()),
+        "remove" | "rm" => return cmd_remove(&pkg_path, &rest_no_flags, rest.iter().any(|a| a == "--yes" || a == "-y")),
+        "install" | "i" => {
+            // `--only a b` or `--only=a,b`: install just these curated packages
+            let only: Option<Vec<String>> = rest.iter().any(|a| a == "--only" || a.starts_with("--only=")).then(|| {
+                let inline = rest.iter().filter_map(|a| a.strip_prefix("--only="));
+                inline
+                    .chain(rest_no_flags.iter().map(String::as_str))
+                    .flat_map(|a| a.split(','))
+                    .filter(|n| !n.is_empty())
+                    .map(String::from)
+                    .collect()
+            });
+            // `--group=NAME` is `--only` with that section's packages
+            let only = match (only, group) {
+                (only, None) => only,
+                (None, Some(g)) => Some(load_group(&pkg_path, Some(g)).into_keys().collect()),
+                (Some(names), Some(g)) => {
+                    // Uncurated names stay in, so install still reports them
+                    let (members, curated) = (load_group(&pkg_path, Some(g)), load_packages(&pkg_path));
+                    Some(names.into_iter().filter(|n| members.contains_key(n) || !curated.contains(n)).collect())
+                }
+            };
+            let jobs = match jobs.as_deref().map(parse_jobs).transpose() {
+                Ok(jobs) => jobs,
+                Err(e) => {
+                    eprintln!("{RED}{e}{RESET}");
+                    return ExitCode::FAILURE;
+                }
+            };
+            let opts = InstallOpts {
+                dry_run,
+                wait_secs,
+                only,
+                jobs,
+                reinstall: rest.iter().any(|a| a == "--reinstall"),
+                yes: rest.iter().any(|a| a == "--yes" || a == "-y"),
+            };
+            return cmd_install(&pkg_path, &arch(), &opts);
+        }
+        "upgrade" => return cmd_upgrade(&pkg_path, dry_run, &arch()),
+        "outdated" => return cmd_outdated(&pkg_path, &arch()),
+        "uninstall" | "un" => {
+            let (purge, forget) = (rest.iter().any(|a| a == "--purge"), rest.iter().any(|a| a == "--forget"));
+            return cmd_uninstall(&pkg_path, &rest_no_flags, dry_run, purge, forget, &arch());
+        }
+        "sync" => {
+            let yes = rest.iter().any(|a| a == "--yes" || a == "-y");
+            return cmd_sync(&pkg_path, dry_run, rest.iter().any(|a| a == "--purge"), yes, &arch());
+        }
+        "diff" | "d" => {
+            let listing = if rest.iter().any(|a| a == "--full") {
+                DiffListing::Full
+            } else if rest.iter().any(|a| a == "--summary") {
+                DiffListing::Summary
+            } else {
+                DiffListing::Auto
+            };
+            return cmd_diff(&pkg_path, listing, against.as_deref().map(Path::new));
+        }
+        "snap" => {
+            let incremental = rest.iter().any(|a| a == "--incremental");
+            return cmd_snap(&pkg_path, incremental, selections.as_deref().map(Path::new));
+        }
+        "import" => return cmd_import(&pkg_path, rest.iter().any(|a| a == "--merge"), dry_run),
+        "mark-auto" => return cmd_mark_auto(&rest_no_flags, dry_run),
+        "tags" => return cmd_tags(&pkg_path, &rest_no_flags),
+        "fmt" => return cmd_fmt(&pkg_path),
+        "env" => cmd_env(&config, config_arg.as_deref(), window_secs),
+        "export" => {
+            let format = rest.iter().find_map(|a| a.strip_prefix("--format="));
+            let format = if rest.iter().any(|a| a == "--ansible") { Some("ansible") } else { format };
+            return cmd_export(&pkg_path, format);
+        }
+        "recommends" => return cmd_recommends(&pkg_path, dry_run, &arch()),
+        "init" => {
+            let from_system = rest.iter().any(|a| a == "--from-system");
+            return cmd_init(&config, from_system, rest.iter().any(|a| a == "--force"));
+        }
+        "check" => return cmd_check(&pkg_path, &rest_no_flags[0], &arch()),
+        "why" | "w" => {
+            let day_flag = |flag: &str| rest.iter().find_map(|a| a.strip_prefix(flag)).map(String::from);
+            let (since, until) = (day_flag("--since="), day_flag("--until="));
+            if let Some(bad) = [&since, &until].into_iter().flatten().find(|d| !valid_day(d)) {
+                eprintln!("{RED}--since/--until take a date like 2025-08-01, not {bad}{RESET}");
+                return ExitCode::FAILURE;
+            }
+            let source = match rest.iter().find_map(|a| a.strip_prefix("--source=")) {
+                Some("journal") => HistorySource::Journal,
+                Some("file") => HistorySource::File,
+                Some("auto") | None => HistorySource::Auto,
+                Some(bad) => {
+                    eprintln!("{RED}--source takes file, journal or auto, not {bad}{RESET}");
+                    return ExitCode::FAILURE;
+                }
+            };
+            let opts = WhyOpts {
+                before_secs: flag_secs("--before=").unwrap_or(window_secs),
+                after_secs: flag_secs("--after=").unwrap_or(window_secs),
+                show_all,
+                only: env::var("APT_SYNC_INTERESTING_ONLY")
+                    .ok()
+                    .or_else(|| config.interesting_only.clone())
+                    .map_or_else(Vec::new, |v| parse_prefix_list(&v)),
+                siblings: !rest.iter().any(|a| a == "--no-siblings"),
+                group_siblings: rest.iter().any(|a| a == "--group-siblings"),
+                same_day: !rest.iter().any(|a| a == "--no-same-day"),
+                quiet,
+                format: rest.iter().find_map(|a| a.strip_prefix("--format=")).map(String::from),
+                csv: rest.iter().any(|a| a == "--csv"),
+                timeline: rest.iter().any(|a| a == "--timeline"),
+                source,
+                cmdline_width: (!rest.iter().any(|a| a == "--full-cmdline")).then(terminal_width),
+                dir: if rest.iter().any(|a| a == "--print-dir") {
+                    Some(DirOutput::Bare)
+                } else {
+                    rest.iter().any(|a| a == "--open").then_some(DirOutput::Cd)
+                },
+                hook: env::var_os("APT_SYNC_WHY_HOOK").filter(|h| !h.is_empty()).map(PathBuf::from),
+                verbose: rest.iter().any(|a| a == "--verbose"),
+                json,
+                limit: rest.iter().find_map(|a| a.strip_prefix("--limit=")?.parse().ok()).filter(|&n| n > 0),
+                since,
+                until,
+                removals_only: rest.iter().any(|a| a == "--removals-only"),
+                oldest_first: why_oldest_first(rest, config.why_oldest_first),
+            };
+            cmd_why(&rest_no_flags, &opts, &arch());
+        }
+        _ => {
+            eprintln!("{RED}Unknown command: {cmd}{RESET}");
+            print_help();
+            return ExitCode::FAILURE;
+        }
+    }
+    ExitCode::SUCCESS
+}
+
+// ── Tests ───────────────────────────────────────────────────────────
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("apt-sync-test-{name}"));
+            let _ = fs::create_dir_all(path.parent().unwrap());
+            Self(path)
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+            let _ = fs::remove_file(sibling_path(&self.0, ".bak"));
+        }
+    }
+
+    impl std::ops::Deref for TempFile {
+        type Target = Path;
+        fn deref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    fn list_of(names: &[&str]) -> PkgList {
+        names.iter().map(|n| ((*n).to_string(), PkgEntry::default())).collect()
+    }
+
+    fn entry(timestamp: i64, command: &str) -> ShellHistoryEntry {
+        ShellHistoryEntry {
+            timestamp,
+            command: command.to_string(),
+            cwd: None,
+        }
+    }
+
+    #[test]
+    fn explicit_config_path_is_honored() {
+        let tmp = TempFile::new("config.toml");
+        fs::write(&*tmp, "# test config\nfile = \"lists/work.txt\"\n").unwrap();
+        let config = load_config(Some(&tmp)).unwrap();
+        assert_eq!(config.file, Some(tmp.parent().unwrap().join("lists/work.txt")));
+    }
+
+    #[test]
+    fn explicit_config_must_exist() {
+        assert!(load_config(Some(Path::new("/nonexistent/apt-sync.toml"))).is_err());
+    }
+
+    #[test]
+    fn parse_config_absolute_and_unknown_keys() {
+        let config = parse_config("[general]\nfile = \"/srv/pkgs.txt\"\nbogus = 1\n", Path::new("/etc"));
+        assert_eq!(config.file, Some(PathBuf::from("/srv/pkgs.txt")));
+    }
+
+    #[test]
+    fn parse_config_header() {
+        let config = parse_config("header = \"managed by apt-sync\\ndo not hand-edit on prod\"\n", Path::new("/etc"));
+        assert_eq!(config.header.as_deref(), Some("managed by apt-sync\\ndo not hand-edit on prod"));
+        assert_eq!(header_lines(config.header.as_deref().unwrap()), ["# managed by apt-sync", "# do not hand-edit on prod"]);
+        assert_eq!(header_lines("# already a comment\n\nplain"), ["# already a comment", "#", "# plain"]);
+    }
+
+    #[test]
+    fn custom_header_is_not_duplicated() {
+        let tmp = TempFile::new("header.txt");
+        let header = "managed by apt-sync — edit via `apt-sync add`";
+        save_packages_with_header(&tmp, &list_of(&["git"]), header).unwrap();
+        let reloaded = load_list(&tmp);
+        save_packages_with_header(&tmp, &reloaded, header).unwrap();
+        let contents = fs::read_to_string(&*tmp).unwrap();
+        assert_eq!(contents, "# managed by apt-sync — edit via `apt-sync add`\ngit\n");
+    }
+
+    #[test]
+    fn parse_config_large_list() {
+        assert_eq!(parse_config("large_list = 250\n", Path::new("/etc")).large_list, Some(250));
+        assert_eq!(parse_config("large_list = lots\n", Path::new("/etc")).large_list, None);
+    }
+
+    #[test]
+    fn parse_config_why_order() {
+        assert!(parse_config("why_order = \"oldest-first\"\n", Path::new("/etc")).why_oldest_first);
+        assert!(!parse_config("why_order = \"newest-first\"\n", Path::new("/etc")).why_oldest_first);
+        assert!(!parse_config("why_order = \"sideways\"\n", Path::new("/etc")).why_oldest_first);
+    }
+
+    #[test]
+    fn parse_config_keeps_urls() {
+        let config = parse_config("file = \"https://example.com/pkgs.txt\"\n", Path::new("/etc"));
+        assert_eq!(config.file, Some(PathBuf::from("https://example.com/pkgs.txt")));
+    }
+
+    #[test]
+    fn remote_list_fetches_caches_and_falls_back() {
+        let cache = TempFile::new("remote-cache.txt");
+        let _ = fs::remove_file(&*cache);
+        let url = "https://example.com/pkgs.txt";
+        let missing = |_: &str| Err(io::Error::other("connection refused"));
+
+        // Nothing cached and the fetch fails: a clear error
+        assert!(remote_list(url, &cache, REMOTE_MAX_AGE, missing).is_err());
+
+        let path = remote_list(url, &cache, REMOTE_MAX_AGE, |u| {
+            assert_eq!(u, url);
+            Ok("curl\ngit @dev\n".to_string())
+        })
+        .unwrap();
+        assert_eq!(load_packages(&path), BTreeSet::from(["curl".to_string(), "git".to_string()]));
+
+        // Fresh cache is reused without fetching
+        remote_list(url, &cache, REMOTE_MAX_AGE, |_| panic!("should not refetch")).unwrap();
+        // Stale cache survives a failed refetch
+        let path = remote_list(url, &cache, Duration::ZERO, missing).unwrap();
+        assert_eq!(load_packages(&path).len(), 2);
+    }
+
+    #[test]
+    fn remote_lists_refuse_writes() {
+        assert!(modifies_list("add", &[]));
+        assert!(modifies_list("tags", &["rename".to_string()]));
+        assert!(!modifies_list("tags", &[]));
+        assert!(!modifies_list("install", &[]));
+        assert!(!modifies_list("tags", &["--json".to_string()]));
+        assert!(modifies_list("uninstall", &["jq".to_string(), "--forget".to_string()]));
+        assert!(!modifies_list("un", &["jq".to_string()]));
+        // Accepted recommends are appended to the list
+        assert!(modifies_list("recommends", &[]));
+        assert!(!modifies_list("recommends", &["--dry-run".to_string()]));
+    }
+
+    #[test]
+    fn env_reports_overridden_file() {
+        let config = Config { file: Some(PathBuf::from("/etc/apt-sync/pkgs.txt")), ..Config::default() };
+        let pkg = resolve_pkg_file(Some("/srv/team/packages.txt".into()), &config);
+        assert_eq!(pkg, (PathBuf::from("/srv/team/packages.txt"), "$APT_SYNC_FILE"));
+        let tools = [("journalctl", true), ("curl", false)];
+        let lines = env_lines(&pkg, Path::new("/nonexistent/config.toml"), None, (false, 10 * 60), &tools);
+        assert_eq!(lines[0], "packages file: /srv/team/packages.txt (from $APT_SYNC_FILE)");
+        assert!(lines.contains(&"config file: /nonexistent/config.toml (not present)".to_string()));
+        assert!(lines.contains(&"shell history: none found".to_string()));
+        assert!(lines.contains(&"journalctl: found".to_string()));
+        assert!(lines.contains(&"curl: missing".to_string()));
+        assert!(lines.contains(&"color: off".to_string()));
+        assert!(lines.contains(&"why window: 10m before/after".to_string()));
+        assert_eq!(resolve_pkg_file(None, &config).1, "`file` in config.toml");
+    }
+
+    #[test]
+    fn no_color_env_and_flag() {
+        use std::ffi::OsStr;
+        assert!(wants_color(None, false));
+        assert!(!wants_color(None, true));
+        assert!(!wants_color(Some(OsStr::new("1")), false));
+        // An empty NO_COLOR doesn't count
+        assert!(wants_color(Some(OsStr::new("")), false));
+    }
+
+    #[test]
+    fn glyphs_fall_back_to_ascii() {
+        assert_eq!(ICON_OK.pick(true), "✔ ");
+        assert_eq!(ICON_OK.pick(false), "[ok] ");
+        assert_eq!(ICON_NO.pick(false), "[--] ");
+        for glyph in [ICON_OK, ICON_NO, ICON_ADD, ICON_DEL, ICON_WARN, ICON_ERROR, ICON_PACKAGE, ICON_DRY_RUN, ICON_DATE] {
+            assert!(glyph.pick(false).is_ascii());
+        }
+    }
+
+    #[test]
+    fn root_gate_covers_every_apt_call() {
+        for cmd in ["install", "i", "sync", "upgrade", "uninstall", "un", "mark-auto"] {
+            assert!(needs_root(cmd, false, false), "{cmd}");
+            assert!(!needs_root(cmd, true, false), "{cmd} --dry-run");
+        }
+        assert!(!needs_root("mark-auto", false, true));
+        assert!(!needs_root("uninstall", false, true));
+        // recommends only asks once an install is picked
+        for cmd in ["status", "why", "add", "outdated", "recommends"] {
+            assert!(!needs_root(cmd, false, false), "{cmd}");
+        }
+    }
+
+    #[test]
+    fn dpkg_status_is_for_read_only_commands() {
+        for cmd in ["status", "s", "diff", "d", "list", "ls", "check"] {
+            assert!(reads_dpkg_status(cmd), "{cmd}");
+        }
+        for cmd in ["install", "i", "sync", "uninstall", "un", "upgrade", "recommends", "mark-auto", "snap", "import"] {
+            assert!(!reads_dpkg_status(cmd), "{cmd}");
+        }
+    }
+
+    #[test]
+    fn take_flag_value_forms() {
+        let mut args: Vec<String> = ["--config", "a.toml", "status"].map(String::from).to_vec();
+        assert_eq!(take_flag_value(&mut args, "--config").unwrap().as_deref(), Some("a.toml"));
+        assert_eq!(args, ["status"]);
+
+        let mut args: Vec<String> = ["list", "--config=b.toml"].map(String::from).to_vec();
+        assert_eq!(take_flag_value(&mut args, "--config").unwrap().as_deref(), Some("b.toml"));
+        assert_eq!(args, ["list"]);
+
+        let mut args: Vec<String> = ["status"].map(String::from).to_vec();
+        assert_eq!(take_flag_value(&mut args, "--config"), Ok(None));
+
+        // A trailing flag with nothing after it isn't silently dropped
+        let mut args: Vec<String> = ["status", "--config"].map(String::from).to_vec();
+        assert_eq!(take_flag_value(&mut args, "--config"), Err("`--config` needs a value".to_string()));
+    }
+
+    #[test]
+    fn pasted_multiline_arg_expands_to_packages() {
+        let args = ["curl\ngit  # vcs\n\nneovim/bookworm-backports\n".to_string(), "jq".to_string(), "@cli".to_string()];
+        assert_eq!(expand_pasted_args(&args), ["curl", "git", "neovim/bookworm-backports", "jq", "@cli"]);
+    }
+
+    #[test]
+    fn snap_selections_only_adopt_uncurated() {
+        let (git, jq) = ("git".to_string(), "jq".to_string());
+        let uncurated = [&git, &jq];
+        let selected = BTreeSet::from(["jq".to_string(), "curl".to_string(), "git".to_string(), "vim".to_string()]);
+        let (accepted, rejected) = partition_selections(&selected, &uncurated);
+        assert_eq!(accepted, ["git", "jq"]);
+        assert_eq!(rejected, ["curl", "vim"]);
+    }
+
+    #[test]
+    fn format_packages_text_tidies() {
+        let before = "\n\n# dev tools   \nripgrep @cli\ngit  \n\n\n\n# shells\nzsh\nbash # login shell\n\n\n";
+        let after = "# dev tools\ngit\nripgrep @cli\n\n# shells\nbash # login shell\nzsh\n";
+        assert_eq!(format_packages_text(before), after);
+        // Idempotent, and the package set is unchanged
+        assert_eq!(format_packages_text(after), after);
+        assert_eq!(parse_list(before), parse_list(after));
+        assert_eq!(format_packages_text("\n\n"), "");
+    }
+
+    #[test]
+    fn fmt_keeps_a_backup() {
+        let tmp = TempFile::new("fmt.txt");
+        fs::write(&*tmp, "zsh\ngit  \n").unwrap();
+        assert_eq!(cmd_fmt(&tmp), ExitCode::SUCCESS);
+        assert_eq!(fs::read_to_string(&*tmp).unwrap(), "git\nzsh\n");
+        assert_eq!(fs::read_to_string(sibling_path(&tmp, ".bak")).unwrap(), "zsh\ngit  \n");
+    }
+
+    #[test]
+    fn parse_skips_flag_like_lines() {
+        let pkgs = parse_packages("curl\n--dry-run\n-\n-y git\nzsh\n");
+        assert_eq!(pkgs, BTreeSet::from(["curl".to_string(), "zsh".to_string()]));
+    }
+
+    #[test]
+    fn parse_empty() {
+        assert!(parse_packages("").is_empty());
+    }
+
+    #[test]
+    fn parse_with_comments_and_blanks() {
+        let input = "# comment\n\nzsh\ngit\n# another\ncurl\n";
+        let pkgs = parse_packages(input);
+        assert_eq!(pkgs.len(), 3);
+        assert!(pkgs.contains("zsh"));
+        assert!(pkgs.contains("git"));
+        assert!(pkgs.contains("curl"));
+    }
+
+    #[test]
+    fn parse_trims_whitespace() {
+        let input = "  zsh  \n  git  \n";
+        let pkgs = parse_packages(input);
+        assert!(pkgs.contains("zsh"));
+        assert!(pkgs.contains("git"));
+    }
+
+    #[test]
+    fn parse_deduplicates() {
+        let input = "zsh\nzsh\ngit\n";
+        let pkgs = parse_packages(input);
+        assert_eq!(pkgs.len(), 2);
+    }
+
+    #[test]
+    fn parse_strips_trailing_punctuation() {
+        let pkgs = parse_packages("git,\nzsh;\ncurl.\njq;,\n");
+        assert_eq!(pkgs, BTreeSet::from(["curl".into(), "git".into(), "jq".into(), "zsh".into()]));
+    }
+
+    #[test]
+    fn parse_keeps_valid_name_chars() {
+        let pkgs = parse_packages("libstdc++6\npython3.12\ng++\n");
+        assert!(pkgs.contains("libstdc++6"));
+        assert!(pkgs.contains("python3.12"));
+        assert!(pkgs.contains("g++"));
+    }
+
+    #[test]
+    fn parse_skips_punctuation_only_lines() {
+        assert!(parse_packages(";\n,.\n").is_empty());
+    }
+
+    #[test]
+    fn parse_strips_mixed_trailing_punctuation_and_spaces() {
+        assert_eq!(clean_pkg_name("git. ,"), "git");
+        assert_eq!(parse_packages("zsh ; .\n"), BTreeSet::from(["zsh".into()]));
+    }
+
+    // Tiny xorshift so the fuzz test stays deterministic and dependency-free
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn pick<'a>(&mut self, items: &[&'a str]) -> &'a str {
+            items[(self.next() % items.len() as u64) as usize]
+        }
+    }
+
+    fn random_package_file(rng: &mut Rng) -> String {
+        const PIECES: &[&str] = &[
+            "git", "zsh", "libstdc++6", "python3.12", "g++", "-", ".", ",", ";", " ", "\t", "#", "x", "0", "@", "@dev",
+            "/", "/bookworm-backports",
+        ];
+        let mut out = String::new();
+        for _ in 0..rng.next() % 12 {
+            for _ in 0..rng.next() % 5 {
+                out.push_str(rng.pick(PIECES));
+            }
+            out.push_str(rng.pick(&["\n", "\r\n"]));
+        }
+        out
+    }
+
+    #[test]
+    fn fuzz_load_save_load_roundtrip() {
+        let tmp = TempFile::new("fuzz.txt");
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+        for _ in 0..500 {
+            let contents = random_package_file(&mut rng);
+            fs::write(&*tmp, &contents).unwrap();
+            let first = load_list(&tmp);
+            save_packages(&tmp, &first).unwrap();
+            let second = load_list(&tmp);
+            assert_eq!(first, second, "round-trip changed the model for {contents:?}");
+        }
+    }
+
+    #[test]
+    fn init_creates_parseable_file() {
+        let dir = std::env::temp_dir().join("apt-sync-test-init");
+        let _ = fs::remove_dir_all(&dir);
+        let path = dir.join("nested").join("packages.txt");
+        init_packages_file(&path, &BTreeSet::from(["git".to_string(), "zsh".to_string()]), false).unwrap();
+        let raw = fs::read_to_string(&path).unwrap();
+        assert!(raw.starts_with("# apt-sync curated packages\n"));
+        assert!(raw.contains("# examples:"));
+        assert_eq!(load_packages(&path), BTreeSet::from(["git".into(), "zsh".into()]));
+
+        // Refuses to overwrite without force
+        let err = init_packages_file(&path, &BTreeSet::new(), false).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        init_packages_file(&path, &BTreeSet::new(), true).unwrap();
+        assert!(load_packages(&path).is_empty());
+        // --force keeps what it replaced
+        assert_eq!(load_packages(&sibling_path(&path, ".bak")), BTreeSet::from(["git".into(), "zsh".into()]));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn snap_incremental_interrupted_keeps_accepted() {
+        let tmp = TempFile::new("snap-incremental.txt");
+        save_packages(&tmp, &PkgList::new()).unwrap();
+        let uncurated = ["curl", "git", "htop", "jq", "zsh"].map(String::from);
+        let uncurated: Vec<&String> = uncurated.iter().collect();
+        // stdin closes after the third answer
+        let input = io::Cursor::new("y\nn\ny\n");
+        let end = snap_review(input, &uncurated, |pkg| add_one(&tmp, pkg)).unwrap();
+        assert_eq!(end, SnapEnd::Interrupted);
+        assert_eq!(load_packages(&tmp), BTreeSet::from(["curl".into(), "htop".into()]));
+    }
+
+    #[test]
+    fn snap_review_stops_when_a_save_fails() {
+        let uncurated = ["curl", "git", "htop"].map(String::from);
+        let uncurated: Vec<&String> = uncurated.iter().collect();
+        let mut asked = 0;
+        let end = snap_review(io::Cursor::new("y\ny\ny\n"), &uncurated, |_| {
+            asked += 1;
+            Err(io::Error::from(io::ErrorKind::PermissionDenied))
+        });
+        assert!(end.is_err());
+        assert_eq!(asked, 1);
+    }
+
+    #[test]
+    fn snap_review_quit_stops_early() {
+        let uncurated = ["curl", "git"].map(String::from);
+        let uncurated: Vec<&String> = uncurated.iter().collect();
+        let mut accepted = Vec::new();
+        let end = snap_review(io::Cursor::new("y\nq\n"), &uncurated, |p| {
+            accepted.push(p.to_string());
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(end, SnapEnd::Quit);
+        assert_eq!(accepted, vec!["curl"]);
+    }
+
+    #[test]
+    fn roundtrip_save_load() {
+        let tmp = TempFile::new("roundtrip.txt");
+        let pkgs = list_of(&["curl", "git", "zsh"]);
+        save_packages(&tmp, &pkgs).unwrap();
+        let loaded = load_list(&tmp);
+        assert_eq!(pkgs, loaded);
+    }
+
+    #[test]
+    fn diff_logic() {
+        let curated = BTreeSet::from(["git".into(), "curl".into(), "zsh".into()]);
+        let system = BTreeSet::from(["git".into(), "vim".into()]);
+        let on_system_only: Vec<&String> = system.difference(&curated).collect();
+        let in_list_only: Vec<&String> = curated.difference(&system).collect();
+        assert_eq!(on_system_only, vec![&"vim".to_string()]);
+        assert!(in_list_only.contains(&&"curl".to_string()));
+        assert!(in_list_only.contains(&&"zsh".to_string()));
+    }
+
+    #[test]
+    fn parse_recommends_groups_by_package() {
+        let output = "\
+curl
+  Recommends: ca-certificates
+git
+  Recommends: patch
+  Recommends: less
+ |Recommends: ssh-client
+  Recommends: openssh-client
+  Recommends: <mail-transport-agent>
+  Recommends: less
+vim
+";
+        let recs = parse_recommends(output);
+        assert_eq!(recs["curl"], ["ca-certificates"]);
+        assert_eq!(recs["git"], ["patch", "less", "openssh-client"]);
+        assert!(!recs.contains_key("vim"));
+    }
+
+    #[test]
+    fn diff_against_another_list() {
+        let mine = TempFile::new("diff-mine.txt");
+        let theirs = TempFile::new("diff-theirs.txt");
+        fs::write(&*mine, "curl\ngit @dev\nzsh\n").unwrap();
+        fs::write(&*theirs, "curl\nfish\ngit/bookworm-backports\n").unwrap();
+        let (a, b) = (load_packages(&mine), load_packages(&theirs));
+        let (mine_only, theirs_only) = compute_diff(&a, &b);
+        assert_eq!(mine_only, ["zsh"]);
+        assert_eq!(theirs_only, ["fish"]);
+
+        let out = diff_lines(&mine_only, &theirs_only, [(ICON_FILE, "Only in mine"), (ICON_FILE, "Only in theirs")], DiffListing::Auto).0.join("\n");
+        assert!(out.contains("Only in mine"));
+        assert!(out.contains("Only in theirs"));
+        assert!(!out.contains("On system"));
+    }
+
+    #[test]
+    fn diff_hints_match_drift() {
+        let uncurated = diff_hints(true, false, false);
+        assert_eq!(uncurated.len(), 1);
+        assert!(uncurated[0].contains("apt-sync snap"));
+
+        let missing = diff_hints(false, true, false);
+        assert_eq!(missing.len(), 1);
+        assert!(missing[0].contains("apt-sync install"));
+
+        assert_eq!(diff_hints(true, true, false).len(), 2);
+        assert!(diff_hints(false, true, true).last().unwrap().contains("--full"));
+    }
+
+    #[test]
+    fn diff_summarizes_above_threshold() {
+        let many: Vec<String> = (0..=DIFF_SUMMARY_THRESHOLD).map(|i| format!("lib{i}")).collect();
+        let many: Vec<&String> = many.iter().collect();
+        let curated_only = ["zsh".to_string()];
+        let curated_only: Vec<&String> = curated_only.iter().collect();
+        let (lines, summarized) = diff_lines(&many, &curated_only, SYSTEM_DIFF_HEADERS, DiffListing::Auto);
+        let out = lines.join("\n");
+        assert!(summarized);
+        assert!(out.contains(&format!("{} packages, summarized", many.len())));
+        assert!(!out.contains("? lib0"));
+        // The short section is still listed
+        assert!(out.contains("✘ zsh"));
+    }
+
+    #[test]
+    fn diff_full_and_summary_override_threshold() {
+        let few = ["vim".to_string()];
+        let few: Vec<&String> = few.iter().collect();
+        let many: Vec<String> = (0..=DIFF_SUMMARY_THRESHOLD).map(|i| format!("lib{i}")).collect();
+        let many: Vec<&String> = many.iter().collect();
+        assert!(diff_lines(&many, &[], SYSTEM_DIFF_HEADERS, DiffListing::Full).0.join("\n").contains("? lib0"));
+        assert!(!diff_lines(&few, &[], SYSTEM_DIFF_HEADERS, DiffListing::Summary).0.join("\n").contains("? vim"));
+        assert!(diff_lines(&few, &[], SYSTEM_DIFF_HEADERS, DiffListing::Auto).0.join("\n").contains("? vim"));
+    }
+
+    #[test]
+    fn load_nonexistent_file() {
+        let path = Path::new("/tmp/apt-sync-nonexistent-test.txt");
+        assert!(load_packages(path).is_empty());
+    }
+
+    #[test]
+    fn parse_inline_comments() {
+        let list = parse_list("jq # json processing for scripts\nripgrep @cli  # fast grep\nbat#no space\n");
+        assert_eq!(list.keys().collect::<Vec<_>>(), ["bat", "jq", "ripgrep"]);
+        assert_eq!(list["ripgrep"].tags, BTreeSet::from(["cli".to_string()]));
+        // The comment survives a save that changes the entry
+        let original = "jq # json processing for scripts\n";
+        let mut list = parse_list(original);
+        list.get_mut("jq").unwrap().tags.insert("cli".into());
+        assert_eq!(render_packages(original, &list, "", false), "jq @cli  # json processing for scripts\n");
+    }
+
+    #[test]
+    fn bracket_comment_headers_open_groups() {
+        let list = parse_list("curl\n# [dev]\ncargo\n# [desktop]\nfirefox\n# [not a header] really\nvlc\n");
+        assert_eq!(list["curl"].section, None);
+        assert_eq!(list["cargo"].section.as_deref(), Some("dev"));
+        assert_eq!(list["vlc"].section.as_deref(), Some("desktop"));
+        assert_eq!(group_names(&list), ["default", "desktop", "dev"]);
+        let dev: Vec<&String> = list.iter().filter(|(_, e)| in_group(e, "dev")).map(|(p, _)| p).collect();
+        assert_eq!(dev, ["cargo"]);
+        assert!(in_group(&list["curl"], DEFAULT_GROUP));
+    }
+
+    #[test]
+    fn parse_only_comments() {
+        let input = "# just a comment\n# another comment\n";
+        assert!(parse_packages(input).is_empty());
+    }
+
+    #[test]
+    fn save_preserves_header() {
+        let tmp = TempFile::new("header.txt");
+        save_packages(&tmp, &list_of(&["git"])).unwrap();
+        let raw = fs::read_to_string(&*tmp).unwrap();
+        assert!(raw.starts_with("# apt-sync curated packages\n"));
+        assert!(raw.contains("# one package per line"));
+    }
+
+    #[test]
+    fn save_keeps_comments_blank_lines_and_order() {
+        let original = "\
+# apt-sync curated packages
+# one package per line, comments start with #
+# work laptop
+zsh
+git  # needed by dotfiles
+
+# cli toys
+ripgrep @cli
+bat
+";
+        let mut list = parse_list(original);
+        list.remove("bat");
+        list.get_mut("git").unwrap().tags.insert("vcs".into());
+        list.insert("fd-find".into(), PkgEntry::default());
+        let saved = render_packages(original, &list, DEFAULT_HEADER, false);
+        // An unsorted block gets new entries at its end
+        assert_eq!(
+            saved,
+            "\
+# apt-sync curated packages
+# one package per line, comments start with #
+# work laptop
+zsh
+git @vcs  # needed by dotfiles
+
+# cli toys
+ripgrep @cli
+fd-find
+"
+        );
+        assert_eq!(parse_list(&saved), list);
+        // Saving again with nothing changed is a no-op, header included
+        assert_eq!(render_packages(&saved, &list, DEFAULT_HEADER, false), saved);
+    }
+
+    #[test]
+    fn save_inserts_into_sorted_block_in_order() {
+        let original = "# mine\nbat\ncurl\nzsh\n";
+        let mut list = parse_list(original);
+        list.insert("jq".into(), PkgEntry::default());
+        assert_eq!(render_packages(original, &list, "", false), "# mine\nbat\ncurl\njq\nzsh\n");
+    }
+
+    #[test]
+    fn sort_into_places_new_package_alphabetically() {
+        let original = "# tools\nzsh\nbat\nfd-find\n\n## Desktop\nvlc\nfirefox\n";
+        let mut list = parse_list(original);
+        list.insert("curl".into(), PkgEntry::default());
+        // Without it the unsorted block just grows at its end
+        assert!(render_packages(original, &list, "", false).starts_with("# tools\nzsh\nbat\nfd-find\ncurl\n"));
+        assert_eq!(
+            render_packages(original, &list, "", true),
+            "# tools\ncurl\nzsh\nbat\nfd-find\n\n## Desktop\nvlc\nfirefox\n"
+        );
+    }
+
+    #[test]
+    fn add_to_section_lands_in_its_block() {
+        let tmp = TempFile::new("sections.txt");
+        fs::write(&*tmp, "curl\n\n## Shells\nzsh\n").unwrap();
+        cmd_modify_in(&tmp, &["ripgrep".into(), "@cli".into()], true, Some("Development"));
+        cmd_modify_in(&tmp, &["fish".into()], true, Some("Shells"));
+
+        let raw = fs::read_to_string(&*tmp).unwrap();
+        let body: Vec<&str> = raw.lines().filter(|l| !l.starts_with("# ")).collect();
+        assert_eq!(body, ["curl", "", "## Development", "ripgrep @cli", "", "## Shells", "fish", "zsh"]);
+
+        let list = load_list(&tmp);
+        assert_eq!(list["ripgrep"].section.as_deref(), Some("Development"));
+        assert_eq!(list["curl"].section, None);
+        save_packages(&tmp, &list).unwrap();
+        assert_eq!(load_list(&tmp), list);
+
+        // Re-adding under another section moves it
+        cmd_modify_in(&tmp, &["zsh".into()], true, Some("Development"));
+        assert_eq!(load_list(&tmp)["zsh"].section.as_deref(), Some("Development"));
+        let lines = section_list_lines(&load_list(&tmp));
+        assert_eq!(lines[..2], [format!("{BOLD}## (no section){RESET}"), "curl".to_string()]);
+    }
+
+    #[test]
+    fn add_remove_roundtrip() {
+        let tmp = TempFile::new("addrem.txt");
+        save_packages(&tmp, &PkgList::new()).unwrap();
+
+        cmd_modify(&tmp, &["curl".into(), "git".into(), "zsh".into()], true);
+        let pkgs = load_packages(&tmp);
+        assert_eq!(pkgs.len(), 3);
+
+        cmd_modify(&tmp, &["git".into()], false);
+        let pkgs = load_packages(&tmp);
+        assert_eq!(pkgs.len(), 2);
+        assert!(!pkgs.contains("git"));
+    }
+
+    #[test]
+    fn parse_installed_output() {
+        let output = "curl\tamd64\tinstall ok installed\n\
+                      git\tamd64\tdeinstall ok config-files\n\
+                      zsh\tamd64\tinstall ok installed\n";
+        let set = parse_installed(output, &ArchMode::Any);
+        assert_eq!(set.len(), 4);
+        assert!(set.contains("curl"));
+        assert!(set.contains("curl:amd64"));
+        assert!(set.contains("zsh"));
+        assert!(!set.contains("git"));
+    }
+
+    #[test]
+    fn parse_installed_empty() {
+        assert!(parse_installed("", &ArchMode::Any).is_empty());
+    }
+
+    #[test]
+    fn parse_installed_malformed() {
+        let output = "no-tab-here\n\tamd64\tinstall ok installed\ncurl\tinstall ok installed\n";
+        assert!(parse_installed(output, &ArchMode::Any).is_empty());
+    }
+
+    #[test]
+    fn parse_installed_i386_only_matches_unqualified_in_any_mode() {
+        let output = "libfoo\ti386\tinstall ok installed\n";
+        let set = parse_installed(output, &ArchMode::Any);
+        assert!(set.contains("libfoo"));
+        assert!(set.contains("libfoo:i386"));
+    }
+
+    #[test]
+    fn default_arch_mode_matches_foreign_only_installs() {
+        assert_eq!(arch_mode(None), ArchMode::Any);
+        assert_eq!(arch_mode(Some("all")), ArchMode::Any);
+        let set = parse_installed("libfoo\ti386\tinstall ok installed\n", &arch_mode(None));
+        assert!(set.contains("libfoo"));
+    }
+
+    #[test]
+    fn parse_installed_native_mode_requires_native_arch() {
+        let native = ArchMode::Native("amd64".into());
+        let set = parse_installed("libfoo\ti386\tinstall ok installed\n", &native);
+        assert!(!set.contains("libfoo"));
+        assert!(set.contains("libfoo:i386"));
+
+        let set = parse_installed("tzdata\tall\tinstall ok installed\ncurl\tamd64\tinstall ok installed\n", &native);
+        assert!(set.contains("tzdata"));
+        assert!(set.contains("curl"));
+    }
+
+    #[test]
+    fn parse_installed_versions_output() {
+        let output = "curl\tamd64\tinstall ok installed\t8.5.0-2ubuntu10\n\
+                      git\tamd64\tdeinstall ok config-files\t1:2.43.0-1\n\
+                      broken-line\n";
+        let versions = parse_installed_versions(output, &ArchMode::Native("amd64".into()));
+        assert_eq!(versions.len(), 2);
+        assert_eq!(versions.get("curl").map(String::as_str), Some("8.5.0-2ubuntu10"));
+    }
+
+    #[test]
+    fn dpkg_status_file_answers_queries() {
+        let status = "\
+Package: curl
+Status: install ok installed
+Priority: optional
+Architecture: amd64
+Version: 8.5.0-2ubuntu10
+Description: command line tool for transferring data with URL syntax
+ curl is a command line tool for transferring data with URL syntax.
+ Description lines: like this one are continuation lines
+
+Package: libcurl4
+Status: install ok installed
+Architecture: amd64
+Version: 8.5.0-2ubuntu10
+
+Package: git
+Status: deinstall ok config-files
+Architecture: amd64
+Version: 1:2.43.0-1
+";
+        let versions = parse_installed_versions(&status_query_rows(status), &ArchMode::Native("amd64".into()));
+        assert_eq!(versions.get("curl").map(String::as_str), Some("8.5.0-2ubuntu10"));
+        assert!(versions.contains_key("curl:amd64"));
+        assert!(!versions.contains_key("git"));
+        assert!(!versions.contains_key("Description lines"));
+
+        let all = BTreeSet::from(["curl".to_string(), "libcurl4".to_string()]);
+        assert_eq!(status_manual_packages(status, None), all);
+        let extended = "Package: libcurl4\nArchitecture: amd64\nAuto-Installed: 1\n";
+        assert_eq!(status_manual_packages(status, Some(extended)), BTreeSet::from(["curl".to_string()]));
+    }
+
+    #[test]
+    fn status_file_fast_path_matches_dpkg_query() {
+        let status = "\
+Package: curl
+Status: install ok installed
+Architecture: amd64
+Version: 8.5.0-2
+
+Package: libfoo
+Status: install ok installed
+Architecture: i386
+Version: 1.0
+
+Package: oldpkg
+Status: deinstall ok config-files
+Architecture: amd64
+Version: 2.1
+";
+        // Answers the way dpkg-query -W would on a system with that status file
+        let stub = TempFile::new("dpkg-query-stub");
+        fs::write(
+            &stub.0,
+            "for arg in \"$@\"; do
+  case \"$arg\" in
+    curl) printf 'curl\\tamd64\\tinstall ok installed\\t8.5.0-2\\n' ;;
+    libfoo) printf 'libfoo\\ti386\\tinstall ok installed\\t1.0\\n' ;;
+    oldpkg) printf 'oldpkg\\tamd64\\tdeinstall ok config-files\\t2.1\\n' ;;
+  esac
+done
+",
+        )
+        .unwrap();
+        let pkgs: BTreeSet<String> = ["curl", "libfoo", "oldpkg", "not-a-real-package"].map(String::from).into();
+        let queried = run_dpkg_query_with(
+            || {
+                let mut sh = Command::new("sh");
+                sh.arg(&stub.0);
+                sh
+            },
+            &pkgs,
+        );
+        for arch in [ArchMode::Any, ArchMode::Native("amd64".into())] {
+            assert_eq!(parse_installed_versions(&queried, &arch), parse_installed_versions(&status_query_rows(status), &arch));
+        }
+        let installed: BTreeSet<String> = parse_installed(&queried, &ArchMode::Any).intersection(&pkgs).cloned().collect();
+        assert_eq!(installed, ["curl", "libfoo"].map(String::from).into());
+    }
+
+    #[test]
+    fn ansible_export_lists_packages() {
+        let mut list = list_of(&["curl", "g++", "neovim"]);
+        list.get_mut("neovim").unwrap().release = Some("bookworm-backports".into());
+        assert_eq!(
+            ansible_tasks(&list),
+            "\
+- name: Install curated packages
+  ansible.builtin.apt:
+    name:
+      - \"curl\"
+      - \"g++\"
+    state: present
+- name: Install curated packages from bookworm-backports
+  ansible.builtin.apt:
+    name:
+      - \"neovim\"
+    state: present
+    default_release: \"bookworm-backports\"
+"
+        );
+        assert_eq!(ansible_tasks(&PkgList::new()), "");
+    }
+
+    #[test]
+    fn dockerfile_export_one_package_per_line() {
+        let list = parse_list("git\ncurl=8.5*\n");
+        assert_eq!(
+            dockerfile_run(&list),
+            "RUN apt-get update && apt-get install -y \\\n    curl=8.5* \\\n    git \\\n && rm -rf /var/lib/apt/lists/*\n"
+        );
+        assert_eq!(dockerfile_run(&PkgList::new()), "");
+    }
+
+    #[test]
+    fn selections_and_json_exports() {
+        let list = parse_list("curl/bookworm-backports @net\n## Dev\njq=1.7*\n");
+        assert_eq!(dpkg_selections(&list), "curl\tinstall\njq\tinstall\n");
+        assert_eq!(
+            export_json(&list),
+            "[\n  {\"name\": \"curl\", \"release\": \"bookworm-backports\", \"tags\": [\"net\"]},\n  \
+             {\"name\": \"jq\", \"version\": \"1.7*\", \"section\": \"Dev\", \"tags\": []}\n]"
+        );
+        assert_eq!(export_json(&PkgList::new()), "[]");
+    }
+
+    #[test]
+    fn import_replaces_or_merges() {
+        let mut list = list_of(&["curl", "jq", "old-tool"]);
+        list.get_mut("curl").unwrap().tags.insert("net".into());
+        let system: BTreeSet<String> = ["curl", "git", "jq"].map(String::from).into();
+
+        let replaced = import_plan(&list, &system, false);
+        assert_eq!(replaced.list.keys().collect::<Vec<_>>(), ["curl", "git", "jq"]);
+        assert!(replaced.list["curl"].tags.contains("net"));
+        assert_eq!(replaced.added, ["git"]);
+        assert_eq!(replaced.present, 2);
+        assert_eq!(replaced.dropped, ["old-tool"]);
+
+        let merged = import_plan(&list, &system, true);
+        assert_eq!(merged.list.keys().collect::<Vec<_>>(), ["curl", "git", "jq", "old-tool"]);
+        assert!(merged.dropped.is_empty());
+    }
+
+    #[test]
+    fn status_json_structure() {
+        let pkgs: BTreeSet<String> = ["curl", "git", "zsh"].map(String::from).into();
+        let installed: BTreeSet<String> = ["curl", "git"].map(String::from).into();
+        assert_eq!(status_json(&pkgs, &installed), r#"{"curated": 3, "installed": ["curl", "git"], "missing": ["zsh"]}"#);
+        assert_eq!(status_json(&BTreeSet::new(), &BTreeSet::new()), r#"{"curated": 0, "installed": [], "missing": []}"#);
+    }
+
+    #[test]
+    fn list_json_structure() {
+        let pkgs = BTreeSet::from(["curl".to_string(), "zsh".to_string()]);
+        let versions = BTreeMap::from([("curl".to_string(), "8.5.0".to_string())]);
+        assert_eq!(
+            list_json(&pkgs, &versions),
+            "[\n  {\"name\": \"curl\", \"installed\": true, \"version\": \"8.5.0\"},\n  {\"name\": \"zsh\", \"installed\": false}\n]"
+        );
+        assert_eq!(list_json(&BTreeSet::new(), &versions), "[]");
+    }
+
+    #[test]
+    fn json_str_escapes() {
+        assert_eq!(json_str("a\"b\\c\n"), "\"a\\\"b\\\\c\\n\"");
+    }
+
+    #[test]
+    fn save_merges_external_modification() {
+        let tmp = TempFile::new("external.txt");
+        save_packages(&tmp, &list_of(&["git"])).unwrap();
+
+        let stamp = file_stamp(&tmp);
+        let mut pkgs = load_list(&tmp);
+        let names = ["zsh".to_string()];
+        apply_modify(&mut pkgs, &names, true);
+
+        // Someone edits the file between our load and save
+        fs::write(&*tmp, "git\nhtop\n").unwrap();
+
+        let merged = save_merging(&tmp, stamp, &pkgs, |fresh| { apply_modify(fresh, &names, true); }).unwrap();
+        assert!(merged);
+        assert_eq!(load_packages(&tmp), BTreeSet::from(["git".into(), "htop".into(), "zsh".into()]));
+    }
+
+    #[test]
+    fn save_without_external_modification_overwrites() {
+        let tmp = TempFile::new("unchanged.txt");
+        save_packages(&tmp, &list_of(&["git"])).unwrap();
+        let stamp = file_stamp(&tmp);
+        let pkgs = list_of(&["curl"]);
+        assert!(!save_merging(&tmp, stamp, &pkgs, |_| panic!("should not re-apply")).unwrap());
+        assert_eq!(load_list(&tmp), pkgs);
+    }
+
+    #[test]
+    fn add_duplicate_is_idempotent() {
+        let tmp = TempFile::new("dup.txt");
+        save_packages(&tmp, &PkgList::new()).unwrap();
+
+        cmd_modify(&tmp, &["git".into(), "git".into(), "curl".into()], true);
+        let pkgs = load_packages(&tmp);
+        assert_eq!(pkgs.len(), 2);
+        assert!(pkgs.contains("git"));
+        assert!(pkgs.contains("curl"));
+
+        // Adding again doesn't duplicate
+        cmd_modify(&tmp, &["git".into()], true);
+        let pkgs = load_packages(&tmp);
+        assert_eq!(pkgs.len(), 2);
+    }
+
+    #[test]
+    fn status_lines_mark_missing() {
+        let pkgs = BTreeSet::from(["curl".to_string(), "zsh".to_string()]);
+        let installed = BTreeSet::from(["curl".to_string()]);
+        let out = status_lines(&pkgs, &installed, &BTreeMap::new(), StatusView::default()).join("\n");
+        assert!(out.contains("✔ curl"));
+        assert!(out.contains("✘ zsh"));
+        assert!(out.contains("1 missing"));
+        // The install hint is advisory and goes to stderr
+        assert!(!out.contains("Run `apt-sync install`"));
+    }
+
+    #[test]
+    fn porcelain_format_is_locked() {
+        let pkgs: BTreeSet<String> = ["curl", "jq", "linux-image-generic", "zsh"].map(String::from).into();
+        let installed: BTreeSet<String> = ["curl", "jq", "linux-image-generic"].map(String::from).into();
+        let held = BTreeSet::from(["linux-image-generic".to_string()]);
+        let auto = BTreeSet::from(["jq".to_string(), "linux-image-generic".to_string()]);
+        assert_eq!(
+            porcelain_lines(&pkgs, &installed, &held, &auto),
+            ["I curl", "A jq", "H linux-image-generic", "M zsh"]
+        );
+        let output = "curl\tamd64\thold ok installed\t8.5.0\njq\tamd64\tinstall ok installed\t1.7\n";
+        let native = ArchMode::Native("amd64".into());
+        assert_eq!(parse_held(output, &native), BTreeSet::from(["curl".to_string(), "curl:amd64".to_string()]));
+        // Held packages are still installed
+        assert!(parse_installed(output, &native).contains("curl"));
+    }
+
+    #[test]
+    fn version_pins_round_trip() {
+        let tmp = TempFile::new("pins.txt");
+        fs::write(&*tmp, "docker-ce=5:24.0.7-1 @infra\nlibc6:amd64=2.36*\ngit/bookworm-backports\n").unwrap();
+        let list = load_list(&tmp);
+        assert_eq!(list["docker-ce"].version.as_deref(), Some("5:24.0.7-1"));
+        assert_eq!(list["libc6:amd64"].version.as_deref(), Some("2.36*"));
+        assert_eq!(install_spec("docker-ce", &list["docker-ce"]), "docker-ce=5:24.0.7-1");
+        save_packages(&tmp, &list).unwrap();
+        let saved = fs::read_to_string(&*tmp).unwrap();
+        assert!(saved.contains("\ndocker-ce=5:24.0.7-1 @infra\n"));
+        assert!(saved.contains("\nlibc6:amd64=2.36*\n"));
+        assert!(saved.contains("\ngit/bookworm-backports\n"));
+
+        // Re-adding with a release swaps the pin out
+        let mut list = list;
+        apply_modify(&mut list, &["docker-ce/bookworm".to_string()], true);
+        assert_eq!((list["docker-ce"].release.as_deref(), list["docker-ce"].version.as_deref()), (Some("bookworm"), None));
+    }
+
+    #[test]
+    fn status_flags_versions_off_pin() {
+        let list = parse_list("docker-ce=5:24.0.7-1\ncurl=8.5*\njq=1.7\nzsh\n");
+        let versions: BTreeMap<String, String> =
+            [("docker-ce", "5:25.0.0-1"), ("curl", "8.5.0-2"), ("zsh", "5.9")].map(|(p, v)| (p.to_string(), v.to_string())).into();
+        let off = pin_mismatches(&list, &versions);
+        // jq isn't installed at all: that's missing, not off pin
+        assert_eq!(off, BTreeMap::from([("docker-ce", ("5:24.0.7-1", "5:25.0.0-1"))]));
+
+        let pkgs: BTreeSet<String> = list.keys().cloned().collect();
+        let installed: BTreeSet<String> = versions.keys().cloned().collect();
+        let out = status_lines(&pkgs, &installed, &off, StatusView::default()).join("\n");
+        assert!(out.contains("⚠ docker-ce"));
+        assert!(out.contains("5:25.0.0-1 installed, pinned to 5:24.0.7-1"));
+        assert!(out.contains("✘ jq"));
+        assert!(out.contains("1 missing"));
+        assert!(out.contains("1 off pin"));
+    }
+
+    #[test]
+    fn status_group_by_state_keeps_groups_contiguous() {
+        let pkgs: BTreeSet<String> = ["curl", "git", "jq", "vim", "zsh"].map(String::from).into();
+        let installed: BTreeSet<String> = ["git", "vim"].map(String::from).into();
+        let lines = status_lines(&pkgs, &installed, &BTreeMap::new(), StatusView { group_by_state: true, ..Default::default() });
+        let names: Vec<&str> = lines[1..lines.len() - 2]
+            .iter()
+            .map(|l| ["git", "vim", "curl", "jq", "zsh"].into_iter().find(|n| l.contains(&format!(" {n}"))).unwrap_or(""))
+            .collect();
+        assert_eq!(names, ["git", "vim", "", "curl", "jq", "zsh"]);
+        assert!(lines[1..3].iter().all(|l| l.contains("✔")));
+        assert!(lines[4..7].iter().all(|l| l.contains("✘")));
+        assert!(lines.last().unwrap().contains("2 installed"));
+    }
+
+    #[test]
+    fn status_filters_rows_but_not_the_summary() {
+        let pkgs: BTreeSet<String> = ["curl", "git", "jq"].map(String::from).into();
+        let installed: BTreeSet<String> = ["git"].map(String::from).into();
+        let view = |filter| StatusView { filter, ..Default::default() };
+        let missing = status_lines(&pkgs, &installed, &BTreeMap::new(), view(StatusFilter::Missing)).join("\n");
+        assert!(missing.contains("✘ curl") && missing.contains("✘ jq"));
+        assert!(!missing.contains("git"));
+        assert!(missing.contains("1 installed") && missing.contains("2 missing"));
+        let present = status_lines(&pkgs, &installed, &BTreeMap::new(), view(StatusFilter::Installed)).join("\n");
+        assert!(present.contains("✔ git"));
+        assert!(!present.contains("curl") && !present.contains("jq"));
+        assert!(present.contains("1 installed") && present.contains("2 missing"));
+    }
+
+    #[test]
+    fn status_lines_all_installed() {
+        let pkgs = BTreeSet::from(["curl".to_string()]);
+        let out = status_lines(&pkgs, &pkgs, &BTreeMap::new(), StatusView::default()).join("\n");
+        assert!(out.contains("0 missing"));
+    }
+
+    #[test]
+    fn list_writes_only_names_to_stdout() {
+        let (mut out, mut err) = (Vec::new(), Vec::new());
+        write_list(&mut out, &mut err, &BTreeSet::from(["curl".to_string(), "git".to_string()])).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "curl\ngit\n");
+        assert!(err.is_empty());
+
+        let (mut out, mut err) = (Vec::new(), Vec::new());
+        write_list(&mut out, &mut err, &BTreeSet::new()).unwrap();
+        assert!(out.is_empty());
+        assert!(String::from_utf8(err).unwrap().contains("No curated packages"));
     }
-    println!("{BOLD}{CYAN}🚀 Installing {} missing package(s){RESET}\n", missing.len());
-    for m in &missing {
-        println!("  {CYAN}• {m}{RESET}");
+
+    #[test]
+    fn check_code_curated_and_installed() {
+        assert_eq!(check_code(true, true), 0);
     }
-    println!();
-    if dry_run {
-        println!("{YELLOW}🏜️  Dry run — nothing was installed{RESET}");
-        println!("{DIM}Would run: apt-get install -y {}{RESET}", missing.join(" "));
-        return;
+
+    #[test]
+    fn check_code_curated_not_installed() {
+        assert_eq!(check_code(true, false), 1);
     }
-    let status = Command::new("apt-get")
-        .args(["install", "-y"])
-        .args(&missing)
-        .status()
-        .expect("failed to run apt-get");
-    if status.success() {
-        println!("\n{GREEN}✨ Done! All packages installed.{RESET}");
-    } else {
-        println!("\n{RED}💥 apt-get exited with errors{RESET}");
+
+    #[test]
+    fn check_code_installed_not_curated() {
+        assert_eq!(check_code(false, true), 2);
     }
-}
 
-fn cmd_diff(pkg_path: &Path) {
-    let curated = load_packages(pkg_path);
-    let system = system_manual_packages();
-    let on_system_only: Vec<&String> = system.difference(&curated).collect();
-    let in_list_only: Vec<&String> = curated.difference(&system).collect();
+    #[test]
+    fn check_code_neither() {
+        assert_eq!(check_code(false, false), 3);
+    }
 
-    if on_system_only.is_empty() && in_list_only.is_empty() {
-        println!("{GREEN}✨ System and curated list are in perfect sync!{RESET}");
-        return;
+    #[test]
+    fn uninstall_skips_packages_not_installed() {
+        let names = ["jq".to_string(), "htop".to_string(), "vim".to_string()];
+        let installed = BTreeSet::from(["vim".to_string(), "jq".to_string()]);
+        let (present, absent) = split_installed(&names, &installed);
+        assert_eq!(present, ["jq", "vim"]);
+        assert_eq!(absent, ["htop"]);
     }
-    for (items, header, icon, color) in [
-        (&on_system_only, "🔍 On system but not curated", "?", YELLOW),
-        (&in_list_only, "📋 Curated but not on system", "✘", RED),
-    ] {
-        if !items.is_empty() {
-            println!("{BOLD}{color}{header}{RESET} {DIM}({} packages){RESET}\n", items.len());
-            for p in items { println!("  {color}{icon} {p}{RESET}"); }
-            println!();
-        }
+
+    #[test]
+    fn sync_spots_dependents_apt_would_also_remove() {
+        let sim = "NOTE: This is only a simulation!\nReading package lists...\n\
+                   Remv htop [3.3.0-4]\nRemv libhtop:amd64 [3.3.0-4]\nPurg git-gui [1:2.43.0-1]\nPurg git [1:2.43.0-1]\n";
+        let simulated = parse_simulated_removals(sim);
+        assert_eq!(simulated, ["git", "git-gui", "htop", "libhtop"].map(String::from).into());
+        let planned = ["htop".to_string(), "git-gui".to_string()];
+        let curated = BTreeSet::from(["git:amd64".to_string()]);
+        let (blocked, dependents) = removal_collateral(&simulated, &planned, &curated, &BTreeSet::new());
+        assert_eq!((blocked, dependents), (vec!["git".to_string()], vec!["libhtop".to_string()]));
+        let protected = BTreeSet::from(["libhtop".to_string()]);
+        let (blocked, dependents) = removal_collateral(&simulated, &planned, &BTreeSet::new(), &protected);
+        assert_eq!((blocked, dependents), (vec!["libhtop".to_string()], vec!["git".to_string()]));
     }
-    println!("{DIM}Use `apt-sync add <pkg>` to curate, `apt-sync install` to install missing{RESET}");
-}
 
-#[allow(clippy::significant_drop_tightening)]
-fn cmd_snap(pkg_path: &Path) {
-    let system = system_manual_packages();
-    let curated = load_packages(pkg_path);
-    let uncurated: Vec<&String> = system.difference(&curated).collect();
+    #[test]
+    fn sync_plan_installs_missing_and_removes_uncurated() {
+        let list = parse_list("curl\ngit/bookworm-backports\nvim\n");
+        let installed = BTreeSet::from(["curl".to_string()]);
+        let system = BTreeSet::from(["curl".to_string(), "htop".to_string(), "sudo".to_string()]);
+        let protected = BTreeSet::from(["sudo".to_string()]);
+        let plan = sync_plan(&list, &installed, &system, &protected);
+        assert_eq!(plan.install, ["git/bookworm-backports", "vim"]);
+        assert_eq!(plan.remove, ["htop"]);
+        assert_eq!(plan.protected, ["sudo"]);
+    }
 
-    if uncurated.is_empty() {
-        println!("{GREEN}✨ All manual system packages are already curated!{RESET}");
-        return;
+    #[test]
+    fn upgrade_only_targets_newer_candidates() {
+        let output = "\
+curl:
+  Installed: 8.5.0-2ubuntu10.1
+  Candidate: 8.5.0-2ubuntu10.6
+  Version table:
+ *** 8.5.0-2ubuntu10.1 100
+        100 /var/lib/dpkg/status
+jq:
+  Installed: 1.7.1-3
+  Candidate: 1.7.1-3
+htop:
+  Installed: (none)
+  Candidate: 3.3.0-4
+";
+        let policy = parse_policy(output);
+        assert_eq!(policy.len(), 2);
+        assert_eq!(newer_candidates(&policy), [("curl", "8.5.0-2ubuntu10.1", "8.5.0-2ubuntu10.6")]);
+        let cmd = apt_get_upgrade_command(&["curl"]);
+        assert_eq!(cmd.get_args().collect::<Vec<_>>(), ["install", "--only-upgrade", "-y", "curl"]);
     }
 
-    println!("{BOLD}{CYAN}📸 Snapshot — {} uncurated manual packages{RESET}\n", uncurated.len());
-    println!(
-        "{DIM}For each package, type {RESET}{BOLD}y{RESET}{DIM} to add, \
-         {RESET}{BOLD}n{RESET}{DIM} to skip, \
-         {RESET}{BOLD}q{RESET}{DIM} to quit:{RESET}\n"
-    );
+    #[test]
+    fn outdated_line_shows_version_change() {
+        let line = version_change_line("curl", "8.5.0-2", "8.5.0-3");
+        assert!(line.contains("curl"));
+        assert!(line.contains("8.5.0-2 → 8.5.0-3"));
+    }
 
-    let stdin = io::stdin();
-    let mut to_add = Vec::new();
+    #[test]
+    fn install_forwards_jobs_to_apt() {
+        let plain = apt_get_install_command(&["jq"], None, false);
+        assert_eq!(plain.get_args().collect::<Vec<_>>(), ["install", "-y", "jq"]);
+        let tuned = apt_get_install_command(&["jq", "rg"], Some(8), false);
+        assert_eq!(
+            tuned.get_args().collect::<Vec<_>>(),
+            ["install", "-y", "-o", "Acquire::Queue-Mode=access", "-o", "Acquire::http::Pipeline-Depth=8", "jq", "rg"]
+        );
+        assert_eq!(parse_jobs("4"), Ok(4));
+        assert!(parse_jobs("0").is_err());
+        assert!(parse_jobs("65").is_err());
+        assert!(parse_jobs("many").is_err());
+    }
 
-    {
-        let mut reader = stdin.lock();
-        for pkg in &uncurated {
-            print!("  {CYAN}{pkg}{RESET}  [y/n/q] ");
-            io::stdout().flush().unwrap();
-            let mut line = String::new();
-            if reader.read_line(&mut line).is_err() {
-                break;
-            }
-            match line.trim().to_lowercase().as_str() {
-                "y" | "yes" => to_add.push((*pkg).clone()),
-                "q" | "quit" => break,
-                _ => {}
-            }
-        }
+    #[test]
+    fn apt_get_remove_purges_only_on_request() {
+        let remove = apt_get_remove_command(&["htop"], false);
+        assert_eq!(remove.get_args().collect::<Vec<_>>(), ["remove", "-y", "htop"]);
+        let purge = apt_get_remove_command(&["htop"], true);
+        assert_eq!(purge.get_args().collect::<Vec<_>>(), ["purge", "-y", "htop"]);
     }
 
-    if to_add.is_empty() {
-        println!("\n{DIM}No packages added.{RESET}");
-        return;
+    #[test]
+    fn protected_package_excluded_from_prune_plan() {
+        let protected = BTreeSet::from(["dpkg".to_string(), "systemd".to_string()]);
+        let (allowed, blocked) = plan_removal(&["htop", "dpkg", "jq", "systemd"], &protected);
+        assert_eq!(allowed, vec!["htop", "jq"]);
+        assert_eq!(blocked, vec!["dpkg", "systemd"]);
     }
-    cmd_modify(pkg_path, &to_add, true);
-}
 
-fn cmd_why(names: &[String], window_mins: u32, show_all: bool) {
-    let log = read_history_logs();
-    let entries = parse_history(&log);
-    let shell_history = read_shell_history();
-    let window_secs = i64::from(window_mins) * 60;
+    #[test]
+    fn parse_protected_list_accepts_commas_and_spaces() {
+        let set = parse_protected_list("openssh-server, nginx  docker-ce,");
+        assert_eq!(set.len(), 3);
+        assert!(set.contains("openssh-server"));
+        assert!(set.contains("nginx"));
+        assert!(set.contains("docker-ce"));
+    }
 
-    for (i, name) in names.iter().enumerate() {
-        if i > 0 {
-            println!();
-        }
-        let hits = find_install_history(&entries, name);
-        if hits.is_empty() {
-            println!("{DIM}{name}: no install history found{RESET}");
-            continue;
-        }
-        println!("{BOLD}{CYAN}{name}{RESET}");
-        for entry in &hits {
-            let date = entry.date.split_whitespace().next().unwrap_or(&entry.date);
-            println!("  {GREEN}📅 {date}{RESET}  {DIM}{}{RESET}", entry.commandline);
-            if let Some(ref user) = entry.requested_by {
-                println!("     {DIM}by {user}{RESET}");
-            }
+    #[test]
+    fn parse_essential_output() {
+        let output = "bash\tyes\ncurl\t\ndpkg\tyes\nvim\tno\n";
+        assert_eq!(parse_essential(output), BTreeSet::from(["bash".into(), "dpkg".into()]));
+    }
 
-            // Working directory from journal
-            if let Some(pwd) = read_journal_pwd(&entry.date, &entry.commandline) {
-                println!("     {DIM}in: {pwd}{RESET}");
-            }
+    #[test]
+    fn spec_key_strips_version_keeps_arch() {
+        assert_eq!(spec_key("git"), ("git", None));
+        assert_eq!(spec_key("git=1:2.43.0-1"), ("git", None));
+        assert_eq!(spec_key("libc6:i386=2.39"), ("libc6", Some("i386")));
+    }
 
-            let sibs: Vec<&str> = entry.installed.iter().map(String::as_str).filter(|p| *p != name).collect();
-            if !sibs.is_empty() {
-                println!("     {DIM}with: {}{RESET}", format_pkg_list(&sibs));
-            }
-            let sibling_set: BTreeSet<&str> = sibs.iter().copied().collect();
-            let neighbors = same_day_neighbors(&entries, entry, name, &sibling_set);
-            if !neighbors.is_empty() {
-                println!("     {DIM}also that day: {}{RESET}", format_pkg_list(&neighbors));
-            }
+    #[test]
+    fn target_release_parsed_and_serialized() {
+        let pkgs = parse_list("neovim/bookworm-backports @editor\ngit\n");
+        assert_eq!(pkgs["neovim"].release.as_deref(), Some("bookworm-backports"));
+        assert_eq!(install_spec("neovim", &pkgs["neovim"]), "neovim/bookworm-backports");
+        assert_eq!(install_spec("git", &pkgs["git"]), "git");
 
-            // Shell history context
-            if let Some(epoch) = apt_date_to_epoch(&entry.date) {
-                let nearby = find_nearby_commands(&shell_history, epoch, window_secs, show_all);
-                if !nearby.is_empty() {
-                    println!("     {DIM}around then:{RESET}");
-                    for cmd in &nearby {
-                        println!("       {DIM}{cmd}{RESET}");
-                    }
-                }
-            }
-        }
+        let tmp = TempFile::new("release.txt");
+        save_packages(&tmp, &pkgs).unwrap();
+        assert!(fs::read_to_string(&*tmp).unwrap().contains("neovim/bookworm-backports @editor\n"));
+        assert_eq!(load_list(&tmp), pkgs);
     }
-}
 
-// ── Help ────────────────────────────────────────────────────────────
+    #[test]
+    fn add_and_remove_with_release() {
+        let mut pkgs = PkgList::new();
+        let names = ["neovim/bookworm-backports".to_string()];
+        apply_modify(&mut pkgs, &names, true);
+        assert_eq!(pkgs["neovim"].release.as_deref(), Some("bookworm-backports"));
+        let names = ["neovim".to_string()];
+        let (changed, _) = apply_modify(&mut pkgs, &names, false);
+        assert_eq!(changed, vec!["neovim"]);
+        assert!(pkgs.is_empty());
+    }
 
-fn print_help() {
-    println!(
-        "\n\
-{BOLD}{CYAN}📦 apt-sync{RESET} — curated APT package manager\n\
-\n\
-{BOLD}USAGE:{RESET}\n    \
-    apt-sync <command> [options]\n\
-\n\
-{BOLD}COMMANDS:{RESET}\n    \
-    {GREEN}status{RESET}  {DIM}(s){RESET}     Show installed/missing curated packages\n    \
-    {GREEN}list{RESET}    {DIM}(ls){RESET}    List all curated packages\n    \
-    {GREEN}add{RESET}     {DIM}(a){RESET}     Add package(s) to curated list\n    \
-    {GREEN}remove{RESET}  {DIM}(rm){RESET}    Remove package(s) from curated list\n    \
-    {GREEN}install{RESET} {DIM}(i){RESET}     Install missing curated packages\n    \
-    {GREEN}diff{RESET}    {DIM}(d){RESET}     Compare system packages vs curated list\n    \
-    {GREEN}snap{RESET}             Interactively pick from system packages\n    \
-    {GREEN}why{RESET}     {DIM}(w){RESET}     Show install history for package(s)\n\
-\n\
-{BOLD}OPTIONS:{RESET}\n    \
-    {YELLOW}--dry-run{RESET}        Show what would happen (install only)\n    \
-    {YELLOW}--window=N{RESET}       Minutes before/after install to search history (why only, default: 5)\n    \
-    {YELLOW}--all{RESET}            Show all commands in history window (why only, default: interesting only)\n    \
-    {YELLOW}--help, -h{RESET}       Show this help\n\
-\n\
-{BOLD}CONFIG:{RESET}\n    \
-    Packages file: {DIM}$APT_SYNC_FILE{RESET} or {DIM}~/.config/apt-sync/packages.txt{RESET}\n",
-    );
-}
+    #[test]
+    fn status_matching_ignores_release_suffix() {
+        let curated = parse_packages("neovim/bookworm-backports\n");
+        assert_eq!(curated, BTreeSet::from(["neovim".into()]));
+        let installed = parse_installed("neovim\tamd64\tinstall ok installed\n", &ArchMode::Any);
+        assert!(curated.is_subset(&installed));
+    }
 
-// ── Main ────────────────────────────────────────────────────────────
+    #[test]
+    fn install_only_narrows_to_named_packages() {
+        let mut list = list_of(&["curl", "jq", "ripgrep"]);
+        list.get_mut("jq").unwrap().release = Some("bookworm-backports".into());
+        let installed = BTreeSet::from(["curl".to_string()]);
 
-fn main() -> ExitCode {
-    let args: Vec<String> = env::args().skip(1).collect();
-    if args.is_empty() || args.iter().any(|a| a == "--help" || a == "-h") {
-        print_help();
-        return ExitCode::SUCCESS;
+        let only = ["jq".to_string(), "curl".to_string()];
+        // curl is already installed; jq keeps its release pin; ripgrep isn't requested
+        assert_eq!(missing_specs(&list, &installed, Some(&only)), Ok(vec!["jq/bookworm-backports".to_string()]));
+        assert_eq!(missing_specs(&list, &installed, None).unwrap().len(), 2);
+
+        let only = ["jq".to_string(), "vim".to_string()];
+        assert_eq!(missing_specs(&list, &installed, Some(&only)), Err(vec!["vim".to_string()]));
     }
 
-    let pkg_path = pkg_file_path();
-    let cmd = args[0].as_str();
-    let rest = &args[1..];
-    let dry_run = rest.iter().any(|a| a == "--dry-run");
+    #[test]
+    fn gz_logs_read_in_order_and_skip_bad_files() {
+        let old = TempFile::new("history.log.3.gz");
+        let newer = TempFile::new("history.log.2.gz");
+        let bad = TempFile::new("history.log.bad.gz");
+        use flate2::{Compression, write::GzEncoder};
+        let gzip = |text: &str| {
+            let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+            gz.write_all(text.as_bytes()).unwrap();
+            gz.finish().unwrap()
+        };
+        fs::write(&*old, gzip("stored\n")).unwrap();
+        fs::write(&*newer, gzip("Commandline: apt install ripgrep\n")).unwrap();
+        fs::write(&*bad, b"junk").unwrap();
+        let paths = [old.to_path_buf(), bad.to_path_buf(), newer.to_path_buf()];
+        assert_eq!(read_gz_logs(&paths), "stored\nCommandline: apt install ripgrep\n");
+    }
 
-    // Parse --window=N for why command
-    let window_mins = rest
-        .iter()
-        .find_map(|a| a.strip_prefix("--window=")?.parse().ok())
-        .unwrap_or(5);
-    let show_all = rest.iter().any(|a| a == "--all");
+    #[test]
+    fn dpkg_query_chunks_match_one_call() {
+        let pkgs: BTreeSet<String> = (0..5000).map(|i| format!("pkg{i}")).collect();
+        // Stand-in for dpkg-query: every even-numbered package is installed
+        let fake = |chunk: &[&String]| -> String {
+            chunk
+                .iter()
+                .filter(|p| p[3..].parse::<u32>().unwrap() % 2 == 0)
+                .map(|p| format!("{p}\tamd64\tinstall ok installed\t1.0\n"))
+                .collect()
+        };
+        let mut calls = 0;
+        let chunked = query_in_chunks(&pkgs, DPKG_QUERY_CHUNK, |c| {
+            calls += 1;
+            assert!(c.len() <= DPKG_QUERY_CHUNK);
+            fake(c)
+        });
+        let single = query_in_chunks(&pkgs, pkgs.len(), fake);
+        assert_eq!(calls, 10);
+        let arch = ArchMode::Native("amd64".into());
+        assert_eq!(parse_installed(&chunked, &arch), parse_installed(&single, &arch));
+        assert_eq!(parse_installed(&chunked, &arch).intersection(&pkgs).count(), 2500);
+    }
 
-    let rest_no_flags: Vec<String> = rest
-        .iter()
-        .filter(|a| !a.starts_with('-'))
-        .cloned()
-        .collect();
+    #[test]
+    fn euid_from_proc_status() {
+        assert_eq!(parse_euid("Name:\tapt-sync\nUid:\t1000\t0\t0\t0\nGid:\t1000\t1000\t1000\t1000\n"), Some(0));
+        assert_eq!(parse_euid("Uid:\t1000\t1000\t1000\t1000\n"), Some(1000));
+        assert_eq!(parse_euid("Name:\tx\n"), None);
+    }
 
-    match cmd {
-        "status" | "s" => cmd_status(&pkg_path),
-        "list" | "ls" => cmd_list(&pkg_path),
-        "add" | "a" | "remove" | "rm" | "why" | "w" if rest_no_flags.is_empty() => {
-            let name = match cmd { "a" => "add", "rm" => "remove", "w" => "why", c => c };
-            eprintln!("{RED}Usage: apt-sync {name} <pkg...>{RESET}");
-            return ExitCode::FAILURE;
-        }
-        "add" | "a" => cmd_modify(&pkg_path, &rest_no_flags, true),
-        "remove" | "rm" => cmd_modify(&pkg_path, &rest_no_flags, false),
-        "install" | "i" => cmd_install(&pkg_path, dry_run),
-        "diff" | "d" => cmd_diff(&pkg_path),
-        "snap" => cmd_snap(&pkg_path),
-        "why" | "w" => cmd_why(&rest_no_flags, window_mins, show_all),
-        _ => {
-            eprintln!("{RED}Unknown command: {cmd}{RESET}");
-            print_help();
-            return ExitCode::FAILURE;
-        }
+    #[test]
+    fn sudo_reexec_keeps_list_and_args() {
+        let args = ["install".to_string(), "--only".to_string(), "jq".to_string()];
+        let cmd = sudo_reexec_command(Path::new("/usr/local/bin/apt-sync"), &args, Path::new("/home/me/packages.txt"));
+        assert_eq!(cmd.get_program(), "sudo");
+        assert_eq!(
+            cmd.get_args().collect::<Vec<_>>(),
+            [
+                "--",
+                "env",
+                "APT_SYNC_SUDO=1",
+                "APT_SYNC_FILE=/home/me/packages.txt",
+                "/usr/local/bin/apt-sync",
+                "install",
+                "--only",
+                "jq"
+            ]
+        );
     }
-    ExitCode::SUCCESS
-}
 
-// ── Tests ───────────────────────────────────────────────────────────
+    #[test]
+    fn install_confirms_only_interactively() {
+        assert!(install_needs_confirmation(false, true));
+        assert!(!install_needs_confirmation(true, true));
+        assert!(!install_needs_confirmation(false, false));
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[test]
+    fn reinstall_targets_installed_subset() {
+        let mut list = list_of(&["curl", "jq", "ripgrep"]);
+        list.get_mut("curl").unwrap().version = Some("8.5.0-2".into());
+        let installed = BTreeSet::from(["curl".to_string(), "jq".to_string()]);
 
-    struct TempFile(PathBuf);
+        // Missing ripgrep is skipped; curl reinstalls its current version, not the pin
+        assert_eq!(reinstall_targets(&list, &installed, None), Ok(vec!["curl", "jq"]));
+        let only = ["jq".to_string(), "ripgrep".to_string()];
+        assert_eq!(reinstall_targets(&list, &installed, Some(&only)), Ok(vec!["jq"]));
+        let only = ["vim".to_string()];
+        assert_eq!(reinstall_targets(&list, &installed, Some(&only)), Err(vec!["vim".to_string()]));
 
-    impl TempFile {
-        fn new(name: &str) -> Self {
-            let path = std::env::temp_dir().join(format!("apt-sync-test-{name}"));
-            let _ = fs::create_dir_all(path.parent().unwrap());
-            Self(path)
-        }
+        let cmd = apt_get_install_command(&["curl", "jq"], None, true);
+        assert_eq!(cmd.get_args().collect::<Vec<_>>(), ["install", "--reinstall", "-y", "curl", "jq"]);
     }
 
-    impl Drop for TempFile {
-        fn drop(&mut self) {
-            let _ = fs::remove_file(&self.0);
-        }
+    #[test]
+    fn remove_gate_threshold() {
+        assert_eq!(remove_gate(REMOVE_CONFIRM_THRESHOLD, false, false), RemoveGate::Proceed);
+        assert_eq!(remove_gate(REMOVE_CONFIRM_THRESHOLD + 1, false, true), RemoveGate::Ask);
+        assert_eq!(remove_gate(REMOVE_CONFIRM_THRESHOLD + 1, false, false), RemoveGate::Refuse);
+        assert_eq!(remove_gate(REMOVE_CONFIRM_THRESHOLD + 1, true, false), RemoveGate::Proceed);
     }
 
-    impl std::ops::Deref for TempFile {
-        type Target = Path;
-        fn deref(&self) -> &Path {
-            &self.0
-        }
+    #[test]
+    fn stale_entries_are_curated_but_not_installed() {
+        let pkgs: BTreeSet<String> = ["curl", "old-tool", "zsh"].map(String::from).into();
+        let installed: BTreeSet<String> = ["curl", "zsh", "vim"].map(String::from).into();
+        assert_eq!(stale_entries(&pkgs, &installed), ["old-tool"]);
     }
 
-    fn entry(timestamp: i64, command: &str) -> ShellHistoryEntry {
-        ShellHistoryEntry {
-            timestamp,
-            command: command.to_string(),
-        }
+    #[test]
+    fn residual_missing_after_install() {
+        let specs = ["curl", "neovim/bookworm-backports", "jq=1.7", "libc6:i386", "nope"];
+        let installed_after = parse_installed(
+            "curl\tamd64\tinstall ok installed\n\
+             neovim\tamd64\tinstall ok installed\n\
+             jq\tamd64\tinstall ok installed\n\
+             libc6\ti386\tinstall ok installed\n",
+            &ArchMode::Native("amd64".into()),
+        );
+        assert_eq!(residual_missing(&specs, &installed_after), vec!["nope"]);
     }
 
     #[test]
-    fn parse_empty() {
-        assert!(parse_packages("").is_empty());
+    fn residual_missing_when_nothing_installed() {
+        assert_eq!(residual_missing(&["curl", "jq"], &BTreeSet::new()), vec!["curl", "jq"]);
     }
 
     #[test]
-    fn parse_with_comments_and_blanks() {
-        let input = "# comment\n\nzsh\ngit\n# another\ncurl\n";
-        let pkgs = parse_packages(input);
-        assert_eq!(pkgs.len(), 3);
-        assert!(pkgs.contains("zsh"));
-        assert!(pkgs.contains("git"));
-        assert!(pkgs.contains("curl"));
+    fn dedupe_prefers_release_spec() {
+        let (kept, _) = dedupe_install_specs(&["neovim", "neovim/bookworm-backports"]);
+        assert_eq!(kept, vec!["neovim/bookworm-backports"]);
     }
 
     #[test]
-    fn parse_trims_whitespace() {
-        let input = "  zsh  \n  git  \n";
-        let pkgs = parse_packages(input);
-        assert!(pkgs.contains("zsh"));
-        assert!(pkgs.contains("git"));
+    fn dedupe_prefers_pinned_spec() {
+        let (kept, dropped) = dedupe_install_specs(&["curl", "git", "git=1:2.43.0-1", "zsh"]);
+        assert_eq!(kept, vec!["curl", "git=1:2.43.0-1", "zsh"]);
+        assert_eq!(dropped, vec![("git", "git=1:2.43.0-1")]);
     }
 
     #[test]
-    fn parse_deduplicates() {
-        let input = "zsh\nzsh\ngit\n";
-        let pkgs = parse_packages(input);
-        assert_eq!(pkgs.len(), 2);
+    fn dedupe_keeps_first_when_equally_specific() {
+        let (kept, dropped) = dedupe_install_specs(&["jq=1.7", "jq=1.6"]);
+        assert_eq!(kept, vec!["jq=1.7"]);
+        assert_eq!(dropped, vec![("jq=1.6", "jq=1.7")]);
     }
 
     #[test]
-    fn roundtrip_save_load() {
-        let tmp = TempFile::new("roundtrip.txt");
-        let mut pkgs = BTreeSet::new();
-        pkgs.insert("curl".to_string());
-        pkgs.insert("git".to_string());
-        pkgs.insert("zsh".to_string());
+    fn dedupe_keeps_distinct_architectures() {
+        let (kept, dropped) = dedupe_install_specs(&["libc6:amd64", "libc6:i386"]);
+        assert_eq!(kept, vec!["libc6:amd64", "libc6:i386"]);
+        assert!(dropped.is_empty());
+    }
+
+    #[test]
+    fn install_dedupes_duplicate_from_merged_list() {
+        let tmp = TempFile::new("dedupe.txt");
+        fs::write(&*tmp, "# base\nripgrep\n# appended from another machine\nripgrep=14.1.0-1\n").unwrap();
+        let specs: Vec<String> = load_list(&tmp).iter().map(|(p, entry)| install_spec(p, entry)).collect();
+        let specs: Vec<&str> = specs.iter().map(String::as_str).collect();
+        let (kept, _) = dedupe_install_specs(&specs);
+        assert_eq!(kept, vec!["ripgrep=14.1.0-1"]);
+    }
+
+    #[test]
+    fn parse_and_save_tags() {
+        let pkgs = parse_list("git @dev @cli\nzsh @shell # login shell\ncurl\n");
+        assert_eq!(pkgs["git"].tags, BTreeSet::from(["cli".into(), "dev".into()]));
+        assert_eq!(pkgs["zsh"].tags, BTreeSet::from(["shell".into()]));
+        assert!(pkgs["curl"].tags.is_empty());
+
+        let tmp = TempFile::new("tags.txt");
         save_packages(&tmp, &pkgs).unwrap();
-        let loaded = load_packages(&tmp);
-        assert_eq!(pkgs, loaded);
+        assert!(fs::read_to_string(&*tmp).unwrap().contains("git @cli @dev\n"));
+        assert_eq!(load_list(&tmp), pkgs);
     }
 
     #[test]
-    fn diff_logic() {
-        let curated = BTreeSet::from(["git".into(), "curl".into(), "zsh".into()]);
-        let system = BTreeSet::from(["git".into(), "vim".into()]);
-        let on_system_only: Vec<&String> = system.difference(&curated).collect();
-        let in_list_only: Vec<&String> = curated.difference(&system).collect();
-        assert_eq!(on_system_only, vec![&"vim".to_string()]);
-        assert!(in_list_only.contains(&&"curl".to_string()));
-        assert!(in_list_only.contains(&&"zsh".to_string()));
+    fn add_with_tags() {
+        let mut pkgs = PkgList::new();
+        let names = ["jq".to_string(), "@cli".to_string(), "rg".to_string()];
+        let (changed, _) = apply_modify(&mut pkgs, &names, true);
+        assert_eq!(changed, vec!["jq", "rg"]);
+        assert!(pkgs["jq"].tags.contains("cli"));
+        assert!(pkgs["rg"].tags.contains("cli"));
+        // Tagging an existing package counts as a change, re-adding the same tag does not
+        let names = ["jq".to_string(), "@json".to_string()];
+        let (changed, unchanged) = apply_modify(&mut pkgs, &names, true);
+        assert_eq!((changed, unchanged), (vec!["jq"], vec![]));
+        let (changed, unchanged) = apply_modify(&mut pkgs, &names, true);
+        assert_eq!((changed, unchanged), (vec![], vec!["jq"]));
     }
 
     #[test]
-    fn load_nonexistent_file() {
-        let path = Path::new("/tmp/apt-sync-nonexistent-test.txt");
-        assert!(load_packages(path).is_empty());
+    fn tags_rename_affects_only_tagged() {
+        let mut pkgs = parse_list("git @dev\nvim @dev @editor\ncurl @net\n");
+        assert_eq!(rename_tag(&mut pkgs, "dev", "work"), 2);
+        assert_eq!(pkgs["git"].tags, BTreeSet::from(["work".into()]));
+        assert_eq!(pkgs["vim"].tags, BTreeSet::from(["editor".into(), "work".into()]));
+        assert_eq!(pkgs["curl"].tags, BTreeSet::from(["net".into()]));
     }
 
     #[test]
-    fn parse_only_comments() {
-        let input = "# just a comment\n# another comment\n";
-        assert!(parse_packages(input).is_empty());
+    fn tags_remove_affects_only_tagged() {
+        let mut pkgs = parse_list("git @dev\nvim @dev @editor\ncurl @net\n");
+        assert_eq!(remove_tag(&mut pkgs, "dev"), 2);
+        assert!(pkgs["git"].tags.is_empty());
+        assert_eq!(pkgs["vim"].tags, BTreeSet::from(["editor".into()]));
+        assert_eq!(pkgs["curl"].tags, BTreeSet::from(["net".into()]));
+        assert_eq!(tag_counts(&pkgs), BTreeMap::from([("editor", 1), ("net", 1)]));
     }
 
     #[test]
-    fn save_preserves_header() {
-        let tmp = TempFile::new("header.txt");
-        let pkgs = BTreeSet::from(["git".to_string()]);
-        save_packages(&tmp, &pkgs).unwrap();
-        let raw = fs::read_to_string(&*tmp).unwrap();
-        assert!(raw.starts_with("# apt-sync curated packages\n"));
-        assert!(raw.contains("# one package per line"));
+    fn mark_auto_command_args() {
+        let cmd = apt_mark_auto_command(&["htop".into(), "jq".into()]);
+        assert_eq!(cmd.get_program(), "apt-mark");
+        let args: Vec<_> = cmd.get_args().collect();
+        assert_eq!(args, ["auto", "htop", "jq"]);
     }
 
     #[test]
-    fn add_remove_roundtrip() {
-        let tmp = TempFile::new("addrem.txt");
-        save_packages(&tmp, &BTreeSet::new()).unwrap();
+    fn lock_error_detection() {
+        assert!(is_lock_error("E: Could not get lock /var/lib/dpkg/lock-frontend. It is held by process 1234"));
+        assert!(is_lock_error("E: Unable to acquire the dpkg frontend lock (/var/lib/dpkg/lock-frontend)"));
+        assert!(!is_lock_error("E: Unable to locate package nope"));
+    }
 
-        cmd_modify(&tmp, &["curl".into(), "git".into(), "zsh".into()], true);
-        let pkgs = load_packages(&tmp);
-        assert_eq!(pkgs.len(), 3);
+    #[test]
+    fn retry_on_lock_backs_off_until_success() {
+        let mut results = vec![AptOutcome::Success, AptOutcome::Locked, AptOutcome::Locked];
+        let mut sleeps = Vec::new();
+        let outcome = retry_on_lock(|| results.pop().unwrap(), Duration::from_secs(60), |d| sleeps.push(d.as_secs()));
+        assert_eq!(outcome, AptOutcome::Success);
+        assert_eq!(sleeps, vec![2, 4]);
+    }
 
-        cmd_modify(&tmp, &["git".into()], false);
-        let pkgs = load_packages(&tmp);
-        assert_eq!(pkgs.len(), 2);
-        assert!(!pkgs.contains("git"));
+    #[test]
+    fn retry_on_lock_gives_up_after_max_wait() {
+        let mut sleeps = Vec::new();
+        let outcome = retry_on_lock(|| AptOutcome::Locked, Duration::from_secs(5), |d| sleeps.push(d.as_secs()));
+        assert_eq!(outcome, AptOutcome::Locked);
+        assert_eq!(sleeps, vec![2, 3]);
     }
 
     #[test]
-    fn parse_installed_output() {
-        let output = "curl\tinstall ok installed\n\
-                      git\tdeinstall ok config-files\n\
-                      zsh\tinstall ok installed\n";
-        let set = parse_installed(output);
-        assert_eq!(set.len(), 2);
-        assert!(set.contains("curl"));
-        assert!(set.contains("zsh"));
-        assert!(!set.contains("git"));
+    fn retry_on_lock_does_not_retry_other_failures() {
+        let mut calls = 0;
+        let outcome = retry_on_lock(|| { calls += 1; AptOutcome::Failed }, Duration::from_secs(60), |_| {});
+        assert_eq!(outcome, AptOutcome::Failed);
+        assert_eq!(calls, 1);
     }
 
     #[test]
-    fn parse_installed_empty() {
-        assert!(parse_installed("").is_empty());
+    fn parse_history_remove_and_purge() {
+        let log = "\
+Start-Date: 2026-02-10  12:11:38
+Commandline: apt-get install -y nginx
+Install: nginx:amd64 (1.24.0)
+End-Date: 2026-02-10  12:12:00
+
+Start-Date: 2026-03-01  09:00:00
+Commandline: apt-get remove nginx
+Remove: nginx:amd64 (1.24.0)
+End-Date: 2026-03-01  09:00:05
+
+Start-Date: 2026-04-02  18:30:00
+Commandline: apt-get purge nginx
+Purge: nginx:amd64 (1.24.0)
+End-Date: 2026-04-02  18:30:02
+";
+        let entries = parse_history(log);
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[1].removed, ["nginx"]);
+        assert!(entries[1].purged.is_empty());
+        assert_eq!(entries[2].purged, ["nginx"]);
+        assert_eq!(find_install_history(&entries, "nginx").len(), 1);
+
+        let removals = find_removal_history(&entries, "nginx:amd64");
+        let kinds: Vec<(&str, Removal)> = removals.iter().map(|(e, k)| (e.date.as_str(), *k)).collect();
+        assert_eq!(kinds, [("2026-03-01  09:00:00", Removal::Removed), ("2026-04-02  18:30:00", Removal::Purged)]);
+        assert!(removal_line(removals[0].0, removals[0].1).contains("removed 2026-03-01"));
+        assert!(removal_line(removals[1].0, removals[1].1).contains("purged 2026-04-02"));
+        assert!(why_installed_line(true).contains("currently installed"));
+        assert!(why_installed_line(false).contains("no longer installed"));
     }
 
     #[test]
-    fn parse_installed_malformed() {
-        let output = "no-tab-here\n\tleading-tab\n";
-        assert!(parse_installed(output).is_empty());
+    fn why_events_interleave_installs_and_removals() {
+        let log = "\
+Start-Date: 2026-01-05  10:00:00
+Commandline: apt install jq
+Install: jq:amd64 (1.6)
+End-Date: 2026-01-05  10:00:01
+
+Start-Date: 2026-02-01  10:00:00
+Commandline: apt autoremove
+Remove: jq:amd64 (1.6)
+End-Date: 2026-02-01  10:00:01
+
+Start-Date: 2026-03-01  10:00:00
+Commandline: apt install jq
+Install: jq:amd64 (1.7)
+End-Date: 2026-03-01  10:00:01
+";
+        let entries = parse_history(log);
+        let hits = find_install_history(&entries, "jq");
+        let removals = find_removal_history(&entries, "jq");
+        let summary = |events: Vec<(&HistoryEntry, Option<Removal>)>| -> Vec<(String, Option<Removal>)> {
+            events.into_iter().map(|(e, k)| (e.date[..10].to_string(), k)).collect()
+        };
+        assert_eq!(
+            summary(why_events(&hits, &removals, false)),
+            [("2026-03-01".into(), None), ("2026-02-01".into(), Some(Removal::Removed)), ("2026-01-05".into(), None)]
+        );
+        assert_eq!(summary(why_events(&hits, &removals, true)), [("2026-02-01".into(), Some(Removal::Removed))]);
     }
 
     #[test]
-    fn add_duplicate_is_idempotent() {
-        let tmp = TempFile::new("dup.txt");
-        save_packages(&tmp, &BTreeSet::new()).unwrap();
-
-        cmd_modify(&tmp, &["git".into(), "git".into(), "curl".into()], true);
-        let pkgs = load_packages(&tmp);
-        assert_eq!(pkgs.len(), 2);
-        assert!(pkgs.contains("git"));
-        assert!(pkgs.contains("curl"));
-
-        // Adding again doesn't duplicate
-        cmd_modify(&tmp, &["git".into()], true);
-        let pkgs = load_packages(&tmp);
-        assert_eq!(pkgs.len(), 2);
+    fn timeline_interleaves_apt_and_dpkg_by_date() {
+        let history = parse_history(
+            "Start-Date: 2026-02-10  12:11:38\nCommandline: apt-get install nginx\nInstall: nginx:amd64 (1.24.0)\n\n\
+             Start-Date: 2026-04-02  18:30:00\nCommandline: apt-get purge nginx\nPurge: nginx:amd64 (1.26.0)\n",
+        );
+        let dpkg = parse_dpkg_log(
+            "2026-02-10 12:11:40 install nginx:amd64 <none> 1.24.0\n\
+             2026-02-10 12:11:41 status installed nginx:amd64 1.24.0\n\
+             2026-03-15 06:02:10 upgrade nginx:amd64 1.24.0 1.26.0\n\
+             2026-03-15 06:02:10 install curl:amd64 <none> 8.5.0\n\
+             2026-04-02 18:30:01 purge nginx:amd64 1.26.0 <none>\n",
+        );
+        assert_eq!(dpkg.len(), 4);
+        let timeline = build_timeline(&history, &dpkg, "nginx");
+        let seen: Vec<(&str, &str)> = timeline.iter().map(|e| (e.date.as_str(), e.source)).collect();
+        assert_eq!(
+            seen,
+            [
+                ("2026-02-10 12:11:38", "apt"),
+                ("2026-02-10 12:11:40", "dpkg"),
+                ("2026-03-15 06:02:10", "dpkg"),
+                ("2026-04-02 18:30:00", "apt"),
+                ("2026-04-02 18:30:01", "dpkg"),
+            ]
+        );
+        assert_eq!(timeline[2].what, "upgrade 1.24.0 → 1.26.0");
+        assert_eq!(timeline[3].what, "purged by `apt-get purge nginx`");
     }
 
     #[test]
@@ -834,8 +6049,76 @@ End-Date: 2026-01-15  14:26:10
         assert_eq!(entries.len(), 2);
         let hits = find_install_history(&entries, "apt-transport-https");
         assert_eq!(hits.len(), 2);
-        // Second entry has no requested_by
-        assert!(hits[1].requested_by.is_none());
+        // Newest first, and the newer entry has no requested_by
+        assert_eq!(hits[0].date, "2026-01-15  14:26:08");
+        assert!(hits[0].requested_by.is_none());
+    }
+
+    #[test]
+    fn install_history_is_newest_first_and_limitable() {
+        // Out of order, as when rotated logs are concatenated by file name
+        let log = "\
+Start-Date: 2026-03-01  09:00:00
+Commandline: apt install jq
+Install: jq:amd64 (1.7)
+End-Date: 2026-03-01  09:00:01
+
+Start-Date: 2025-01-01  09:00:00
+Commandline: apt install jq
+Install: jq:amd64 (1.6)
+End-Date: 2025-01-01  09:00:01
+
+Start-Date: 2025-06-01 09:00:00
+Commandline: apt install jq
+Install: jq:amd64 (1.6)
+End-Date: 2025-06-01 09:00:01
+";
+        let entries = parse_history(log);
+        let mut hits = find_install_history(&entries, "jq");
+        let dates: Vec<&str> = hits.iter().map(|e| e.date.as_str()).collect();
+        assert_eq!(dates, ["2026-03-01  09:00:00", "2025-06-01 09:00:00", "2025-01-01  09:00:00"]);
+        assert_eq!(limit_hits(&mut hits, None), 0);
+        assert_eq!(limit_hits(&mut hits, Some(5)), 0);
+        assert_eq!(limit_hits(&mut hits, Some(1)), 2);
+        assert_eq!(hits[0].date, "2026-03-01  09:00:00");
+    }
+
+    #[test]
+    fn why_order_flips_display_but_limit_keeps_the_newest() {
+        let newest_first = || vec!["2026-03", "2025-06", "2025-01"];
+        let mut events = newest_first();
+        assert_eq!(limit_and_order(&mut events, None, true), 0);
+        assert_eq!(events, ["2025-01", "2025-06", "2026-03"]);
+        let mut events = newest_first();
+        assert_eq!(limit_and_order(&mut events, Some(2), true), 1);
+        assert_eq!(events, ["2025-06", "2026-03"]);
+        let mut events = newest_first();
+        assert_eq!(limit_and_order(&mut events, Some(2), false), 1);
+        assert_eq!(events, ["2026-03", "2025-06"]);
+
+        let args = |a: &[&str]| a.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert!(why_oldest_first(&args(&["jq", "--oldest-first"]), false));
+        assert!(!why_oldest_first(&args(&["jq", "--newest-first"]), true));
+        // The last one given wins, and the config applies when neither is
+        assert!(!why_oldest_first(&args(&["--oldest-first", "--newest-first"]), false));
+        assert!(why_oldest_first(&args(&["jq"]), true));
+    }
+
+    #[test]
+    fn date_range_bounds_are_inclusive_days() {
+        let (since, until) = (Some("2025-08-01"), Some("2025-08-31"));
+        assert!(in_date_range("2025-08-01  00:00:01", since, until));
+        assert!(in_date_range("2025-08-31 23:59:59", since, until));
+        assert!(in_date_range("2025-08-15T12:00:00", since, until));
+        assert!(!in_date_range("2025-07-31  23:59:59", since, until));
+        assert!(!in_date_range("2025-09-01  00:00:00", since, until));
+        assert!(in_date_range("2020-01-01  00:00:00", None, until));
+        assert!(in_date_range("2030-01-01  00:00:00", since, None));
+        assert!(valid_day("2025-08-01"));
+        assert!(valid_day("2024-02-29"));
+        for bad in ["2025-8-1", "2025-13-01", "yesterday", "2025-08-01T00", "2025-02-30", "2025-8-001", "+2025-08-1", "2025-02-29", "1900-02-29"] {
+            assert!(!valid_day(bad), "{bad}");
+        }
     }
 
     #[test]
@@ -845,6 +6128,54 @@ End-Date: 2026-01-15  14:26:10
         assert_eq!(pkgs, vec!["build-essential"]);
     }
 
+    #[test]
+    fn find_history_ignores_query_arch() {
+        let log = "\
+Start-Date: 2026-02-10  12:11:38
+Commandline: apt-get install -y libc6
+Install: libc6:amd64 (2.39-0ubuntu8)
+End-Date: 2026-02-10  12:12:00
+";
+        let entries = parse_history(log);
+        assert_eq!(find_install_history(&entries, "libc6:amd64").len(), 1);
+        assert_eq!(find_install_history(&entries, "libc6").len(), 1);
+    }
+
+    #[test]
+    fn requested_by_resolves_bare_uid() {
+        let lookup = |uid: u32| (uid == 1000).then(|| "alice".to_string());
+        assert_eq!(requested_by_display("(1000)", lookup), "alice (1000)");
+        assert_eq!(requested_by_display("1000", lookup), "alice (1000)");
+        // Unknown uids and already-named entries are shown as logged
+        assert_eq!(requested_by_display("(1001)", lookup), "(1001)");
+        assert_eq!(requested_by_display("bob (1002)", |_| panic!("no lookup needed")), "bob (1002)");
+    }
+
+    #[test]
+    fn parse_journal_history_reads_sudo_apt_installs() {
+        let journal = "\
+2025-08-10T10:00:00+1000 box sudo[4242]:    alice : TTY=pts/0 ; PWD=/home/alice/src ; USER=root ; COMMAND=/usr/bin/apt-get install -y uidmap jq=1.6-2 neovim/bookworm-backports
+2025-08-10T10:05:00+1000 box sudo[4243]:    alice : TTY=pts/0 ; PWD=/home/alice ; USER=root ; COMMAND=/usr/bin/apt remove jq
+2025-08-10T10:06:00+1000 box sudo[4244]:    alice : TTY=pts/0 ; PWD=/home/alice ; USER=root ; COMMAND=/usr/bin/vim /etc/hosts
+2025-08-10T10:07:00+1000 box sudo[4245]: pam_unix(sudo:session): session opened for user root
+";
+        let entries = parse_journal_history(journal);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].date, "2025-08-10  10:00:00");
+        assert_eq!(entries[0].commandline, "apt-get install -y uidmap jq=1.6-2 neovim/bookworm-backports");
+        assert_eq!(entries[0].requested_by.as_deref(), Some("alice"));
+        assert_eq!(entries[0].installed, ["uidmap", "jq", "neovim"]);
+        assert_eq!(find_install_history(&entries, "jq").len(), 1);
+    }
+
+    #[test]
+    fn unreadable_history_is_not_empty_history() {
+        let denied = io::Error::from(io::ErrorKind::PermissionDenied);
+        assert_eq!(classify_log_read(Err(denied)), LogRead::Denied);
+        assert_eq!(classify_log_read(Err(io::Error::from(io::ErrorKind::NotFound))), LogRead::Missing);
+        assert_eq!(classify_log_read(Ok(String::new())), LogRead::Read(String::new()));
+    }
+
     #[test]
     fn find_history_no_match() {
         let entries = parse_history("");
@@ -912,6 +6243,25 @@ End-Date: 2025-08-10  14:01:00
         assert!(neighbors.contains(&"podman"));
     }
 
+    #[test]
+    fn why_grouped_siblings_rank_by_co_occurrence() {
+        let log = "\
+Start-Date: 2025-08-10  10:00:00
+Commandline: apt-get install gcc make binutils
+Install: gcc:amd64 (1.0), make:amd64 (1.0), binutils:amd64 (1.0)
+End-Date: 2025-08-10  10:01:00
+
+Start-Date: 2025-09-01  09:00:00
+Commandline: apt-get install gcc make cmake
+Install: gcc:amd64 (2.0), make:amd64 (2.0), cmake:amd64 (1.0)
+End-Date: 2025-09-01  09:01:00
+";
+        let entries = parse_history(log);
+        let hits = find_install_history(&entries, "gcc");
+        assert_eq!(hits.len(), 2);
+        assert_eq!(grouped_siblings(&hits, "gcc"), vec![("make", 2), ("binutils", 1), ("cmake", 1)]);
+    }
+
     #[test]
     fn why_no_context_when_solo() {
         let log = "\
@@ -929,6 +6279,342 @@ End-Date: 2025-08-10  10:01:00
         assert!(neighbors.is_empty());
     }
 
+    fn why_opts() -> WhyOpts {
+        WhyOpts {
+            before_secs: 300,
+            after_secs: 300,
+            show_all: false,
+            only: Vec::new(),
+            siblings: true,
+            group_siblings: false,
+            same_day: true,
+            quiet: true,
+            format: None,
+            csv: false,
+            timeline: false,
+            source: HistorySource::File,
+            cmdline_width: None,
+            dir: None,
+            hook: None,
+            verbose: false,
+            json: false,
+            limit: None,
+            since: None,
+            until: None,
+            removals_only: false,
+            oldest_first: false,
+        }
+    }
+
+    #[test]
+    fn why_json_omits_missing_fields() {
+        let log = "\
+Start-Date: 2025-08-10  10:00:00
+Commandline: apt-get install uidmap aardvark-dns
+Install: uidmap:amd64 (1.0), aardvark-dns:amd64 (1.0)
+End-Date: 2025-08-10  10:01:00
+
+Start-Date: 2025-08-10  14:00:00
+Commandline: apt-get install podman
+Requested-By: alice (1000)
+Install: podman:amd64 (1.0)
+End-Date: 2025-08-10  14:01:00
+";
+        let entries = parse_history(log);
+        assert_eq!(
+            why_json_event(&entries, &entries[0], "uidmap", Some("~/src"), &["git clone \"x\"".into()]),
+            "  {\"package\": \"uidmap\", \"date\": \"2025-08-10 10:00:00\", \
+             \"commandline\": \"apt-get install uidmap aardvark-dns\", \"siblings\": [\"aardvark-dns\"], \
+             \"same_day\": [\"podman\"], \"pwd\": \"~/src\", \"nearby_commands\": [\"git clone \\\"x\\\"\"]}"
+        );
+        let out = why_json_event(&entries, &entries[1], "podman", None, &[]);
+        assert!(out.contains("\"requested_by\": \"alice (1000)\""));
+        assert!(!out.contains("pwd"));
+        assert!(out.contains("\"siblings\": [], \"same_day\": [\"aardvark-dns\", \"uidmap\"]"), "{out}");
+    }
+
+    #[test]
+    fn why_entry_lines_show_context() {
+        let log = "\
+Start-Date: 2025-08-10  10:00:00
+Commandline: apt-get install uidmap aardvark-dns
+Install: uidmap:amd64 (1.0), aardvark-dns:amd64 (1.0)
+End-Date: 2025-08-10  10:01:00
+
+Start-Date: 2025-08-10  14:00:00
+Commandline: apt-get install podman
+Install: podman:amd64 (1.0)
+End-Date: 2025-08-10  14:01:00
+";
+        let entries = parse_history(log);
+        let out = why_entry_lines(&entries, &entries[0], "uidmap", &why_opts(), None, &[]).join("\n");
+        assert!(out.contains("with: aardvark-dns"));
+        assert!(out.contains("also that day: podman"));
+    }
+
+    #[test]
+    fn save_keeps_backup_and_leaves_no_temp_file() {
+        let tmp = TempFile::new("atomic-save");
+        fs::write(&*tmp, "git\n").unwrap();
+        save_packages_with_header(&tmp, &list_of(&["git", "jq"]), "").unwrap();
+        assert_eq!(fs::read_to_string(&*tmp).unwrap(), "git\njq\n");
+        assert_eq!(fs::read_to_string(sibling_path(&tmp, ".bak")).unwrap(), "git\n");
+        let dir = tmp.parent().unwrap();
+        let leftovers = fs::read_dir(dir)
+            .unwrap()
+            .flatten()
+            .any(|e| e.file_name().to_string_lossy().starts_with("apt-sync-test-atomic-save.tmp"));
+        assert!(!leftovers);
+    }
+
+    #[test]
+    fn save_keeps_owner_and_survives_a_broken_backup() {
+        let tmp = TempFile::new("owned-save");
+        let bak = sibling_path(&tmp, ".bak");
+        fs::write(&*tmp, "git\n").unwrap();
+        // A .bak that can't be replaced (here: a directory) must not stop the save
+        fs::create_dir_all(&bak).unwrap();
+        save_packages_with_header(&tmp, &list_of(&["git", "jq"]), "").unwrap();
+        assert_eq!(fs::read_to_string(&*tmp).unwrap(), "git\njq\n");
+        fs::remove_dir(&bak).unwrap();
+
+        if is_root() {
+            // What a sudo save sees: the list belongs to someone else
+            chown(&*tmp, Some(65534), Some(65534)).unwrap();
+            save_packages_with_header(&tmp, &list_of(&["git"]), "").unwrap();
+            for path in [tmp.to_path_buf(), bak] {
+                let meta = fs::metadata(&path).unwrap();
+                assert_eq!((meta.uid(), meta.gid()), (65534, 65534), "{}", path.display());
+            }
+        }
+    }
+
+    #[test]
+    fn second_list_lock_fails_while_first_is_held() {
+        let tmp = TempFile::new("locked-list");
+        let lock_path = TempFile(sibling_path(&tmp, ".lock"));
+        fs::write(&*tmp, "git\n").unwrap();
+        let held = lock_list(&tmp, Duration::ZERO).unwrap();
+        assert!(held.is_some());
+        let err = lock_list(&tmp, Duration::ZERO).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        drop(held);
+        assert!(lock_list(&tmp, Duration::ZERO).unwrap().is_some());
+        assert!(lock_path.exists());
+        assert!(lock_list(Path::new("/nonexistent/dir/packages.txt"), Duration::ZERO).unwrap().is_none());
+    }
+
+    #[test]
+    fn read_only_lock_file_still_locks() {
+        use std::os::unix::fs::PermissionsExt;
+        let tmp = TempFile::new("ro-lock-list");
+        let lock_path = TempFile(sibling_path(&tmp, ".lock"));
+        fs::write(&*tmp, "git\n").unwrap();
+        // As left behind by a sudo run: not writable by the next (normal) user
+        fs::write(&*lock_path, "").unwrap();
+        fs::set_permissions(&*lock_path, fs::Permissions::from_mode(0o444)).unwrap();
+        let held = lock_list(&tmp, Duration::ZERO).unwrap();
+        assert!(held.is_some());
+        assert_eq!(lock_list(&tmp, Duration::ZERO).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn apt_dates_parse_with_any_separator() {
+        for date in ["2026-02-10  12:11:38", "2026-02-10 12:11:38", "2026-02-10T12:11:38"] {
+            assert_eq!(normalize_apt_date(date), "2026-02-10 12:11:38");
+        }
+        assert_eq!(normalize_apt_date("2026-02-10T12:11:38Z"), "2026-02-10 12:11:38Z");
+        assert_eq!(normalize_apt_date(" 2026-02-10  12:11:38 +0100"), "2026-02-10 12:11:38 +0100");
+        let epochs: Vec<_> = ["2026-02-10  12:11:38", "2026-02-10 12:11:38", "2026-02-10T12:11:38"]
+            .iter()
+            .map(|d| apt_date_to_epoch(d))
+            .collect();
+        assert!(epochs[0].is_some());
+        assert!(epochs.iter().all(|e| *e == epochs[0]));
+        assert_eq!(apt_date_to_epoch("2026-02-10T12:11:38Z"), Some(1770725498));
+        assert_eq!(apt_date_to_epoch("2026-02-10  12:11:38 +0100"), Some(1770721898));
+    }
+
+    #[test]
+    fn missing_tools_get_a_friendly_error() {
+        let missing = io::Error::from(io::ErrorKind::NotFound);
+        assert_eq!(tool_error("apt-mark", &missing), "apt-mark not found — is this a Debian/Ubuntu system?");
+        let denied = io::Error::from(io::ErrorKind::PermissionDenied);
+        assert!(tool_error("apt-get", &denied).starts_with("failed to run apt-get: "));
+    }
+
+    #[test]
+    fn apt_dates_parse_in_process() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(2000, 3, 1), 11017);
+        assert_eq!(days_from_civil(1969, 12, 31), -1);
+        assert_eq!(apt_date_to_epoch("2026-02-10  21:50:50 UTC"), Some(1770760250));
+        assert_eq!(apt_date_to_epoch("2026-02-10 21:50:50 UTC"), Some(1770760250));
+        assert_eq!(apt_date_to_epoch("2026-02-10T21:50:50+01:00"), Some(1770756650));
+        assert_eq!(apt_date_to_epoch("2026-02-10 21:50:50 -0530"), Some(1770780050));
+        for bad in ["", "2026-02-10", "2026-13-10 21:50:50", "2026-02-10 25:00:00", "2026-02-10 21:50:50 CET", "yesterday"] {
+            assert_eq!(apt_date_to_epoch(bad), None, "{bad}");
+        }
+    }
+
+    // A v2 TZif with one transition: +01:00 until 1000000000, then +02:00
+    fn tzif_fixture() -> Vec<u8> {
+        let header = |time: u32, typ: u32, chars: u32| {
+            let mut h = b"TZif2".to_vec();
+            h.resize(20, 0);
+            for n in [0, 0, 0, time, typ, chars] {
+                h.extend(u32::to_be_bytes(n));
+            }
+            h
+        };
+        let mut data = header(0, 1, 4);
+        data.extend([0, 0, 0x0e, 0x10, 0, 0]);
+        data.extend(b"CET\0");
+        data.extend(header(1, 2, 4));
+        data.extend(1_000_000_000i64.to_be_bytes());
+        data.push(1);
+        data.extend([0, 0, 0x0e, 0x10, 0, 0]);
+        data.extend([0, 0, 0x1c, 0x20, 1, 0]);
+        data.extend(b"CET\0");
+        data.extend(b"\nCET-1\n");
+        data
+    }
+
+    #[test]
+    fn tzif_offsets_follow_transitions() {
+        let data = tzif_fixture();
+        assert_eq!(tzif_offset(&data, 0), Some(3600));
+        assert_eq!(tzif_offset(&data, 999_999_999), Some(3600));
+        assert_eq!(tzif_offset(&data, 1_000_000_000), Some(7200));
+        assert_eq!(tzif_offset(&data, 2_000_000_000), Some(7200));
+        assert_eq!(tzif_offset(b"not a zone file", 0), None);
+        assert_eq!(tzif_offset(&data[..30], 0), None);
+    }
+
+    #[test]
+    fn why_sources_line_reflects_window_and_history() {
+        let before = parse_duration_secs("10").unwrap();
+        let line = why_sources_line(before, 30, Some(Path::new("/home/me/.zsh_history")), false, Some("/home/me"));
+        assert!(line.contains("window: 10m before, 30s after"));
+        assert!(line.contains("shell history: ~/.zsh_history"));
+        assert!(line.contains("apt log: /var/log/apt/history.log"));
+        let line = why_sources_line(300, 300, None, true, None);
+        assert!(line.contains("shell history: none found"));
+        assert!(line.contains("apt log: journal"));
+    }
+
+    #[test]
+    fn why_hook_output_becomes_context() {
+        use std::os::unix::fs::PermissionsExt;
+        let hook = TempFile::new("why-hook");
+        fs::write(&*hook, "#!/bin/sh\necho \"ticket PROV-42 for $1 on $2\"\n").unwrap();
+        fs::set_permissions(&*hook, fs::Permissions::from_mode(0o755)).unwrap();
+        let lines = why_hook_lines(&hook, "jq", "2025-08-10  10:00:00", false);
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].contains("ticket PROV-42 for jq on 2025-08-10 10:00:00"));
+
+        let failing = TempFile::new("why-hook-fail");
+        fs::write(&*failing, "#!/bin/sh\necho partial\nexit 3\n").unwrap();
+        fs::set_permissions(&*failing, fs::Permissions::from_mode(0o755)).unwrap();
+        assert!(why_hook_lines(&failing, "jq", "2025-08-10", false).is_empty());
+        assert!(why_hook_lines(Path::new("/nonexistent/hook"), "jq", "2025-08-10", false).is_empty());
+    }
+
+    #[test]
+    fn why_csv_quotes_commas() {
+        let log = "\
+Start-Date: 2025-08-10  10:00:00
+Commandline: apt-get install -o Dpkg::Options::=--force-confdef,--force-confold jq \"oniguruma\"
+Requested-By: alice (1000)
+Install: jq:amd64 (1.6), libonig5:amd64 (6.9), libjq1:amd64 (1.6)
+";
+        let entries = parse_history(log);
+        assert_eq!(
+            why_csv_row("jq", &entries[0], Some("~/src")),
+            "jq,2025-08-10 10:00:00,\"apt-get install -o Dpkg::Options::=--force-confdef,--force-confold jq \"\"oniguruma\"\"\",\
+             alice (1000),libonig5;libjq1,~/src"
+        );
+        assert_eq!(csv_field("plain"), "plain");
+    }
+
+    #[test]
+    fn why_truncates_long_cmdline_to_width() {
+        let pkgs: Vec<String> = (0..40).map(|i| format!("package-{i}")).collect();
+        let log = format!(
+            "Start-Date: 2025-08-10  10:00:00\nCommandline: apt-get install {}\nInstall: package-0:amd64 (1.0)\n",
+            pkgs.join(" ")
+        );
+        let entries = parse_history(&log);
+        let opts = WhyOpts { cmdline_width: Some(60), ..why_opts() };
+        let first = why_entry_lines(&entries, &entries[0], "package-0", &opts, None, &[]).remove(0);
+        let shown = first.split(&DIM.to_string()).nth(1).unwrap().trim_end_matches(&RESET.to_string());
+        assert_eq!(shown.chars().count(), 60 - 17);
+        assert!(shown.ends_with('…'));
+
+        // --full-cmdline keeps it intact
+        let first = why_entry_lines(&entries, &entries[0], "package-0", &why_opts(), None, &[]).remove(0);
+        assert!(first.contains("package-39"));
+        assert_eq!(truncate_chars("short", 10), "short");
+    }
+
+    #[test]
+    fn why_no_siblings_and_no_same_day_suppress_lines() {
+        let log = "\
+Start-Date: 2025-08-10  10:00:00
+Commandline: apt-get install uidmap aardvark-dns
+Install: uidmap:amd64 (1.0), aardvark-dns:amd64 (1.0)
+End-Date: 2025-08-10  10:01:00
+
+Start-Date: 2025-08-10  14:00:00
+Commandline: apt-get install podman
+Install: podman:amd64 (1.0)
+End-Date: 2025-08-10  14:01:00
+";
+        let entries = parse_history(log);
+        let opts = WhyOpts { siblings: false, same_day: false, ..why_opts() };
+        let out = why_entry_lines(&entries, &entries[0], "uidmap", &opts, None, &[]).join("\n");
+        assert!(out.contains("2025-08-10"));
+        assert!(out.contains("apt-get install uidmap aardvark-dns"));
+        assert!(!out.contains("with:"));
+        assert!(!out.contains("also that day:"));
+    }
+
+    #[test]
+    fn format_why_event_placeholders() {
+        let log = "\
+Start-Date: 2025-08-10  10:00:00
+Commandline: apt-get install uidmap aardvark-dns
+Requested-By: user (1000)
+Install: uidmap:amd64 (1.0), aardvark-dns:amd64 (1.0)
+End-Date: 2025-08-10  10:01:00
+";
+        let entries = parse_history(log);
+        let line = format_why_event(
+            "{date}\\t{pkg}\\t{cmdline}\\t{user}\\t{pwd}\\t{siblings}",
+            "uidmap",
+            &entries[0],
+            Some("~/dotfiles"),
+        );
+        assert_eq!(
+            line,
+            "2025-08-10 10:00:00\tuidmap\tapt-get install uidmap aardvark-dns\tuser (1000)\t~/dotfiles\taardvark-dns"
+        );
+    }
+
+    #[test]
+    fn format_why_event_missing_user_and_pwd() {
+        let log = "\
+Start-Date: 2025-08-10  10:00:00
+Commandline: apt install jq
+Install: jq:amd64 (1.7)
+End-Date: 2025-08-10  10:00:05
+";
+        let entries = parse_history(log);
+        let line = format_why_event("{pkg}|{user}|{pwd}|{siblings}|{unknown}", "jq", &entries[0], None);
+        assert_eq!(line, "jq||||{unknown}");
+        assert_eq!(format_why_event("{pkg} {", "jq", &entries[0], None), "jq {");
+    }
+
     #[test]
     fn why_format_pkg_list_truncation() {
         let short: Vec<&str> = vec!["a", "b", "c"];
@@ -943,6 +6629,48 @@ End-Date: 2025-08-10  10:01:00
         assert!(result.starts_with("a, b, c"));
     }
 
+    #[test]
+    fn print_dir_matches_journal_pwd() {
+        let home = env::var("HOME").unwrap_or_else(|_| "/home/testuser".to_string());
+        let journal = format!("Feb 10 21:50:50 host sudo[12345]: PWD={home}/dotfiles ; USER=root ; COMMAND=/usr/bin/apt install jq");
+        let pwd = parse_journal_pwd(&journal, "apt install jq").unwrap();
+        let dir = expand_home(&pwd, Some(&home));
+        assert_eq!(dir, format!("{home}/dotfiles"));
+        assert_eq!(dir_output_line(&dir, DirOutput::Bare), dir);
+        assert_eq!(dir_output_line(&dir, DirOutput::Cd), format!("cd '{home}/dotfiles'"));
+
+        assert_eq!(expand_home("/srv/app", Some("/home/me")), "/srv/app");
+        assert_eq!(expand_home("~other/x", Some("/home/me")), "~other/x");
+        assert_eq!(dir_output_line("/tmp/it's", DirOutput::Cd), r"cd '/tmp/it'\''s'");
+    }
+
+    #[test]
+    fn print_dir_takes_the_newest_install_whatever_the_log_order() {
+        // As read from history.log.10.gz before history.log.2.gz: newest first, then oldest, then middle
+        let log = "\
+Start-Date: 2026-03-01  09:00:00
+Commandline: apt install jq
+Install: jq:amd64 (1.7)
+
+Start-Date: 2024-01-01  09:00:00
+Commandline: apt install jq
+Install: jq:amd64 (1.5)
+
+Start-Date: 2025-06-01  09:00:00
+Commandline: apt install jq
+Install: jq:amd64 (1.6)
+";
+        let entries = parse_history(log);
+        // The newest install predates sudo's journal, so it has no directory
+        let pwd_of = |e: &HistoryEntry| match e.date.as_str() {
+            "2024-01-01  09:00:00" => Some("/srv/old".to_string()),
+            "2025-06-01  09:00:00" => Some("/srv/middle".to_string()),
+            _ => None,
+        };
+        assert_eq!(latest_install_dir(&entries, &["jq"], pwd_of).as_deref(), Some("/srv/middle"));
+        assert_eq!(latest_install_dir(&entries, &["ripgrep"], pwd_of), None);
+    }
+
     #[test]
     fn parse_journal_pwd_extracts_path() {
         let home = env::var("HOME").unwrap_or_else(|_| "/home/testuser".to_string());
@@ -964,6 +6692,26 @@ End-Date: 2025-08-10  10:01:00
         assert_eq!(result, Some("~/projects/foo".to_string()));
     }
 
+    #[test]
+    fn parse_journal_pwd_aptitude() {
+        let home = env::var("HOME").unwrap_or_else(|_| "/home/testuser".to_string());
+        let journal = format!(
+            "Feb 10 21:50:50 host sudo[12345]: PWD={home}/src ; USER=root ; COMMAND=/usr/bin/aptitude install foo"
+        );
+        let result = parse_journal_pwd(&journal, "aptitude install foo");
+        assert_eq!(result, Some("~/src".to_string()));
+    }
+
+    #[test]
+    fn parse_journal_pwd_aptitude_skips_only_frontend_words() {
+        let home = env::var("HOME").unwrap_or_else(|_| "/home/testuser".to_string());
+        // "aptitude" itself must not count as a package name that matches any apt command
+        let journal = format!(
+            "Feb 10 21:50:50 host sudo[12345]: PWD={home}/src ; USER=root ; COMMAND=/usr/bin/aptitude install bar"
+        );
+        assert_eq!(parse_journal_pwd(&journal, "aptitude -y install foo"), None);
+    }
+
     #[test]
     fn parse_journal_pwd_no_match() {
         let home = env::var("HOME").unwrap_or_else(|_| "/home/testuser".to_string());
@@ -983,16 +6731,44 @@ End-Date: 2025-08-10  10:01:00
             entry(1500, "make build"),
             entry(1800, "vim README.md"),
         ];
-        let nearby = find_nearby_commands(&history, 1200, 300, false);
+        let nearby = find_nearby_commands(&history, 1200, 300, 300, false, &[]);
         // Within ±300s of 1200: 1000 (200s away), 1100 (100s away), 1500 (300s away)
         // 1800 is 600s away, excluded
         assert_eq!(nearby.len(), 3);
-        assert!(nearby.contains(&"cd ~/project".to_string()));
+        assert!(nearby.contains(&"cd ~/project  (working dir)".to_string()));
         assert!(nearby.contains(&"git status".to_string()));
         assert!(nearby.contains(&"make build".to_string()));
         assert!(!nearby.contains(&"vim README.md".to_string()));
     }
 
+    #[test]
+    fn find_nearby_commands_asymmetric_window() {
+        let history = vec![
+            entry(650, "too early"),
+            entry(800, "apt search ripgrep"),
+            entry(1020, "rg --version"),
+            entry(1100, "too late"),
+        ];
+        // 5 minutes before, 30 seconds after
+        let nearby = find_nearby_commands(&history, 1000, 300, 30, false, &[]);
+        assert_eq!(nearby, vec!["rg --version", "apt search ripgrep"]);
+    }
+
+    #[test]
+    fn find_nearby_commands_zero_after() {
+        let history = vec![entry(990, "before"), entry(1000, "same second"), entry(1001, "after")];
+        let nearby = find_nearby_commands(&history, 1000, 60, 0, false, &[]);
+        assert_eq!(nearby, vec!["same second", "before"]);
+    }
+
+    #[test]
+    fn parse_duration_units() {
+        assert_eq!(parse_duration_secs("5"), Some(300));
+        assert_eq!(parse_duration_secs("5m"), Some(300));
+        assert_eq!(parse_duration_secs("30s"), Some(30));
+        assert_eq!(parse_duration_secs("abc"), None);
+    }
+
     #[test]
     fn find_nearby_commands_excludes_apt() {
         let history = vec![
@@ -1000,7 +6776,7 @@ End-Date: 2025-08-10  10:01:00
             entry(1050, "apt-get install foo"),
             entry(1100, "apt install bar"),
         ];
-        let nearby = find_nearby_commands(&history, 1050, 300, false);
+        let nearby = find_nearby_commands(&history, 1050, 300, 300, false, &[]);
         assert_eq!(nearby.len(), 1);
         assert_eq!(nearby[0], "git status");
     }
@@ -1011,8 +6787,38 @@ End-Date: 2025-08-10  10:01:00
         for i in 0..10 {
             history.push(entry(1000 + i * 10, &format!("command{i}")));
         }
-        let nearby = find_nearby_commands(&history, 1050, 300, false);
+        let nearby = find_nearby_commands(&history, 1050, 300, 300, false, &[]);
+        assert_eq!(nearby.len(), 5);
+    }
+
+    #[test]
+    fn find_nearby_commands_keeps_preceding_cd() {
+        let mut history = vec![entry(700, "cd ~/old"), entry(800, "pushd ~/src/app"), entry(1300, "cd /tmp")];
+        for i in 0..8 {
+            history.push(entry(950 + i * 10, &format!("command{i}")));
+        }
+        let nearby = find_nearby_commands(&history, 1000, 300, 300, false, &[]);
+        // Eight closer commands would fill the cap; the latest cd before the install still makes it
         assert_eq!(nearby.len(), 5);
+        assert!(nearby.contains(&"pushd ~/src/app  (working dir)".to_string()));
+        assert!(!nearby.iter().any(|c| c.starts_with("cd ~/old") || c.starts_with("cd /tmp")));
+    }
+
+    #[test]
+    fn find_nearby_commands_allowlist() {
+        let history = vec![
+            entry(1000, "ls -la"),
+            entry(1010, "git clone https://example.com/tool"),
+            entry(1020, "vim notes.md"),
+            entry(1030, "make deps"),
+            entry(1040, "gitk"),
+        ];
+        let only = parse_prefix_list("git clone, make ,");
+        assert_eq!(only, ["git clone", "make"]);
+        let nearby = find_nearby_commands(&history, 1020, 300, 300, false, &only);
+        assert_eq!(nearby, ["git clone https://example.com/tool", "make deps"]);
+        // The allowlist wins over --all too
+        assert_eq!(find_nearby_commands(&history, 1020, 300, 300, true, &only).len(), 2);
     }
 
     #[test]
@@ -1024,7 +6830,7 @@ End-Date: 2025-08-10  10:01:00
             entry(1030, "pwd"),
             entry(1040, "cargo build"),
         ];
-        let nearby = find_nearby_commands(&history, 1020, 300, false);
+        let nearby = find_nearby_commands(&history, 1020, 300, 300, false, &[]);
         // Only git status and cargo build should be included
         assert_eq!(nearby.len(), 2);
         assert!(nearby.contains(&"git status".to_string()));
@@ -1041,7 +6847,7 @@ End-Date: 2025-08-10  10:01:00
             entry(1010, "clear"),
             entry(1020, "git status"),
         ];
-        let nearby = find_nearby_commands(&history, 1010, 300, true);
+        let nearby = find_nearby_commands(&history, 1010, 300, 300, true, &[]);
         // With show_all=true, all commands should be included
         assert_eq!(nearby.len(), 3);
         assert!(nearby.contains(&"ls -la".to_string()));
@@ -1049,6 +6855,31 @@ End-Date: 2025-08-10  10:01:00
         assert!(nearby.contains(&"git status".to_string()));
     }
 
+    #[test]
+    fn missing_histfile_falls_back_to_defaults() {
+        let home = std::env::temp_dir().join("apt-sync-test-histhome");
+        let _ = fs::create_dir_all(&home);
+        let zsh = TempFile(home.join(".zsh_history"));
+        fs::write(&*zsh, ": 1723305600:0;git status\n").unwrap();
+        let missing = home.join("does-not-exist");
+        let (path, contents) = read_first_history(missing.to_str(), home.to_str()).unwrap();
+        assert_eq!(path, *zsh);
+        assert_eq!(parse_shell_history(&contents, None)[0].command, "git status");
+    }
+
+    #[test]
+    fn existing_histfile_takes_precedence() {
+        let tmp = TempFile::new("histfile");
+        fs::write(&*tmp, ": 1723305600:0;cargo build\n").unwrap();
+        let (_, contents) = read_first_history(tmp.to_str(), Some("/nonexistent-home")).unwrap();
+        assert_eq!(parse_shell_history(&contents, None)[0].command, "cargo build");
+    }
+
+    #[test]
+    fn no_history_anywhere() {
+        assert!(read_first_history(Some("/nonexistent/hist"), Some("/nonexistent-home")).is_none());
+    }
+
     #[test]
     fn parse_zsh_history_entries() {
         let contents = "\
@@ -1058,7 +6889,7 @@ End-Date: 2025-08-10  10:01:00
 not a valid line
 : invalid:0;skipped
 ";
-        let entries = parse_shell_history(contents);
+        let entries = parse_shell_history(contents, None);
         assert_eq!(entries.len(), 3);
         assert_eq!(entries[0].timestamp, 1723305600);
         assert_eq!(entries[0].command, "git status");
@@ -1067,4 +6898,81 @@ not a valid line
         assert_eq!(entries[2].timestamp, 1723305620);
         assert_eq!(entries[2].command, "cargo build");
     }
+
+    #[test]
+    fn parse_atuin_history_rows() {
+        let rows = "1723305600123456789\x1fgit status\x1f/home/me/src\x1e\
+                    1723305610000000000\x1fprintf 'a\\tb'\nls\x1f\x1e\
+                    1723399999000000000\x1fmuch later\x1f/tmp\x1e";
+        let entries = parse_atuin_rows(rows, Some(&[(1723305500, 1723305700)]));
+        assert_eq!(entries.len(), 2);
+        assert_eq!((entries[0].timestamp, entries[0].command.as_str()), (1723305600, "git status"));
+        assert_eq!(entries[0].cwd.as_deref(), Some("/home/me/src"));
+        assert_eq!(entries[1].command, "printf 'a\\tb'\nls");
+        assert_eq!(entries[1].cwd, None);
+
+        assert_eq!(history_cwd(&entries, 1723305612, 300, 300), Some("/home/me/src"));
+        assert_eq!(history_cwd(&entries, 1723309000, 300, 300), None);
+        assert_eq!(atuin_db_path(None, "/home/me"), PathBuf::from("/home/me/.local/share/atuin/history.db"));
+    }
+
+    #[test]
+    fn parse_fish_history_entries() {
+        let contents = "\
+- cmd: git status
+  when: 1723305600
+- cmd: cd ~/project
+  when: 1723305610
+  paths:
+    - ~/project
+- cmd: echo one\\ntwo
+  when: 1723305620
+";
+        assert!(is_fish_history(contents));
+        assert!(!is_fish_history(": 1723305600:0;git status\n"));
+        let entries = parse_fish_history(contents, None);
+        let parsed: Vec<(i64, &str)> = entries.iter().map(|e| (e.timestamp, e.command.as_str())).collect();
+        assert_eq!(parsed, [(1723305600, "git status"), (1723305610, "cd ~/project"), (1723305620, "echo one\ntwo")]);
+        assert_eq!(parse_fish_history(contents, Some(&[(1723305605, 1723305615)])).len(), 1);
+    }
+
+    #[test]
+    fn fish_history_location() {
+        assert_eq!(fish_history_path(None, "/home/me", None), PathBuf::from("/home/me/.local/share/fish/fish_history"));
+        assert_eq!(fish_history_path(Some("/data"), "/home/me", Some("work")), PathBuf::from("/data/fish/work_history"));
+    }
+
+    #[test]
+    fn parse_bash_history_entries() {
+        let contents = "\
+untimed command
+#1723305600
+git status
+#1723305610
+cd ~/project
+cargo build
+#not-a-number
+";
+        let entries = parse_shell_history(contents, None);
+        let parsed: Vec<(i64, &str)> = entries.iter().map(|e| (e.timestamp, e.command.as_str())).collect();
+        assert_eq!(parsed, [(1723305600, "git status"), (1723305610, "cd ~/project")]);
+    }
+
+    #[test]
+    fn shell_history_skips_entries_outside_windows() {
+        let contents = "\
+: 1000:0;long ago
+: 5000:0;cd ~/project
+: 5100:0;make
+: 9000:0;unrelated
+: 500000:0;much later
+: 5050:0;out of order
+";
+        let entries = parse_shell_history(contents, Some(&[(4900, 5200)]));
+        let commands: Vec<&str> = entries.iter().map(|e| e.command.as_str()).collect();
+        // Several shells merge only roughly in order, so a window entry after a much later one still counts
+        assert_eq!(commands, ["cd ~/project", "make", "out of order"]);
+        // Without windows everything parses
+        assert_eq!(parse_shell_history(contents, None).len(), 6);
+    }
 }
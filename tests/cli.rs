@@ -0,0 +1,289 @@
+// End-to-end runs of the binary against a fake apt: PATH points at stub dpkg, dpkg-query,
+// apt-cache, apt-mark and apt-get scripts with canned output, so no real package state is touched.
+// The system has curl and git installed (plus htop, installed manually but not curated), and
+// libfoo for i386 only.
+
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+use std::sync::OnceLock;
+
+const STUBS: &[(&str, &str)] = &[
+    ("dpkg", "[ \"$1\" = --print-architecture ] && echo amd64\n"),
+    (
+        "dpkg-query",
+        "for arg in \"$@\"; do
+  case \"$arg\" in
+    curl) printf 'curl\\tamd64\\tinstall ok installed\\t8.5.0-2\\n' ;;
+    git) printf 'git\\tamd64\\tinstall ok installed\\t1:2.43.0-1\\n' ;;
+    libfoo) printf 'libfoo\\ti386\\tinstall ok installed\\t1.0\\n' ;;
+  esac
+done
+",
+    ),
+    ("apt-cache", "[ \"$1\" = depends ] && printf 'curl\\n  Recommends: ca-certificates\\n'\n"),
+    ("apt-mark", "case \"$1\" in showmanual) printf 'curl\\ngit\\nhtop\\n' ;; showauto) echo libc6 ;; esac\n"),
+    // `-s` simulates: each named package plus whatever $HOME/apt-sim-extra says apt would also take
+    (
+        "apt-get",
+        "if [ \"$1\" = -s ]; then
+  shift 2
+  for p in \"$@\"; do echo \"Remv $p [1.0]\"; done
+  [ -f \"$HOME/apt-sim-extra\" ] && while IFS= read -r l; do echo \"$l\"; done < \"$HOME/apt-sim-extra\"
+  exit 0
+fi
+echo \"apt-get $*\" >> \"$HOME/apt-get.log\"
+",
+    ),
+];
+
+// Written once, before any test spawns, so no stub is still open for writing when it's exec'd
+fn fake_bin() -> &'static Path {
+    static BIN: OnceLock<PathBuf> = OnceLock::new();
+    BIN.get_or_init(|| {
+        let bin = std::env::temp_dir().join(format!("apt-sync-cli-bin-{}", std::process::id()));
+        fs::create_dir_all(&bin).unwrap();
+        for (name, body) in STUBS {
+            let path = bin.join(name);
+            fs::write(&path, format!("#!/bin/sh\n{body}")).unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        }
+        bin
+    })
+}
+
+struct FakeSystem {
+    home: PathBuf,
+}
+
+impl FakeSystem {
+    fn new(name: &str, packages: &str) -> Self {
+        let home = std::env::temp_dir().join(format!("apt-sync-cli-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&home);
+        fs::create_dir_all(&home).unwrap();
+        fs::write(home.join("packages.txt"), packages).unwrap();
+        Self { home }
+    }
+
+    fn run(&self, args: &[&str]) -> Output {
+        self.run_with_path(args, fake_bin())
+    }
+
+    fn run_with_path(&self, args: &[&str], path: &Path) -> Output {
+        self.command(args, path).output().unwrap()
+    }
+
+    fn command(&self, args: &[&str], path: &Path) -> Command {
+        let mut cmd = Command::new(env!("CARGO_BIN_EXE_apt-sync"));
+        cmd.args(args)
+            .env_clear()
+            .env("PATH", path)
+            .env("HOME", &self.home)
+            .env("XDG_CONFIG_HOME", &self.home)
+            .env("APT_SYNC_FILE", self.home.join("packages.txt"))
+            // The stub apt-get doesn't need root, so neither does the run
+            .env("APT_SYNC_NO_ROOT_CHECK", "1");
+        cmd
+    }
+
+    fn stdout(&self, args: &[&str]) -> String {
+        String::from_utf8(self.run(args).stdout).unwrap()
+    }
+
+    fn packages(&self) -> String {
+        fs::read_to_string(self.home.join("packages.txt")).unwrap()
+    }
+}
+
+impl Drop for FakeSystem {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.home);
+    }
+}
+
+#[test]
+fn status_marks_installed_and_missing() {
+    let sys = FakeSystem::new("status", "curl\ngit\njq\n");
+    let out = sys.stdout(&["status"]);
+    assert!(out.contains("[ok] curl"));
+    assert!(out.contains("[ok] git"));
+    assert!(out.contains("[--] jq  (not installed)"));
+    assert!(out.contains("2 installed  1 missing"));
+}
+
+#[test]
+fn status_porcelain_is_stable() {
+    let sys = FakeSystem::new("porcelain", "curl\njq\n");
+    assert_eq!(sys.stdout(&["status", "--porcelain"]), "I curl\nM jq\n");
+}
+
+#[test]
+fn diff_shows_drift_both_ways() {
+    let sys = FakeSystem::new("diff", "curl\ngit\njq\n");
+    let out = sys.stdout(&["diff"]);
+    assert!(out.contains("On system but not curated (1 packages)"));
+    assert!(out.contains("[?] htop"));
+    assert!(out.contains("Curated but not on system (1 packages)"));
+    assert!(out.contains("[--] jq"));
+}
+
+#[test]
+fn diff_in_sync() {
+    let sys = FakeSystem::new("diff-sync", "curl\ngit\nhtop\n");
+    assert!(sys.stdout(&["diff"]).contains("System and curated list are in perfect sync!"));
+}
+
+#[test]
+fn install_runs_apt_get_for_missing_only() {
+    let sys = FakeSystem::new("install", "curl\njq\nripgrep\n");
+    let out = sys.run(&["install"]);
+    // The stub installs nothing, so apt-sync reports them still missing
+    assert!(!out.status.success());
+    let log = fs::read_to_string(sys.home.join("apt-get.log")).unwrap();
+    assert_eq!(log, "apt-get install -y jq ripgrep\n");
+}
+
+#[test]
+fn add_and_remove_edit_the_file_in_place() {
+    let sys = FakeSystem::new("add", "# mine\ncurl  # http\n\n## Dev\ngit\n");
+    assert!(sys.run(&["add", "jq", "--section", "Dev"]).status.success());
+    assert!(sys.run(&["remove", "curl"]).status.success());
+    assert_eq!(
+        sys.packages(),
+        "# apt-sync curated packages\n# one package per line, comments start with #\n# mine\n\n## Dev\ngit\njq\n"
+    );
+}
+
+#[test]
+fn import_merge_keeps_existing_entries() {
+    let sys = FakeSystem::new("import", "jq @json\n");
+    let out = sys.stdout(&["import", "--merge"]);
+    assert!(out.contains("3 added, 0 already present"));
+    let entries: Vec<String> = sys.packages().lines().filter(|l| !l.starts_with('#')).map(String::from).collect();
+    assert_eq!(entries, ["curl", "git", "htop", "jq @json"]);
+}
+
+#[test]
+fn missing_apt_tools_fail_cleanly() {
+    let sys = FakeSystem::new("no-apt", "curl\n");
+    // An empty PATH dir stands in for a box without dpkg/apt
+    let out = sys.run_with_path(&["diff"], &sys.home);
+    assert_eq!(out.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stderr.contains("not found — is this a Debian/Ubuntu system?"), "{stderr}");
+    assert!(!stderr.contains("panicked"));
+}
+
+#[test]
+fn unwritable_list_reports_instead_of_panicking() {
+    let sys = FakeSystem::new("unwritable", "");
+    // A directory where the list should be makes the save fail even for root
+    let list = sys.home.join("lists");
+    fs::create_dir(&list).unwrap();
+    let out = sys.command(&["add", "jq"], fake_bin()).env("APT_SYNC_FILE", &list).output().unwrap();
+    assert_eq!(out.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stderr.contains(&format!("could not write {}", list.display())), "{stderr}");
+    assert!(!stderr.contains("panicked"));
+    assert!(!String::from_utf8_lossy(&out.stdout).contains("Added"));
+}
+
+#[test]
+fn dpkg_status_is_refused_for_commands_that_change_the_system() {
+    let sys = FakeSystem::new("dpkg-status", "curl\njq\n");
+    let status = sys.home.join("status");
+    fs::write(&status, "Package: jq\nStatus: install ok installed\nArchitecture: amd64\nVersion: 1.7\n").unwrap();
+    let status = status.to_str().unwrap();
+    for args in [["sync", "--dpkg-status", status, "--yes"], ["install", "--dpkg-status", status, "--dry-run"]] {
+        let out = sys.run(&args);
+        assert_eq!(out.status.code(), Some(1));
+        assert!(String::from_utf8_lossy(&out.stderr).contains("--dpkg-status only works with"));
+    }
+    assert!(!sys.home.join("apt-get.log").exists());
+    // The read-only views still take it
+    assert_eq!(sys.stdout(&["status", "--porcelain", "--dpkg-status", status]), "M curl\nI jq\n");
+}
+
+#[test]
+fn foreign_arch_install_counts_unless_native_is_asked_for() {
+    let sys = FakeSystem::new("multiarch", "libfoo\n");
+    assert_eq!(sys.stdout(&["status", "--porcelain"]), "I libfoo\n");
+    assert_eq!(sys.stdout(&["status", "--porcelain", "--dpkg-query-arch=native"]), "M libfoo\n");
+}
+
+#[test]
+fn install_without_root_refuses_up_front() {
+    let sys = FakeSystem::new("install-root", "jq\n");
+    let status = fs::read_to_string("/proc/self/status").unwrap();
+    if status.lines().find_map(|l| l.strip_prefix("Uid:")).and_then(|u| u.split_whitespace().nth(1)) == Some("0") {
+        return; // root has nothing to gate
+    }
+    let out = sys.command(&["install"], fake_bin()).env_remove("APT_SYNC_NO_ROOT_CHECK").output().unwrap();
+    // No terminal to type a sudo password into, so it refuses before apt-get
+    assert!(String::from_utf8_lossy(&out.stderr).contains("needs root"));
+    assert!(!sys.home.join("apt-get.log").exists());
+}
+
+#[test]
+fn sync_refuses_when_apt_would_take_a_curated_package() {
+    let sys = FakeSystem::new("sync-blocked", "curl\ngit\n");
+    // htop isn't curated, but removing it would take curated git with it
+    fs::write(sys.home.join("apt-sim-extra"), "Remv git [1:2.43.0-1]\n").unwrap();
+    let out = sys.run(&["sync", "--yes"]);
+    assert_eq!(out.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&out.stderr).contains("curated or protected package(s) that depend on these: git"));
+    assert!(!sys.home.join("apt-get.log").exists());
+}
+
+#[test]
+fn sync_lists_dependents_in_the_plan() {
+    let sys = FakeSystem::new("sync-dependents", "curl\ngit\n");
+    fs::write(sys.home.join("apt-sim-extra"), "Remv htop-plugins [1.0]\n").unwrap();
+    let out = sys.run(&["sync", "--yes"]);
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    assert!(stdout.contains("1 package(s) that depend on them"), "{stdout}");
+    assert!(stdout.contains("htop-plugins"));
+    assert_eq!(fs::read_to_string(sys.home.join("apt-get.log")).unwrap(), "apt-get remove -y htop\n");
+}
+
+#[test]
+fn why_rejects_an_unknown_source() {
+    let sys = FakeSystem::new("why-source", "jq\n");
+    let out = sys.run(&["why", "jq", "--source=jounral"]);
+    assert_eq!(out.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&out.stderr).contains("--source takes file, journal or auto, not jounral"));
+}
+
+#[test]
+fn check_takes_exactly_one_package() {
+    let sys = FakeSystem::new("check-one", "curl\n");
+    assert_eq!(sys.run(&["check", "curl"]).status.code(), Some(0));
+    let out = sys.run(&["check", "curl", "jq"]);
+    assert_eq!(out.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&out.stderr).contains("one package at a time"));
+    assert!(out.stdout.is_empty());
+}
+
+#[test]
+fn recommends_curates_without_root() {
+    let sys = FakeSystem::new("recommends-curate", "curl\n");
+    let status = fs::read_to_string("/proc/self/status").unwrap();
+    if status.lines().find_map(|l| l.strip_prefix("Uid:")).and_then(|u| u.split_whitespace().nth(1)) == Some("0") {
+        return; // root has nothing to gate
+    }
+    let mut child = sys
+        .command(&["recommends"], fake_bin())
+        .env_remove("APT_SYNC_NO_ROOT_CHECK")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(b"c\n").unwrap();
+    let out = child.wait_with_output().unwrap();
+    assert_eq!(out.status.code(), Some(0), "{}", String::from_utf8_lossy(&out.stderr));
+    assert!(!String::from_utf8_lossy(&out.stderr).contains("needs root"));
+    assert!(fs::read_to_string(sys.home.join("packages.txt")).unwrap().lines().any(|l| l == "ca-certificates"));
+}